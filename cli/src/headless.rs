@@ -0,0 +1,128 @@
+//! Running the proxy without a terminal UI, e.g. as a daemon or in a
+//! container. Log messages are written to stderr or a file, and the proxy
+//! shuts down gracefully when it receives SIGINT or SIGTERM.
+
+use crate::proxy::Proxy;
+use failure::Error;
+use log::{error, info, LevelFilter, Log, Metadata, Record};
+use std::fs::OpenOptions;
+use std::io::{stderr, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::prelude::*;
+
+/// The proxy shut down cleanly after receiving a signal
+pub const EXIT_OK: i32 = 0;
+
+/// The proxy couldn't be started, e.g. due to invalid options or mappings
+pub const EXIT_STARTUP_ERROR: i32 = 1;
+
+/// An error occurred while the proxy was running
+pub const EXIT_RUNTIME_ERROR: i32 = 2;
+
+/// Some sessions were still open when the shutdown timeout elapsed
+pub const EXIT_SHUTDOWN_TIMEOUT: i32 = 3;
+
+/// How long to wait for sessions to close when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A plain logger writing one line per message
+struct HeadlessLogger {
+    level: LevelFilter,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for HeadlessLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut output = self.output.lock().expect("error acquiring log lock");
+        writeln!(
+            output,
+            "{} {:<5} [{}] {}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        )
+        .ok();
+    }
+
+    fn flush(&self) {
+        self.output
+            .lock()
+            .expect("error acquiring log lock")
+            .flush()
+            .ok();
+    }
+}
+
+/// Set up logging to stderr, or to the given file if specified
+pub fn init_logger(level: LevelFilter, file: Option<&Path>) -> Result<(), Error> {
+    let output: Box<dyn Write + Send> = match file {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(stderr()),
+    };
+
+    log::set_boxed_logger(Box::new(HeadlessLogger {
+        level,
+        output: Mutex::new(output),
+    }))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+/// A future which resolves once SIGINT or SIGTERM is received
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Item = (), Error = Error> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    let interrupt = Signal::new(SIGINT).flatten_stream();
+    let terminate = Signal::new(SIGTERM).flatten_stream();
+
+    interrupt
+        .select(terminate)
+        .into_future()
+        .map(|(signal, _)| {
+            if let Some(signal) = signal {
+                info!("Received signal {}", signal);
+            }
+        })
+        .map_err(|(e, _)| Error::from(e))
+}
+
+/// A future which resolves once Ctrl+C is pressed
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Item = (), Error = Error> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| info!("Received Ctrl+C"))
+        .map_err(|(e, _)| Error::from(e))
+}
+
+/// Run the proxy until a shutdown signal is received, returning the exit
+/// code for the process
+pub fn run(mut proxy: Proxy) -> i32 {
+    // shutting down stops accepting clients straight away, then waits for the
+    // open sessions to close
+    if let Err(e) = proxy.run_until(shutdown_signal()) {
+        error!("Proxy error: {}", e);
+        proxy.shutdown(SHUTDOWN_TIMEOUT);
+        return EXIT_RUNTIME_ERROR;
+    }
+
+    if proxy.shutdown(SHUTDOWN_TIMEOUT) {
+        EXIT_OK
+    } else {
+        EXIT_SHUTDOWN_TIMEOUT
+    }
+}
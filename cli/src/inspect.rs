@@ -0,0 +1,262 @@
+//! Inspecting capture files recorded with `--record`, to check how well the
+//! packet definitions match what the game actually sends.
+
+use failure::Error;
+use realmpipe_core::capture::{CaptureReader, CapturedPacket};
+use realmpipe_core::mappings::Mappings;
+use realmpipe_core::packets::Packet;
+use realmpipe_core::pipe::PacketSide;
+use realmpipe_core::proxy::raw::RawPacket;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use structopt::StructOpt;
+
+/// The capture file couldn't be read
+pub const EXIT_READ_ERROR: i32 = 1;
+
+/// The number of bytes shown on each line of a hex dump
+const HEX_LINE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+pub struct InspectOpts {
+    /// The capture file to inspect
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    /// Print each packet as a line of JSON
+    #[structopt(long = "json", raw(conflicts_with_all = r#"&["stats", "failures"]"#))]
+    json: bool,
+
+    /// Print the number and total size of packets of each type, in each
+    /// direction
+    #[structopt(long = "stats", conflicts_with = "failures")]
+    stats: bool,
+
+    /// Only print packets which couldn't be decoded, with a hex dump of their
+    /// contents
+    #[structopt(long = "failures")]
+    failures: bool,
+}
+
+/// A recorded packet, along with the result of decoding it
+struct Decoded {
+    index: usize,
+    captured: CapturedPacket,
+    result: Result<Packet, String>,
+}
+
+impl Decoded {
+    /// Decode a packet, also checking that it encodes back to the same bytes,
+    /// since a packet with extra fields may still decode successfully
+    fn new(index: usize, captured: CapturedPacket, mappings: &Mappings) -> Self {
+        let result = captured
+            .raw
+            .to_packet(mappings)
+            .map_err(|e| e.to_string())
+            .and_then(
+                |packet| match RawPacket::from_packet(packet.clone(), mappings) {
+                    Ok(ref raw) if raw.contents() == captured.raw.contents() => Ok(packet),
+                    Ok(raw) => Err(format!(
+                        "Packet encodes differently after decoding ({} bytes, expected {})",
+                        raw.size(),
+                        captured.raw.size()
+                    )),
+                    Err(e) => Err(format!("Error encoding decoded packet: {}", e)),
+                },
+            );
+
+        Self {
+            index,
+            captured,
+            result,
+        }
+    }
+
+    /// Get the name of the packet type
+    fn name(&self, mappings: &Mappings) -> String {
+        match mappings.get_internal_id(self.captured.raw.game_id()) {
+            Some(id) => id.get_name().to_string(),
+            None => format!("Unknown({})", self.captured.raw.game_id()),
+        }
+    }
+
+    /// Format the packet as a single line summary
+    fn describe(&self, mappings: &Mappings) -> String {
+        let time = humantime::format_rfc3339_millis(self.captured.time);
+        format!(
+            "#{:<6} {} {} {:<24} {:>6}",
+            self.index,
+            time,
+            direction(self.captured.side),
+            self.name(mappings),
+            self.captured.raw.size()
+        )
+    }
+}
+
+/// Describe the direction a packet was sent in
+fn direction(side: PacketSide) -> &'static str {
+    match side {
+        PacketSide::Client => "C -> S",
+        PacketSide::Server => "S -> C",
+    }
+}
+
+/// Format bytes as a hex dump, with the offset of each line and the printable
+/// ASCII characters alongside
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+
+    for (i, line) in bytes.chunks(HEX_LINE).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect::<String>();
+
+        writeln!(
+            dump,
+            "  {:06x}  {:<width$}  |{}|",
+            i * HEX_LINE,
+            hex,
+            ascii,
+            width = HEX_LINE * 3 - 1
+        )
+        .unwrap();
+    }
+
+    dump
+}
+
+/// Packet counts for one packet type in one direction
+#[derive(Debug, Default)]
+struct Counts {
+    packets: u64,
+    bytes: u64,
+    failed: u64,
+}
+
+/// Packet counts for each packet type and direction in a capture
+#[derive(Debug, Default)]
+struct Stats {
+    counts: BTreeMap<(String, &'static str), Counts>,
+    packets: u64,
+    failed: u64,
+}
+
+impl Stats {
+    /// Count a packet
+    fn record(&mut self, packet: &Decoded, mappings: &Mappings) {
+        let failed = packet.result.is_err() as u64;
+        let counts = self
+            .counts
+            .entry((packet.name(mappings), direction(packet.captured.side)))
+            .or_default();
+
+        counts.packets += 1;
+        counts.bytes += packet.captured.raw.size() as u64;
+        counts.failed += failed;
+        self.packets += 1;
+        self.failed += failed;
+    }
+
+    /// Print the counts as a table
+    fn print(&self) {
+        println!(
+            "{:<24} {:<6} {:>8} {:>10} {:>8}",
+            "Packet", "Dir", "Count", "Bytes", "Failed"
+        );
+        for ((name, direction), counts) in &self.counts {
+            println!(
+                "{:<24} {:<6} {:>8} {:>10} {:>8}",
+                name, direction, counts.packets, counts.bytes, counts.failed
+            );
+        }
+
+        println!();
+        println!("{} packets, {} failed to decode", self.packets, self.failed);
+    }
+}
+
+/// Print a packet as a line of JSON
+fn print_json(packet: &Decoded, mappings: &Mappings) -> Result<(), Error> {
+    let millis = packet
+        .captured
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let side = match packet.captured.side {
+        PacketSide::Client => "client",
+        PacketSide::Server => "server",
+    };
+
+    let mut line = json!({
+        "index": packet.index,
+        "time": millis,
+        "side": side,
+        "name": packet.name(mappings),
+        "size": packet.captured.raw.size(),
+    });
+
+    match &packet.result {
+        Ok(decoded) => line["packet"] = serde_json::to_value(decoded)?,
+        Err(e) => {
+            line["error"] = e.as_str().into();
+            line["contents"] = packet
+                .captured
+                .raw
+                .contents()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+                .into();
+        }
+    }
+
+    println!("{}", line);
+    Ok(())
+}
+
+/// Inspect a capture file, printing its packets to stdout
+pub fn run(opts: &InspectOpts) -> Result<(), Error> {
+    let capture = CaptureReader::open(&opts.file)?;
+    let mappings = capture.mappings().clone();
+    let mut stats = Stats::default();
+
+    for (index, captured) in capture.enumerate() {
+        let packet = Decoded::new(index, captured?, &mappings);
+
+        if opts.stats {
+            stats.record(&packet, &mappings);
+            continue;
+        } else if opts.json {
+            print_json(&packet, &mappings)?;
+            continue;
+        }
+
+        match &packet.result {
+            Ok(_) if opts.failures => {}
+            Ok(decoded) => println!("{}\n{:#?}\n", packet.describe(&mappings), decoded),
+            Err(e) => println!(
+                "{}\n{}\n{}",
+                packet.describe(&mappings),
+                e,
+                hex_dump(&packet.captured.raw.contents())
+            ),
+        }
+    }
+
+    if opts.stats {
+        stats.print();
+    }
+
+    Ok(())
+}
@@ -1,15 +1,291 @@
+pub mod headless;
+pub mod inspect;
+pub mod packetlog;
+pub mod proxy;
+#[cfg(feature = "ui")]
 pub mod ui;
 
+use self::inspect::InspectOpts;
+use self::packetlog::PacketLog;
+use self::proxy::Proxy;
+use failure::Error;
+use log::{error, LevelFilter};
+use realmpipe_core::config::{
+    Config, ConfigError, MappingsSource, PluginConfig, PluginRegistry, ServerConfig,
+};
+use realmpipe_core::pipe::{Pipe, Plugin, SettingValue};
+use realmpipe_core::{dynamic, scripting, wasm};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
 use structopt::StructOpt;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, StructOpt)]
-struct Opts {}
+/// The address to listen on if none is specified
+const DEFAULT_LISTEN: &str = "127.0.0.1:2050";
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+#[structopt(
+    about = "A lightweight and efficient proxy for Realm of the Mad God",
+    raw(setting = "structopt::clap::AppSettings::SubcommandsNegateReqs")
+)]
+struct Opts {
+    /// A TOML or JSON config file to load the proxy setup from, instead of
+    /// specifying it with options
+    #[structopt(
+        short = "c",
+        long = "config",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["mappings", "swf", "servers", "default_server"]"#)
+    )]
+    config: Option<PathBuf>,
+
+    /// The address to listen for client connections on [default:
+    /// 127.0.0.1:2050]
+    #[structopt(short = "l", long = "listen")]
+    listen: Option<SocketAddr>,
+
+    /// A JSON file containing mappings generated by the extractor
+    #[structopt(
+        short = "m",
+        long = "mappings",
+        parse(from_os_str),
+        raw(required_unless_one = r#"&["swf", "config"]"#),
+        conflicts_with = "swf"
+    )]
+    mappings: Option<PathBuf>,
+
+    /// A game client SWF to extract mappings from
+    #[structopt(long = "swf", parse(from_os_str))]
+    swf: Option<PathBuf>,
+
+    /// A JSON file mapping server names to IP addresses. If not specified, the
+    /// official server list will be retrieved.
+    #[structopt(short = "s", long = "servers", parse(from_os_str))]
+    servers: Option<PathBuf>,
+
+    /// The name or abbreviation of the server to connect clients to by default
+    /// [default: usw]
+    #[structopt(short = "d", long = "default-server")]
+    default_server: Option<String>,
+
+    /// A directory to load plugin libraries, scripts and WASM modules from.
+    /// Without a config file, every plugin loaded from the directory is
+    /// enabled.
+    #[structopt(long = "plugin-dir", parse(from_os_str))]
+    plugin_dir: Option<PathBuf>,
+
+    /// Record every session to a capture file in the given directory
+    #[structopt(long = "record", parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// The maximum level of log messages to show
+    #[structopt(long = "log-level", default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Run without the terminal UI, logging to stderr or the log file and
+    /// stopping on SIGINT or SIGTERM. This is always enabled if the CLI was
+    /// built without the `ui` feature.
+    #[structopt(long = "headless")]
+    headless: bool,
+
+    /// A file to append log messages to in headless mode, instead of stderr
+    #[structopt(long = "log-file", parse(from_os_str), requires = "headless")]
+    log_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+enum Command {
+    /// Print the packets in a capture file recorded with --record, as text or
+    /// JSON, or summarize them
+    #[structopt(name = "inspect")]
+    Inspect(InspectOpts),
+}
+
+impl Opts {
+    fn is_headless(&self) -> bool {
+        self.headless || cfg!(not(feature = "ui"))
+    }
+}
+
+/// Load the config file, or build a config from the options if there isn't
+/// one
+fn load_config(opts: &Opts) -> Result<Config, Error> {
+    let mut config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => {
+            let mappings = match (&opts.mappings, &opts.swf) {
+                (Some(file), _) => MappingsSource::File { file: file.clone() },
+                (None, Some(swf)) => MappingsSource::Swf { swf: swf.clone() },
+                (None, None) => unreachable!("either mappings or swf is required"),
+            };
+
+            let servers = match &opts.servers {
+                Some(path) => ServerConfig {
+                    official: false,
+                    overrides: proxy::load_servers(path)?,
+                },
+                None => ServerConfig::default(),
+            };
+
+            Config {
+                listen: DEFAULT_LISTEN.parse()?,
+                default_server: opts
+                    .default_server
+                    .clone()
+                    .unwrap_or_else(|| "usw".to_owned()),
+                mappings,
+                servers,
+                plugin_dir: None,
+                plugins: Vec::new(),
+            }
+        }
+    };
+
+    if let Some(listen) = opts.listen {
+        config.listen = listen;
+    }
+
+    if let Some(plugin_dir) = &opts.plugin_dir {
+        config.plugin_dir = Some(plugin_dir.clone());
+    }
+
+    // enable the recorder, replacing its config if it's already configured
+    if let Some(dir) = &opts.record {
+        let directory = SettingValue::Text(dir.display().to_string());
+        let recorder = PluginConfig {
+            name: "recorder".to_owned(),
+            enabled: true,
+            settings: vec![("directory".to_owned(), directory)]
+                .into_iter()
+                .collect(),
+        };
+
+        match config.plugins.iter_mut().find(|p| p.name == recorder.name) {
+            Some(existing) => *existing = recorder,
+            None => config.plugins.push(recorder),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Get the available plugins, loading any plugin libraries, scripts and WASM
+/// modules from the configured directory
+fn load_plugins(opts: &Opts, config: &mut Config) -> Result<PluginRegistry, Error> {
+    let mut registry = proxy::plugin_registry();
+
+    if let Some(dir) = &config.plugin_dir {
+        let mut loaded = dynamic::load_directory(dir, &mut registry)?;
+        loaded.extend(scripting::load_directory(dir, &mut registry)?);
+        loaded.extend(wasm::load_directory(dir, &mut registry)?);
+
+        // without a config file there's no other way to enable them
+        if opts.config.is_none() {
+            config
+                .plugins
+                .extend(loaded.into_iter().map(|name| PluginConfig {
+                    name,
+                    enabled: true,
+                    settings: BTreeMap::new(),
+                }));
+        }
+    }
+
+    Ok(registry)
+}
+
+/// Build a pipe from the config, adding the given plugins after the configured
+/// ones
+fn build_pipe(
+    config: &Config,
+    registry: &PluginRegistry,
+    plugins: Vec<Box<dyn Plugin>>,
+) -> Result<Pipe, Error> {
+    let mut builder = proxy::pipe_builder(config, registry)?;
+
+    for plugin in plugins {
+        if builder.has_plugin(plugin.name()) {
+            return Err(ConfigError::DuplicatePlugin(plugin.name().to_owned()).into());
+        }
+        builder = builder.plugin(plugin);
+    }
+
+    builder.build().map_err(failure::err_msg)
+}
+
+#[cfg(feature = "ui")]
+fn run_ui(proxy: Proxy, listen: &SocketAddr, log: PacketLog) -> i32 {
+    use std::time::Duration;
+
+    ui::run(proxy.get_pipe(), listen, log);
+    proxy.shutdown(Duration::from_secs(1));
+    headless::EXIT_OK
+}
+
+#[cfg(not(feature = "ui"))]
+fn run_ui(_proxy: Proxy, _listen: &SocketAddr, _log: PacketLog) -> i32 {
+    unreachable!("the UI is disabled")
+}
 
 fn main() {
     let opts: Opts = Opts::from_args();
 
-    // setup logging via cursive
-    cursive::logger::init();
+    if let Some(Command::Inspect(inspect)) = &opts.command {
+        if let Err(e) = inspect::run(inspect) {
+            eprintln!("Error inspecting capture: {}", e);
+            exit(inspect::EXIT_READ_ERROR);
+        }
+        return;
+    }
+
+    // setup logging, via cursive if the UI is being used
+    if opts.is_headless() {
+        if let Err(e) = headless::init_logger(opts.log_level, opts.log_file.as_deref()) {
+            eprintln!("Error setting up logging: {}", e);
+            exit(headless::EXIT_STARTUP_ERROR);
+        }
+    } else {
+        #[cfg(feature = "ui")]
+        cursive::logger::init();
+        log::set_max_level(opts.log_level);
+    }
+
+    // the packet log can only be viewed in the UI, so only record packets then
+    let packet_log = PacketLog::new(packetlog::DEFAULT_CAPACITY);
+    let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+    if !opts.is_headless() {
+        plugins.push(Box::new(packet_log.clone()));
+    }
+
+    let started = load_config(&opts).and_then(|mut config| {
+        let registry = load_plugins(&opts, &mut config)?;
+        let pipe = build_pipe(&config, &registry, plugins)?;
+        let proxy = Proxy::start(Arc::new(pipe), &config.listen)?;
+        Ok((proxy, config.listen))
+    });
+
+    let (proxy, listen) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            if opts.is_headless() {
+                error!("Error starting proxy: {}", e);
+            } else {
+                eprintln!("Error starting proxy: {}", e);
+            }
+            exit(headless::EXIT_STARTUP_ERROR);
+        }
+    };
+
+    let code = if opts.is_headless() {
+        headless::run(proxy)
+    } else {
+        run_ui(proxy, &listen, packet_log)
+    };
 
-    ui::run();
+    exit(code);
 }
@@ -0,0 +1,181 @@
+//! A plugin which records every packet passing through the proxy, so they can
+//! be inspected in the UI.
+
+use realmpipe_core::mappings::Mappings;
+use realmpipe_core::packets::InternalPacketId;
+use realmpipe_core::pipe::{
+    AutoPacket, PacketContext, PacketSide, Plugin, PluginState, SessionId, SessionSetup,
+};
+use realmpipe_core::proxy::raw::RawPacket;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// The default number of packets kept in the log
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A single logged packet
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// The position of this entry in the log, counting from the first packet
+    pub seq: u64,
+
+    /// The time at which the packet was received
+    pub time: SystemTime,
+
+    /// The session the packet was received by
+    pub session: SessionId,
+
+    /// The side of the connection the packet was sent from
+    pub side: PacketSide,
+
+    /// The internal ID of the packet, if it was mapped
+    pub id: Option<InternalPacketId>,
+
+    /// The packet itself, as it was received
+    pub raw: RawPacket,
+}
+
+impl LogEntry {
+    /// Get the name of this packet type
+    pub fn name(&self) -> String {
+        match self.id {
+            Some(id) => id.get_name().to_string(),
+            None => format!("Unknown({})", self.raw.game_id()),
+        }
+    }
+
+    /// Decode the packet, returning its `Debug` representation or a
+    /// description of the error
+    pub fn decode(&self, mappings: &Mappings) -> String {
+        match self.raw.to_packet(mappings) {
+            Ok(packet) => format!("{:#?}", packet),
+            Err(e) => format!("Error decoding packet: {}", e),
+        }
+    }
+}
+
+/// Criteria used to select packets from the log
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketFilter {
+    /// Only show packets of this type
+    pub id: Option<InternalPacketId>,
+
+    /// Only show packets sent from this side
+    pub side: Option<PacketSide>,
+
+    /// Only show packets from this session
+    pub session: Option<SessionId>,
+}
+
+impl PacketFilter {
+    /// Check whether an entry matches this filter
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        (self.id.is_none() || self.id == entry.id)
+            && (self.side.is_none() || self.side == Some(entry.side))
+            && (self.session.is_none() || self.session == Some(entry.session))
+    }
+}
+
+#[derive(Debug)]
+struct LogBuffer {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+/// A bounded, shared log of packets. Once the log is full, the oldest packets
+/// are discarded. Cloning a `PacketLog` gives another handle to the same log.
+#[derive(Debug, Clone)]
+pub struct PacketLog {
+    buffer: Arc<Mutex<LogBuffer>>,
+}
+
+impl PacketLog {
+    /// Create a new, empty log holding up to `capacity` packets
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: Arc::new(Mutex::new(LogBuffer {
+                entries: VecDeque::with_capacity(capacity),
+                capacity,
+                next_seq: 0,
+            })),
+        }
+    }
+
+    /// Get the maximum number of packets kept in the log
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogBuffer> {
+        self.buffer.lock().expect("error acquiring packet log lock")
+    }
+
+    fn record(&self, session: SessionId, side: PacketSide, packet: &AutoPacket) {
+        let raw = packet.get_raw().clone();
+        let id = packet.get_mappings().get_internal_id(raw.game_id());
+        let mut buffer = self.lock();
+
+        if buffer.entries.len() >= buffer.capacity {
+            buffer.entries.pop_front();
+        }
+
+        let seq = buffer.next_seq;
+        buffer.next_seq += 1;
+        buffer.entries.push_back(LogEntry {
+            seq,
+            time: SystemTime::now(),
+            session,
+            side,
+            id,
+            raw,
+        });
+    }
+
+    /// Get the logged packets matching `filter` with a sequence number of at
+    /// least `seq`, oldest first, along with the sequence number the next
+    /// logged packet will have
+    pub fn entries_since(&self, seq: u64, filter: &PacketFilter) -> (Vec<LogEntry>, u64) {
+        let buffer = self.lock();
+        let entries = buffer
+            .entries
+            .iter()
+            .skip_while(|e| e.seq < seq)
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect();
+
+        (entries, buffer.next_seq)
+    }
+}
+
+impl Plugin for PacketLog {
+    fn name(&self) -> &str {
+        "packet_log"
+    }
+
+    fn description(&self) -> &str {
+        "Records packets to be shown in the packet log"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        Box::new(PacketLogState {
+            log: self.clone(),
+            session: setup.session_id(),
+        })
+    }
+}
+
+struct PacketLogState {
+    log: PacketLog,
+    session: SessionId,
+}
+
+impl PluginState for PacketLogState {
+    fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        if let Some(side) = context.side() {
+            self.log.record(self.session, side, packet);
+        }
+    }
+}
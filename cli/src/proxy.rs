@@ -0,0 +1,197 @@
+//! Setup for the proxy itself, loading mappings and servers and running the
+//! pipe on a tokio runtime in the background.
+
+use failure::{err_msg, Error};
+use log::{info, warn};
+use realmpipe_core::capture::Recorder;
+use realmpipe_core::config::{Config, MappingsSource, PluginRegistry};
+use realmpipe_core::mappings::Mappings;
+use realmpipe_core::pipe::{Pipe, PipeBuilder};
+use realmpipe_core::proxy::client_listener;
+use realmpipe_core::serverlist::ServerList;
+use realmpipe_extractor::clientdata::Extractor;
+use realmpipe_extractor::serverlist::get_official_servers;
+use std::collections::BTreeMap;
+use std::convert::identity;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::prelude::future::Either;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+/// Load the mappings from the given source, extracting them from the game
+/// client if necessary
+pub fn load_mappings(source: &MappingsSource) -> Result<Mappings, Error> {
+    match source {
+        MappingsSource::Swf { swf } => Ok(Extractor::unpack()?.extract_mappings(swf, false)?),
+        source => Ok(source.load()?),
+    }
+}
+
+/// Load servers from a JSON file mapping server names to IP addresses
+pub fn load_servers(path: &Path) -> Result<BTreeMap<String, IpAddr>, Error> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// Retrieve the official server list
+pub fn official_servers() -> Result<ServerList, Error> {
+    let mut rt = tokio::runtime::current_thread::Runtime::new()?;
+    Ok(rt.block_on(get_official_servers())?)
+}
+
+/// The directory captures are saved to by default
+pub const DEFAULT_CAPTURE_DIR: &str = "captures";
+
+/// Get the plugins which can be enabled through the config
+pub fn plugin_registry() -> PluginRegistry {
+    let mut registry = PluginRegistry::default();
+    registry.register("recorder", || Box::new(Recorder::new(DEFAULT_CAPTURE_DIR)));
+    registry
+}
+
+/// Load the mappings and servers specified by `config`, then create a pipe
+/// builder with the configured plugins
+pub fn pipe_builder(config: &Config, registry: &PluginRegistry) -> Result<PipeBuilder, Error> {
+    let mappings = load_mappings(&config.mappings)?;
+
+    let official = if config.servers.official {
+        Some(official_servers()?)
+    } else {
+        None
+    };
+    let servers = config.server_list(official.as_ref())?;
+
+    Ok(config.builder(mappings, servers, registry)?)
+}
+
+/// A running proxy, accepting clients on a background runtime
+pub struct Proxy {
+    pipe: Arc<Pipe>,
+    runtime: Runtime,
+
+    /// Used to tell the listener to stop accepting clients
+    stop_listener: Option<oneshot::Sender<()>>,
+
+    /// Receives the reason the listener stopped, unless it was asked to
+    listener: Option<oneshot::Receiver<Error>>,
+}
+
+impl Proxy {
+    /// Start listening for clients on `address`, passing them to `pipe`
+    pub fn start(pipe: Arc<Pipe>, address: &SocketAddr) -> Result<Self, Error> {
+        let listener = client_listener(address, Arc::clone(pipe.get_mappings()))?;
+        let mut runtime = Runtime::new()?;
+
+        let (stop_listener, stop) = oneshot::channel();
+        let (finished, listener_error) = oneshot::channel();
+
+        let accept_pipe = Arc::clone(&pipe);
+        let accept = listener
+            .then(|result| match result {
+                // a failed connection shouldn't stop the listener
+                Ok(connection) => Ok(Some(connection)),
+                Err(e) => {
+                    warn!("Error accepting client: {}", e);
+                    Ok(None)
+                }
+            })
+            .filter_map(identity)
+            .for_each(move |connection| {
+                tokio::spawn(
+                    Arc::clone(&accept_pipe)
+                        .accept_client(connection)
+                        .map_err(|e| warn!("Session error: {}", e)),
+                );
+                Ok::<(), Error>(())
+            });
+
+        // run the listener until it's told to stop, letting the proxy know if
+        // it stopped by itself first
+        runtime.spawn(accept.select2(stop).then(move |result| {
+            let error = match result {
+                Ok(Either::A(_)) => err_msg("the client listener stopped unexpectedly"),
+                Err(Either::A((e, _))) => e,
+                // either the proxy asked the listener to stop, or it was dropped
+                Ok(Either::B(_)) | Err(Either::B(_)) => return Ok(()),
+            };
+            finished.send(error).ok();
+            Ok(())
+        }));
+
+        info!("Listening for clients on {}", address);
+        Ok(Self {
+            pipe,
+            runtime,
+            stop_listener: Some(stop_listener),
+            listener: Some(listener_error),
+        })
+    }
+
+    /// Get the pipe used by this proxy
+    pub fn get_pipe(&self) -> &Arc<Pipe> {
+        &self.pipe
+    }
+
+    /// Run a future on the proxy's runtime, blocking until it completes. If the
+    /// proxy stops accepting clients before then, the error which stopped it
+    /// is returned instead.
+    pub fn run_until<F>(&mut self, future: F) -> Result<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let listener = self
+            .listener
+            .take()
+            .ok_or_else(|| err_msg("the proxy is no longer accepting clients"))?;
+
+        match self.runtime.block_on(future.select2(listener)) {
+            Ok(Either::A((item, listener))) => {
+                self.listener = Some(listener);
+                Ok(item)
+            }
+            Err(Either::A((e, listener))) => {
+                self.listener = Some(listener);
+                Err(e)
+            }
+            Ok(Either::B((e, _))) => Err(e),
+            Err(Either::B(_)) => Err(err_msg("the client listener was dropped")),
+        }
+    }
+
+    /// Stop accepting new clients, leaving any open sessions running
+    pub fn stop_listening(&mut self) {
+        if let Some(stop) = self.stop_listener.take() {
+            info!("No longer accepting clients");
+            stop.send(()).ok();
+        }
+    }
+
+    /// Disconnect all clients and stop the proxy, waiting up to `timeout` for
+    /// the sessions to close. Returns whether all sessions closed in time.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        info!("Shutting down");
+        self.stop_listening();
+        self.pipe.disconnect_all();
+
+        let deadline = Instant::now() + timeout;
+        while !self.pipe.sessions().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let remaining = self.pipe.sessions().len();
+        if remaining > 0 {
+            warn!("{} sessions did not close in time", remaining);
+        }
+
+        self.runtime.shutdown_now().wait().ok();
+        remaining == 0
+    }
+}
@@ -0,0 +1,237 @@
+//! The main view of the UI, listing the live sessions running through the
+//! proxy, with details for the selected session.
+
+use cursive::view::{Boxable, Identifiable, View};
+use cursive::views::{Dialog, LinearLayout, Panel, ScrollView, SelectView, TextView};
+use cursive::Cursive;
+use log::info;
+use realmpipe_core::pipe::{PacketSide, Pipe, SessionId, SessionInfo};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LIST_ID: &str = "dashboard_list";
+const DETAIL_ID: &str = "dashboard_detail";
+
+/// How often packet rates are recalculated
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The packet rates of a session, measured over the last interval
+#[derive(Debug, Clone, Copy)]
+struct Rates {
+    measured: Instant,
+    client_packets: u64,
+    server_packets: u64,
+    client_rate: f64,
+    server_rate: f64,
+}
+
+impl Rates {
+    fn new(info: &SessionInfo) -> Self {
+        Self {
+            measured: Instant::now(),
+            client_packets: info.packets(PacketSide::Client),
+            server_packets: info.packets(PacketSide::Server),
+            client_rate: 0.0,
+            server_rate: 0.0,
+        }
+    }
+
+    /// Recalculate the rates, if enough time has passed since the last update
+    fn update(&mut self, info: &SessionInfo) {
+        let elapsed = self.measured.elapsed();
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+
+        let next = Self::new(info);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        self.client_rate = (next.client_packets - self.client_packets) as f64 / secs;
+        self.server_rate = (next.server_packets - self.server_packets) as f64 / secs;
+        self.measured = next.measured;
+        self.client_packets = next.client_packets;
+        self.server_packets = next.server_packets;
+    }
+}
+
+/// Format an uptime to the nearest second
+fn uptime(info: &SessionInfo) -> String {
+    humantime::format_duration(Duration::from_secs(info.uptime().as_secs())).to_string()
+}
+
+/// The session dashboard. Cloning it gives another handle to the same view,
+/// so it can be shared between callbacks.
+#[derive(Clone)]
+pub struct Dashboard {
+    pipe: Arc<Pipe>,
+    rates: Rc<RefCell<HashMap<SessionId, Rates>>>,
+}
+
+impl Dashboard {
+    /// Create a new dashboard for the sessions of the given pipe
+    pub fn new(pipe: Arc<Pipe>) -> Self {
+        Self {
+            pipe,
+            rates: Rc::default(),
+        }
+    }
+
+    /// Show the dashboard, or bring it to the front if it's already open
+    pub fn show(&self, siv: &mut Cursive, listen: &SocketAddr) {
+        if let Some(layer) = siv.screen_mut().find_layer_from_id(LIST_ID) {
+            siv.screen_mut().move_to_front(layer);
+        } else {
+            siv.add_layer(self.view(listen));
+        }
+    }
+
+    /// Build the dashboard view
+    fn view(&self, listen: &SocketAddr) -> impl View {
+        let view = self.clone();
+        let list = SelectView::<SessionId>::new()
+            .on_select(move |s, id| view.show_detail(s, *id))
+            .with_id(LIST_ID);
+
+        let detail = TextView::new("No clients connected").with_id(DETAIL_ID);
+
+        let layout = LinearLayout::horizontal()
+            .child(Panel::new(ScrollView::new(list)).full_width())
+            .child(Panel::new(detail).fixed_width(48));
+
+        let view = self.clone();
+        Dialog::around(layout)
+            .title(format!(
+                "realmpipe - listening on {}, default server {}",
+                listen,
+                self.pipe.get_default_server()
+            ))
+            .button("Disconnect", move |s| view.disconnect_selected(s))
+            .full_screen()
+    }
+
+    fn selected(siv: &mut Cursive) -> Option<SessionId> {
+        siv.call_on_id(LIST_ID, |list: &mut SelectView<SessionId>| {
+            list.selection().map(|id| *id)
+        })
+        .flatten()
+    }
+
+    fn disconnect_selected(&self, siv: &mut Cursive) {
+        if let Some(id) = Self::selected(siv) {
+            if self.pipe.disconnect(id) {
+                info!("Disconnecting session {}", id);
+            }
+        }
+    }
+
+    /// Update the detail pane for the given session
+    fn show_detail(&self, siv: &mut Cursive, id: SessionId) {
+        let info = match self.pipe.get_session(id) {
+            Some(info) => info,
+            None => return,
+        };
+
+        let rates = self.rates.borrow().get(&id).cloned();
+        let (client_rate, server_rate) =
+            rates.map_or((0.0, 0.0), |r| (r.client_rate, r.server_rate));
+
+        let detail = format!(
+            "Session {}\n\
+             Account: {}\n\
+             Character: {}\n\
+             Client: {}\n\
+             Server: {}\n\
+             Started: {}\n\
+             Uptime: {}\n\n\
+             From client: {} packets, {} bytes ({:.1}/s)\n\
+             From server: {} packets, {} bytes ({:.1}/s)",
+            id,
+            info.account().unwrap_or_else(|| "unknown".to_string()),
+            info.character_name()
+                .unwrap_or_else(|| "unknown".to_string()),
+            info.client_addr(),
+            info.server_addr(),
+            humantime::format_rfc3339_seconds(info.started()),
+            uptime(&info),
+            info.packets(PacketSide::Client),
+            info.bytes(PacketSide::Client),
+            client_rate,
+            info.packets(PacketSide::Server),
+            info.bytes(PacketSide::Server),
+            server_rate,
+        );
+
+        siv.call_on_id(DETAIL_ID, |v: &mut TextView| v.set_content(detail));
+    }
+
+    /// Update the list of sessions and the details of the selected session
+    pub fn refresh(&self, siv: &mut Cursive) {
+        let sessions = self.pipe.sessions();
+
+        // measure the packet rates, forgetting sessions which have ended
+        {
+            let mut rates = self.rates.borrow_mut();
+            rates.retain(|id, _| sessions.iter().any(|info| info.id() == *id));
+            for info in &sessions {
+                rates
+                    .entry(info.id())
+                    .or_insert_with(|| Rates::new(info))
+                    .update(info);
+            }
+        }
+
+        // update the list in place, so the selection and scroll position are
+        // kept
+        let rates = self.rates.borrow();
+        siv.call_on_id(LIST_ID, |list: &mut SelectView<SessionId>| {
+            // remove sessions which have ended
+            let mut index = 0;
+            while let Some((_, id)) = list.get_item(index) {
+                if sessions.iter().any(|info| info.id() == *id) {
+                    index += 1;
+                } else {
+                    list.remove_item(index);
+                }
+            }
+
+            // relabel the remaining sessions, and add new ones at the end
+            for info in &sessions {
+                let rate = rates
+                    .get(&info.id())
+                    .map_or(0.0, |r| r.client_rate + r.server_rate);
+                let label = format!(
+                    "{:>5} {:<12} {} -> {} {:>10} {:>7.1}/s",
+                    info.id().to_string(),
+                    info.character_name().unwrap_or_default(),
+                    info.client_addr(),
+                    info.server_addr(),
+                    uptime(info),
+                    rate
+                );
+
+                let index = list.iter().position(|(_, id)| *id == info.id());
+                match index {
+                    Some(index) => {
+                        if let Some((text, _)) = list.get_item_mut(index) {
+                            *text = label.into();
+                        }
+                    }
+                    None => list.add_item(label, info.id()),
+                }
+            }
+        });
+        drop(rates);
+
+        match Self::selected(siv) {
+            Some(id) => self.show_detail(siv, id),
+            None => {
+                siv.call_on_id(DETAIL_ID, |v: &mut TextView| {
+                    v.set_content("No clients connected")
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+mod dashboard;
+mod packetlog;
+mod plugins;
+
+use self::dashboard::Dashboard;
+use self::packetlog::PacketLogView;
+use crate::packetlog::PacketLog;
+use cursive::event::{Event, Key};
+use cursive::views::{Dialog, TextView};
+use cursive::Cursive;
+use realmpipe_core::pipe::Pipe;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub fn run(pipe: &Arc<Pipe>, listen: &SocketAddr, log: PacketLog) {
+    // initialize cursive, refreshing regularly to show new sessions and packets
+    let mut siv = Cursive::default();
+    siv.set_fps(4);
+
+    let dashboard = Dashboard::new(Arc::clone(pipe));
+    let log_view = PacketLogView::new(Arc::clone(pipe), log);
+    let (refresh_dashboard, refresh_log) = (dashboard.clone(), log_view.clone());
+    siv.add_global_callback(Event::Refresh, move |s| {
+        refresh_dashboard.refresh(s);
+        refresh_log.refresh(s);
+    });
+
+    // set up menus
+    let plugins_pipe = Arc::clone(pipe);
+    siv.set_autohide_menu(false);
+    siv.add_global_callback(Key::Esc, |s| s.select_menubar());
+    siv.add_global_callback('~', |s| s.toggle_debug_console());
+    siv.menubar()
+        .add_leaf("Packets", move |s| log_view.show(s))
+        .add_leaf("Plugins", move |s| plugins::show(s, &plugins_pipe))
+        .add_leaf("Logs", |s| s.toggle_debug_console());
+    siv.menubar().add_leaf("Quit", |s| {
+        s.add_layer(
+            Dialog::new()
+                .title("Really Quit?")
+                .content(TextView::new(
+                    "Do you really want to quit? All clients will be disconnected.",
+                ))
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                })
+                .button("Continue", |s| s.quit()),
+        )
+    });
+
+    // show the live sessions, along with where clients should connect
+    dashboard.show(&mut siv, listen);
+
+    siv.run();
+}
@@ -0,0 +1,182 @@
+//! A view of the packet log, with filters for packet type, side and session.
+
+use crate::packetlog::{LogEntry, PacketFilter, PacketLog};
+use cursive::view::{Boxable, Identifiable, ScrollStrategy};
+use cursive::views::{Dialog, DummyView, LinearLayout, Panel, ScrollView, SelectView, TextView};
+use cursive::Cursive;
+use realmpipe_core::packets::InternalPacketId;
+use realmpipe_core::pipe::{PacketSide, Pipe, SessionId};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+const LIST_ID: &str = "packet_log_list";
+const DETAIL_ID: &str = "packet_log_detail";
+
+#[derive(Default)]
+struct ViewState {
+    filter: PacketFilter,
+    next_seq: u64,
+}
+
+/// The packet log window. Cloning it gives another handle to the same window,
+/// so it can be shared between callbacks.
+#[derive(Clone)]
+pub struct PacketLogView {
+    pipe: Arc<Pipe>,
+    log: PacketLog,
+    state: Rc<RefCell<ViewState>>,
+}
+
+/// Format a log entry as a single line for the list
+fn describe(entry: &LogEntry) -> String {
+    // only show the time of day, to the millisecond
+    let time = humantime::format_rfc3339_millis(entry.time).to_string();
+    let direction = match entry.side {
+        PacketSide::Client => "C -> S",
+        PacketSide::Server => "S -> C",
+    };
+
+    format!(
+        "{} {:>5} {} {:<24} {:>6}",
+        &time[11..23],
+        entry.session.to_string(),
+        direction,
+        entry.name(),
+        entry.raw.size()
+    )
+}
+
+impl PacketLogView {
+    /// Create a new packet log window for the given log
+    pub fn new(pipe: Arc<Pipe>, log: PacketLog) -> Self {
+        Self {
+            pipe,
+            log,
+            state: Rc::default(),
+        }
+    }
+
+    /// Open the packet log window, or bring it to the front if it's already
+    /// open
+    pub fn show(&self, siv: &mut Cursive) {
+        if let Some(layer) = siv.screen_mut().find_layer_from_id(LIST_ID) {
+            siv.screen_mut().move_to_front(layer);
+            return;
+        }
+
+        self.state.borrow_mut().next_seq = 0;
+
+        // filter by packet type
+        let mut names: Vec<_> = InternalPacketId::get_name_mappings()
+            .iter()
+            .map(|(id, name)| (*name, *id))
+            .collect();
+        names.sort();
+
+        let mut types = SelectView::new().popup().item("All packets", None);
+        for (name, id) in names {
+            types.add_item(name, Some(id));
+        }
+
+        let view = self.clone();
+        let types = types
+            .on_submit(move |s, id: &Option<InternalPacketId>| view.set_filter(s, |f| f.id = *id));
+
+        // filter by side
+        let view = self.clone();
+        let sides = SelectView::new()
+            .popup()
+            .item("Both sides", None)
+            .item("From client", Some(PacketSide::Client))
+            .item("From server", Some(PacketSide::Server))
+            .on_submit(move |s, side: &Option<PacketSide>| view.set_filter(s, |f| f.side = *side));
+
+        // filter by session, using the sessions that are open right now
+        let mut sessions = SelectView::new().popup().item("All sessions", None);
+        for info in self.pipe.sessions() {
+            let label = match info.character_name() {
+                Some(name) => format!("{} {}", info.id(), name),
+                None => format!("{} {}", info.id(), info.client_addr()),
+            };
+            sessions.add_item(label, Some(info.id()));
+        }
+
+        let view = self.clone();
+        let sessions = sessions.on_submit(move |s, session: &Option<SessionId>| {
+            view.set_filter(s, |f| f.session = *session)
+        });
+
+        // the list of packets, decoding them when selected
+        let mappings = Arc::clone(self.pipe.get_mappings());
+        let list = SelectView::<LogEntry>::new()
+            .on_select(move |s, entry| {
+                let decoded = entry.decode(&mappings);
+                s.call_on_id(DETAIL_ID, |v: &mut TextView| v.set_content(decoded));
+            })
+            .with_id(LIST_ID);
+
+        let detail = TextView::new("Select a packet to decode it").with_id(DETAIL_ID);
+
+        let layout = LinearLayout::vertical()
+            .child(
+                LinearLayout::horizontal()
+                    .child(types)
+                    .child(DummyView)
+                    .child(sides)
+                    .child(DummyView)
+                    .child(sessions),
+            )
+            .child(
+                ScrollView::new(list)
+                    .scroll_strategy(ScrollStrategy::StickToBottom)
+                    .full_height(),
+            )
+            .child(Panel::new(ScrollView::new(detail)).fixed_height(16));
+
+        siv.add_layer(
+            Dialog::around(layout)
+                .title("Packet log")
+                .button("Close", |s| {
+                    s.pop_layer();
+                })
+                .full_screen(),
+        );
+
+        self.refresh(siv);
+    }
+
+    /// Change the filter, showing the matching packets from the start of the
+    /// log
+    fn set_filter(&self, siv: &mut Cursive, update: impl FnOnce(&mut PacketFilter)) {
+        {
+            let mut state = self.state.borrow_mut();
+            update(&mut state.filter);
+            state.next_seq = 0;
+        }
+
+        siv.call_on_id(LIST_ID, |list: &mut SelectView<LogEntry>| list.clear());
+        self.refresh(siv);
+    }
+
+    /// Add any new packets to the window, if it's open
+    pub fn refresh(&self, siv: &mut Cursive) {
+        let mut state = self.state.borrow_mut();
+        let log = &self.log;
+
+        siv.call_on_id(LIST_ID, |list: &mut SelectView<LogEntry>| {
+            let (entries, next_seq) = log.entries_since(state.next_seq, &state.filter);
+            state.next_seq = next_seq;
+
+            for entry in entries {
+                list.add_item(describe(&entry), entry);
+            }
+
+            // keep the list the same size as the log itself
+            let excess = list.len().saturating_sub(log.capacity());
+            for _ in 0..excess {
+                list.remove_item(0);
+            }
+        });
+    }
+}
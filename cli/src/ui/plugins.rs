@@ -0,0 +1,159 @@
+//! Views to enable, disable and configure the plugins used by the pipe.
+
+use cursive::view::{Boxable, Identifiable};
+use cursive::views::{Checkbox, Dialog, EditView, ListView, SelectView};
+use cursive::Cursive;
+use log::info;
+use realmpipe_core::pipe::{Pipe, PluginInfo, Setting, SettingValue};
+use std::sync::Arc;
+
+const LIST_ID: &str = "plugin_list";
+const ENABLED_ID: &str = "plugin_enabled";
+const EXISTING_ID: &str = "plugin_existing";
+
+fn setting_id(setting: &Setting) -> String {
+    format!("plugin_setting_{}", setting.name)
+}
+
+/// Open the list of plugins, or bring it to the front if it's already open
+pub fn show(siv: &mut Cursive, pipe: &Arc<Pipe>) {
+    if let Some(layer) = siv.screen_mut().find_layer_from_id(LIST_ID) {
+        siv.screen_mut().move_to_front(layer);
+        return;
+    }
+
+    let mut list = SelectView::new();
+    for plugin in pipe.plugins() {
+        let label = format!(
+            "[{}] {} - {}",
+            if plugin.enabled { "x" } else { " " },
+            plugin.name,
+            plugin.description
+        );
+        list.add_item(label, plugin);
+    }
+
+    let pipe = Arc::clone(pipe);
+    let list = list.on_submit(move |s, plugin: &PluginInfo| {
+        // the list is opened again afterwards, so it's up to date
+        s.pop_layer();
+        show_plugin(s, &pipe, plugin);
+    });
+
+    siv.add_layer(
+        Dialog::around(list)
+            .title("Plugins")
+            .button("Close", |s| {
+                s.pop_layer();
+            })
+            .with_id(LIST_ID)
+            .min_width(48),
+    );
+}
+
+/// Open the settings of a single plugin
+fn show_plugin(siv: &mut Cursive, pipe: &Arc<Pipe>, plugin: &PluginInfo) {
+    let mut enabled = Checkbox::new();
+    enabled.set_checked(plugin.enabled);
+
+    let mut form = ListView::new()
+        .child("Enabled", enabled.with_id(ENABLED_ID))
+        .child(
+            "Apply to existing sessions",
+            Checkbox::new().with_id(EXISTING_ID),
+        )
+        .delimiter();
+
+    for setting in &plugin.settings {
+        let id = setting_id(setting);
+        let label = format!("{} ({})", setting.name, setting.description);
+
+        form = match &setting.value {
+            SettingValue::Bool(value) => {
+                let mut checkbox = Checkbox::new();
+                checkbox.set_checked(*value);
+                form.child(&label, checkbox.with_id(id))
+            }
+            value => form.child(
+                &label,
+                EditView::new()
+                    .content(value.to_string())
+                    .with_id(id)
+                    .min_width(16),
+            ),
+        };
+    }
+
+    let (save_pipe, save_plugin) = (Arc::clone(pipe), plugin.clone());
+    let cancel_pipe = Arc::clone(pipe);
+    siv.add_layer(
+        Dialog::around(form)
+            .title(format!("Plugin: {}", plugin.name))
+            .button("Save", move |s| save(s, &save_pipe, &save_plugin))
+            .button("Cancel", move |s| {
+                s.pop_layer();
+                show(s, &cancel_pipe);
+            }),
+    );
+}
+
+/// Read the value of a setting back from the form
+fn read_setting(siv: &mut Cursive, setting: &Setting) -> Result<SettingValue, String> {
+    let id = setting_id(setting);
+
+    match &setting.value {
+        SettingValue::Bool(_) => siv
+            .call_on_id(&id, |v: &mut Checkbox| SettingValue::Bool(v.is_checked()))
+            .ok_or_else(|| format!("missing field for {}", setting.name)),
+        SettingValue::Integer(_) => siv
+            .call_on_id(&id, |v: &mut EditView| v.get_content())
+            .ok_or_else(|| format!("missing field for {}", setting.name))?
+            .trim()
+            .parse()
+            .map(SettingValue::Integer)
+            .map_err(|e| format!("invalid value for {}: {}", setting.name, e)),
+        SettingValue::Text(_) => siv
+            .call_on_id(&id, |v: &mut EditView| {
+                SettingValue::Text(v.get_content().to_string())
+            })
+            .ok_or_else(|| format!("missing field for {}", setting.name)),
+    }
+}
+
+/// Apply the changes made in the form for a plugin
+fn save(siv: &mut Cursive, pipe: &Arc<Pipe>, plugin: &PluginInfo) {
+    let enabled = siv.call_on_id(ENABLED_ID, |v: &mut Checkbox| v.is_checked());
+    let existing = siv.call_on_id(EXISTING_ID, |v: &mut Checkbox| v.is_checked());
+
+    // only update the settings that were changed
+    let mut errors = Vec::new();
+    for setting in &plugin.settings {
+        let result = read_setting(siv, setting).and_then(|value| {
+            if value == setting.value {
+                return Ok(());
+            }
+
+            info!("Setting {}.{} to {}", plugin.name, setting.name, value);
+            pipe.update_plugin_setting(&plugin.name, &setting.name, value)
+                .map_err(|e| e.to_string())
+        });
+
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+
+    if let Some(enabled) = enabled {
+        if enabled != plugin.enabled || existing == Some(true) {
+            pipe.set_plugin_enabled(&plugin.name, enabled, existing == Some(true));
+        }
+    }
+
+    if errors.is_empty() {
+        // go back to the list of plugins
+        siv.pop_layer();
+        show(siv, pipe);
+    } else {
+        siv.add_layer(Dialog::info(errors.join("\n")).title("Invalid settings"));
+    }
+}
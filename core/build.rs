@@ -0,0 +1,54 @@
+//! Record the compiler version and a hash of the packet definitions, used to
+//! check that dynamically loaded plugins are compatible.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directories containing code which affects the layout of packets
+const PACKET_DIRS: &[&str] = &["src/packets", "src/gamedata", "src/adapters"];
+
+/// Hash some bytes using 64-bit FNV-1a, continuing from `hash`
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn main() {
+    // get the version of the compiler building this crate
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let output = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .expect("error getting rustc version");
+    let version = String::from_utf8_lossy(&output.stdout);
+    println!("cargo:rustc-env=REALMPIPE_RUSTC_VERSION={}", version.trim());
+
+    // hash the packet definitions, in a consistent order
+    let mut files: Vec<PathBuf> = PACKET_DIRS
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).expect("error reading packet definitions"))
+        .map(|entry| entry.expect("error reading packet definitions").path())
+        .filter(|path| path.extension() == Some(OsStr::new("rs")))
+        .collect();
+    files.sort();
+
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for file in &files {
+        let contents = fs::read(file).expect("error reading packet definitions");
+        hash = fnv1a(hash, &contents);
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+
+    // also check the directories, in case files are added or removed
+    for dir in PACKET_DIRS {
+        println!("cargo:rerun-if-changed={}", dir);
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-env=REALMPIPE_PACKETS_HASH={:016x}", hash);
+}
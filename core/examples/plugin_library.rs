@@ -0,0 +1,32 @@
+//! A minimal plugin library, built as a `cdylib` and loaded by the tests in
+//! `tests/dynamic.rs`
+
+use realmpipe_core::declare_plugin;
+use realmpipe_core::dynamic::PluginRegistrar;
+use realmpipe_core::pipe::{Plugin, PluginState, SessionSetup};
+
+struct Example;
+
+struct ExampleState;
+
+impl PluginState for ExampleState {}
+
+impl Plugin for Example {
+    fn name(&self) -> &str {
+        "example"
+    }
+
+    fn description(&self) -> &str {
+        "An example plugin from a dynamic library"
+    }
+
+    fn init_plugin(&mut self, _setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        Box::new(ExampleState)
+    }
+}
+
+fn register(registrar: &mut dyn PluginRegistrar) {
+    registrar.register("example", Box::new(|| Box::new(Example)));
+}
+
+declare_plugin!(register);
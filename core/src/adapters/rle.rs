@@ -3,6 +3,7 @@
 
 use super::prelude::*;
 use num::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::iter::IntoIterator;
 use std::marker::PhantomData;
@@ -136,6 +137,20 @@ impl<T: Clone, S> Clone for RLE<T, S> {
     }
 }
 
+// the length prefix only matters on the network, so other formats just see
+// the contained value
+impl<T: Serialize, S> Serialize for RLE<T, S> {
+    fn serialize<R: Serializer>(&self, serializer: R) -> std::result::Result<R::Ok, R::Error> {
+        self.inner.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, S> Deserialize<'de> for RLE<T, S> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
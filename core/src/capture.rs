@@ -0,0 +1,423 @@
+//! Recording packets to capture files, which can be decoded or replayed later.
+//!
+//! A capture file starts with a header containing the mappings used by the
+//! session, so it can be decoded without any other files. Every packet is then
+//! recorded as it was received, after decryption, along with the time it was
+//! received and the side it was sent from.
+//!
+//! All integers are big-endian. The header consists of:
+//!
+//! * the magic bytes `RPCAP`
+//! * the format version, as a `u16`
+//! * the length of the mappings, as a `u32`
+//! * the mappings, as JSON
+//!
+//! Each packet is then recorded as:
+//!
+//! * the time it was received, as a `u64` number of milliseconds since the
+//!   Unix epoch
+//! * the side it was sent from, as a `u8` (0 for the client, 1 for the server)
+//! * the raw packet, which starts with its own length as a `u32`
+
+use crate::mappings::Mappings;
+use crate::pipe::{
+    AutoPacket, DisconnectReason, PacketContext, PacketSide, Plugin, PluginState, SessionSetup,
+    Setting, SettingError, SettingValue,
+};
+use crate::proxy::raw::RawPacket;
+use bytes::Bytes;
+use failure_derive::Fail;
+use log::{info, warn};
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, BufWriter, Error as IoError, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The magic bytes at the start of every capture file
+pub const MAGIC: &[u8; 5] = b"RPCAP";
+
+/// The current version of the capture format
+pub const VERSION: u16 = 1;
+
+/// The file extension used for capture files
+pub const CAPTURE_EXTENSION: &str = "rpcap";
+
+/// The largest packet which may be read from a capture file, to avoid huge
+/// allocations when reading corrupt files
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// The largest mappings which may be read from the header of a capture file,
+/// for the same reason
+const MAX_MAPPINGS_SIZE: usize = 16 * 1024 * 1024;
+
+/// An error reading or writing a capture file
+#[derive(Debug, Fail)]
+pub enum CaptureError {
+    /// An error reading or writing the underlying file
+    #[fail(display = "IO error: {}", _0)]
+    IoError(IoError),
+
+    /// The file doesn't start with the magic bytes
+    #[fail(display = "not a capture file")]
+    InvalidMagic,
+
+    /// The file uses a version of the format which isn't supported
+    #[fail(display = "unsupported capture version: {}", _0)]
+    UnsupportedVersion(u16),
+
+    /// The header of the file is corrupt
+    #[fail(display = "invalid header: {}", _0)]
+    InvalidHeader(String),
+
+    /// The mappings in the header couldn't be read
+    #[fail(display = "invalid mappings: {}", _0)]
+    InvalidMappings(serde_json::Error),
+
+    /// A packet record is corrupt
+    #[fail(display = "invalid record: {}", _0)]
+    InvalidRecord(String),
+}
+
+impl From<IoError> for CaptureError {
+    fn from(e: IoError) -> Self {
+        CaptureError::IoError(e)
+    }
+}
+
+/// The result of reading or writing a capture file
+pub type Result<T> = std::result::Result<T, CaptureError>;
+
+/// A packet recorded in a capture file
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// The time the packet was received
+    pub time: SystemTime,
+
+    /// The side of the connection the packet was sent from
+    pub side: PacketSide,
+
+    /// The packet itself, as it was received
+    pub raw: RawPacket,
+}
+
+/// Writes packets to a capture file
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Create a new writer, writing the header with the given mappings
+    pub fn new(mut writer: W, mappings: &Mappings) -> Result<Self> {
+        let mappings = serde_json::to_vec(mappings).map_err(CaptureError::InvalidMappings)?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&(mappings.len() as u32).to_be_bytes())?;
+        writer.write_all(&mappings)?;
+
+        Ok(Self { writer })
+    }
+
+    /// Record a packet
+    pub fn write(&mut self, packet: &CapturedPacket) -> Result<()> {
+        let millis = packet
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let side: u8 = match packet.side {
+            PacketSide::Client => 0,
+            PacketSide::Server => 1,
+        };
+
+        self.writer.write_all(&millis.to_be_bytes())?;
+        self.writer.write_all(&[side])?;
+        self.writer.write_all(&packet.raw.clone().into_bytes())?;
+        Ok(())
+    }
+
+    /// Flush any buffered packets to the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Reads packets from a capture file. Packets are read by iterating over the
+/// reader, which stops at the end of the file.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    mappings: Mappings,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open a capture file
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Create a new reader, reading the header
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::InvalidMagic);
+        }
+
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_be_bytes(version);
+        if version != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MAPPINGS_SIZE {
+            return Err(CaptureError::InvalidHeader(format!(
+                "invalid mappings size {}",
+                len
+            )));
+        }
+
+        let mut mappings = vec![0u8; len];
+        reader.read_exact(&mut mappings)?;
+        let mappings = serde_json::from_slice(&mappings).map_err(CaptureError::InvalidMappings)?;
+
+        Ok(Self { reader, mappings })
+    }
+
+    /// Get the mappings used by the recorded session
+    pub fn mappings(&self) -> &Mappings {
+        &self.mappings
+    }
+
+    /// Read the next packet, returning `None` at the end of the file
+    fn read_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        let mut header = [0u8; 13];
+        match self.reader.read_exact(&mut header[..1]) {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        self.reader.read_exact(&mut header[1..])?;
+
+        let mut millis = [0u8; 8];
+        millis.copy_from_slice(&header[..8]);
+        let time = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis));
+
+        let side = match header[8] {
+            0 => PacketSide::Client,
+            1 => PacketSide::Server,
+            other => {
+                return Err(CaptureError::InvalidRecord(format!(
+                    "invalid side {}",
+                    other
+                )))
+            }
+        };
+
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&header[9..]);
+        let size = u32::from_be_bytes(size) as usize;
+        if !(5..=MAX_PACKET_SIZE).contains(&size) {
+            return Err(CaptureError::InvalidRecord(format!(
+                "invalid packet size {}",
+                size
+            )));
+        }
+
+        let mut bytes = vec![0u8; size];
+        bytes[..4].copy_from_slice(&header[9..]);
+        self.reader.read_exact(&mut bytes[4..])?;
+
+        Ok(Some(CapturedPacket {
+            time,
+            side,
+            raw: RawPacket::new(Bytes::from(bytes)),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_packet().transpose()
+    }
+}
+
+/// A plugin recording each session to a capture file in a directory
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+impl Recorder {
+    /// Create a recorder saving captures to the given directory
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl Plugin for Recorder {
+    fn name(&self) -> &str {
+        "recorder"
+    }
+
+    fn description(&self) -> &str {
+        "Records every packet to a capture file for each session"
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        vec![Setting::new(
+            "directory",
+            "The directory to save capture files to",
+            SettingValue::Text(self.dir.display().to_string()),
+        )]
+    }
+
+    fn update_setting(
+        &mut self,
+        name: &str,
+        value: SettingValue,
+    ) -> std::result::Result<(), SettingError> {
+        match (name, value) {
+            ("directory", SettingValue::Text(dir)) => {
+                self.dir = dir.into();
+                Ok(())
+            }
+            ("directory", value) => Err(SettingError::InvalidValue(
+                name.to_string(),
+                value.to_string(),
+            )),
+            _ => Err(SettingError::UnknownSetting(name.to_string())),
+        }
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!(
+            "session-{}-{}.{}",
+            started,
+            setup.session_id().0,
+            CAPTURE_EXTENSION
+        );
+
+        Box::new(RecorderState::Pending(self.dir.join(name)))
+    }
+}
+
+enum RecorderState {
+    /// The file will be created once the mappings are known, when the first
+    /// packet is received
+    Pending(PathBuf),
+    Recording(CaptureWriter<BufWriter<File>>),
+    Failed,
+}
+
+impl RecorderState {
+    fn record(&mut self, packet: &AutoPacket, side: PacketSide) -> Result<()> {
+        if let RecorderState::Pending(path) = self {
+            if let Some(dir) = path.parent() {
+                create_dir_all(dir)?;
+            }
+            let file = BufWriter::new(File::create(&path)?);
+            info!("Recording session to {}", path.display());
+            *self = RecorderState::Recording(CaptureWriter::new(file, packet.get_mappings())?);
+        }
+
+        if let RecorderState::Recording(writer) = self {
+            writer.write(&CapturedPacket {
+                time: SystemTime::now(),
+                side,
+                raw: packet.get_raw().clone(),
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PluginState for RecorderState {
+    fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        if let Some(side) = context.side() {
+            if let Err(e) = self.record(packet, side) {
+                warn!("Error recording packet, stopping recording: {}", e);
+                *self = RecorderState::Failed;
+            }
+        }
+    }
+
+    fn on_disconnect(&mut self, _reason: DisconnectReason) {
+        if let RecorderState::Recording(writer) = self {
+            if let Err(e) = writer.flush() {
+                warn!("Error saving recording: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::mappings::RC4_LEN;
+    use crate::packets::{client, InternalPacketId, Packet};
+    use assert_matches::assert_matches;
+    use bimap::BiHashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        let mappings = Mappings::new("00".repeat(RC4_LEN), ids).unwrap();
+        let packet = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("hello".to_owned()),
+        });
+        let time = UNIX_EPOCH + Duration::from_millis(1_234_567);
+
+        let mut writer = CaptureWriter::new(Vec::new(), &mappings).unwrap();
+        for side in &[PacketSide::Client, PacketSide::Server] {
+            writer
+                .write(&CapturedPacket {
+                    time,
+                    side: *side,
+                    raw: RawPacket::from_packet(packet.clone(), &mappings).unwrap(),
+                })
+                .unwrap();
+        }
+        let mut file = writer.writer;
+
+        let reader = CaptureReader::new(Cursor::new(&file)).unwrap();
+        assert_eq!(
+            reader.mappings().get_packet_mappings(),
+            mappings.get_packet_mappings()
+        );
+        let packets = reader.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].time, time);
+        assert_eq!(packets[1].side, PacketSide::Server);
+        assert_eq!(packets[1].raw.to_packet(&mappings).unwrap(), packet);
+
+        // a truncated record is an error, rather than the end of the file
+        file.pop();
+        let mut reader = CaptureReader::new(Cursor::new(&file)).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert_matches!(reader.next(), Some(Err(CaptureError::IoError(_))));
+
+        // as is a header claiming to have huge mappings
+        let mut header = MAGIC.to_vec();
+        header.extend(&VERSION.to_be_bytes());
+        header.extend(&u32::MAX.to_be_bytes());
+        assert_matches!(
+            CaptureReader::new(Cursor::new(&header)).err(),
+            Some(CaptureError::InvalidHeader(_))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! A headless game client, which can join a server and play a character
+//! without the real game client, e.g. for uptime monitors or scripted account
+//! tasks.
+//!
+//! The client logs in with `Hello` and `Load`, then keeps the session alive by
+//! acknowledging packets the same way as the game client. While it's running,
+//! it can be controlled through a `ClientHandle`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use realmpipe_core::client::{Client, ClientConfig};
+//! use realmpipe_core::gamedata::WorldPosData;
+//! # use bimap::BiHashMap;
+//! # use realmpipe_core::mappings::{Mappings, RC4_LEN};
+//! use std::sync::Arc;
+//! use tokio::prelude::*;
+//!
+//! # let mappings = || Mappings::new("00".repeat(RC4_LEN), BiHashMap::new()).unwrap();
+//! let config = ClientConfig::new("guid@example.com", "password", "X31.2.3", 1);
+//! let address = "127.0.0.1:2050".parse().unwrap();
+//!
+//! let client = Client::connect(&address, Arc::new(mappings()), config)
+//!     .map_err(|e| eprintln!("Error connecting: {}", e))
+//!     .and_then(|client| {
+//!         let handle = client.handle();
+//!         handle.chat("hello!").unwrap();
+//!         handle.move_to(WorldPosData { x: 100.0, y: 120.0 }).unwrap();
+//!         client.map_err(|e| eprintln!("Client error: {}", e))
+//!     });
+//!
+//! tokio::run(client);
+//! ```
+
+use crate::adapters::RLE;
+use crate::gamedata::WorldPosData;
+use crate::mappings::Mappings;
+use crate::packets::{client, Packet};
+use crate::proxy::codec::CodecError;
+use crate::proxy::raw::RawPacket;
+use crate::proxy::{server_connection, Connection, Transport};
+use failure_derive::Fail;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::convert::From;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tokio::prelude::*;
+
+/// The game ID used to connect to the nexus
+pub const NEXUS_GAME_ID: u32 = 0xffff_fffe;
+
+/// The speed the player moves at by default, in tiles per second
+pub const DEFAULT_SPEED: f32 = 4.0;
+
+/// The details used to log in and play
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The GUID of the account, usually its email address
+    pub guid: String,
+
+    /// The password of the account
+    pub password: String,
+
+    /// The build version of the game client to pretend to be
+    pub build_version: String,
+
+    /// The ID of the game to join, e.g. `NEXUS_GAME_ID`, or the game ID from a
+    /// `Reconnect`
+    pub game_id: u32,
+
+    /// The key time from a `Reconnect`, if following one
+    pub key_time: u32,
+
+    /// The key from a `Reconnect`, if following one
+    pub key: Vec<u8>,
+
+    /// The ID of the character to play
+    pub char_id: u32,
+
+    /// The speed the player moves at, in tiles per second
+    pub speed: f32,
+}
+
+impl ClientConfig {
+    /// Create the config to play the given character in the nexus
+    pub fn new(guid: &str, password: &str, build_version: &str, char_id: u32) -> Self {
+        Self {
+            guid: guid.to_owned(),
+            password: password.to_owned(),
+            build_version: build_version.to_owned(),
+            game_id: NEXUS_GAME_ID,
+            key_time: u32::MAX,
+            key: Vec::new(),
+            char_id,
+            speed: DEFAULT_SPEED,
+        }
+    }
+}
+
+/// An error which ended a client's session
+#[derive(Debug, Fail)]
+pub enum ClientError {
+    /// An error reading or writing a packet
+    #[fail(display = "codec error: {}", _0)]
+    CodecError(CodecError),
+
+    /// The server sent a `Failure` packet
+    #[fail(display = "server failure {}: {}", id, description)]
+    Failure {
+        /// The error ID sent by the server
+        id: u32,
+
+        /// The description of the error sent by the server
+        description: String,
+    },
+}
+
+impl From<CodecError> for ClientError {
+    fn from(e: CodecError) -> Self {
+        ClientError::CodecError(e)
+    }
+}
+
+/// An error returned when using a `ClientHandle` for a client which has
+/// already disconnected
+#[derive(Debug, Fail)]
+#[fail(display = "client closed")]
+pub struct ClientClosed;
+
+/// A snapshot of what a client knows about its session
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientStatus {
+    /// The name of the current map, once `MapInfo` has been received
+    pub map: Option<String>,
+
+    /// The object ID of the player, once `CreateSuccess` has been received
+    pub player_id: Option<u32>,
+
+    /// The position of the player, once it's known
+    pub pos: Option<WorldPosData>,
+
+    /// The position the player is moving towards, if any
+    pub target: Option<WorldPosData>,
+
+    /// The ID of the last tick received
+    pub tick_id: Option<u32>,
+}
+
+/// A request sent to a client through a `ClientHandle`
+#[derive(Debug)]
+enum ClientCommand {
+    MoveTo(WorldPosData),
+    SendPacket(Box<Packet>),
+    Disconnect,
+}
+
+/// A cloneable handle to a running client, which may be used to control it
+/// from another task or thread
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    sender: UnboundedSender<ClientCommand>,
+    status: Arc<Mutex<ClientStatus>>,
+}
+
+impl ClientHandle {
+    fn command(&self, command: ClientCommand) -> Result<(), ClientClosed> {
+        self.sender
+            .unbounded_send(command)
+            .map_err(|_| ClientClosed)
+    }
+
+    /// Get a snapshot of the client's session
+    pub fn status(&self) -> ClientStatus {
+        self.status
+            .lock()
+            .expect("error acquiring client status lock")
+            .clone()
+    }
+
+    /// Walk towards the given position in a straight line, one step each tick
+    pub fn move_to(&self, pos: WorldPosData) -> Result<(), ClientClosed> {
+        self.command(ClientCommand::MoveTo(pos))
+    }
+
+    /// Send a chat message, or a command if it starts with `/`
+    pub fn chat(&self, text: &str) -> Result<(), ClientClosed> {
+        self.send_packet(Packet::PlayerText(client::PlayerText {
+            text: RLE::new(text.to_owned()),
+        }))
+    }
+
+    /// Enter the portal with the given object ID
+    pub fn use_portal(&self, object_id: u32) -> Result<(), ClientClosed> {
+        self.send_packet(Packet::UsePortal(client::UsePortal { object_id }))
+    }
+
+    /// Send any packet to the server. If an error occurs encoding the packet,
+    /// the error will be emitted as a warning, and the packet will be skipped.
+    pub fn send_packet(&self, packet: Packet) -> Result<(), ClientClosed> {
+        self.command(ClientCommand::SendPacket(Box::new(packet)))
+    }
+
+    /// Disconnect from the server once any packets already requested have
+    /// been sent
+    pub fn disconnect(&self) -> Result<(), ClientClosed> {
+        self.command(ClientCommand::Disconnect)
+    }
+}
+
+/// A future playing a session on a server until it's disconnected, either by
+/// the server or through a `ClientHandle`. `Reconnect` packets are not
+/// followed, but may be handled using `ClientConfig::game_id` and a new
+/// client.
+pub struct Client<T> {
+    conn: T,
+    mappings: Arc<Mappings>,
+    config: ClientConfig,
+    started: Instant,
+    status: Arc<Mutex<ClientStatus>>,
+    sender: UnboundedSender<ClientCommand>,
+    commands: UnboundedReceiver<ClientCommand>,
+    queue: VecDeque<RawPacket>,
+    closing: bool,
+}
+
+impl Client<Connection> {
+    /// Connect to the server at the given address
+    pub fn connect(
+        address: &SocketAddr,
+        mappings: Arc<Mappings>,
+        config: ClientConfig,
+    ) -> impl Future<Item = Self, Error = IoError> + Send {
+        server_connection(address, Arc::clone(&mappings))
+            .map(move |conn| Self::new(conn, mappings, config))
+    }
+}
+
+impl<T: Transport> Client<T> {
+    /// Start a client on a connection which has already been opened, sending
+    /// `Hello` straight away
+    pub fn new(conn: T, mappings: Arc<Mappings>, config: ClientConfig) -> Self {
+        let (sender, commands) = unbounded();
+
+        let mut client = Self {
+            conn,
+            mappings,
+            config,
+            started: Instant::now(),
+            status: Arc::new(Mutex::new(ClientStatus::default())),
+            sender,
+            commands,
+            queue: VecDeque::new(),
+            closing: false,
+        };
+
+        let hello = client.hello();
+        client.send(hello);
+        client
+    }
+
+    /// Get a handle to control this client
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            sender: self.sender.clone(),
+            status: Arc::clone(&self.status),
+        }
+    }
+
+    fn status(&self) -> MutexGuard<'_, ClientStatus> {
+        self.status
+            .lock()
+            .expect("error acquiring client status lock")
+    }
+
+    /// Get the time since the client started, as sent in acknowledgements
+    fn time(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    fn hello(&self) -> Packet {
+        let empty = || RLE::new(String::new());
+
+        Packet::Hello(client::Hello {
+            build_version: RLE::new(self.config.build_version.clone()),
+            game_id: self.config.game_id,
+            guid: RLE::new(self.config.guid.clone()),
+            rand1: 0,
+            password: RLE::new(self.config.password.clone()),
+            rand2: 0,
+            secret: empty(),
+            key_time: self.config.key_time,
+            key: RLE::new(self.config.key.clone()),
+            map_json: RLE::new(String::new()),
+            entry_tag: empty(),
+            game_net: RLE::new("rotmg".to_owned()),
+            game_net_user_id: empty(),
+            play_platform: RLE::new("rotmg".to_owned()),
+            platform_token: empty(),
+            user_token: empty(),
+        })
+    }
+
+    /// Queue a packet to be sent to the server. If an error occurs encoding
+    /// the packet, the error will be emitted as a warning, and the packet will
+    /// be skipped.
+    fn send(&mut self, packet: Packet) {
+        match RawPacket::from_packet(packet, &self.mappings) {
+            Ok(raw) => self.queue.push_back(raw),
+            Err(e) => warn!("Error encoding packet: {}", e),
+        }
+    }
+
+    /// Move the player one step towards its target, returning its new position,
+    /// or `None` if its position isn't known yet
+    fn step(&mut self, tick_time: u32) -> Option<WorldPosData> {
+        let max_step = self.config.speed * tick_time as f32 / 1000.0;
+        let mut status = self.status();
+
+        let pos = match (status.pos.clone(), status.target.take()) {
+            (Some(pos), Some(target)) => {
+                let (dx, dy) = (target.x - pos.x, target.y - pos.y);
+                let distance = (dx * dx + dy * dy).sqrt();
+
+                if distance <= max_step {
+                    target
+                } else {
+                    status.target = Some(target);
+                    WorldPosData {
+                        x: pos.x + dx / distance * max_step,
+                        y: pos.y + dy / distance * max_step,
+                    }
+                }
+            }
+            (Some(pos), None) => pos,
+            (None, target) => {
+                // keep the target until the player's position is known
+                status.target = target;
+                return None;
+            }
+        };
+
+        status.pos = Some(pos.clone());
+        Some(pos)
+    }
+
+    /// Handle a packet from the server, sending any acknowledgements it needs
+    fn handle_packet(&mut self, raw: RawPacket) -> Result<(), ClientError> {
+        let packet = match raw.to_packet(&self.mappings) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Client ignoring packet: {}", e);
+                return Ok(());
+            }
+        };
+
+        let time = self.time();
+        let player_id = self.status().player_id;
+
+        match packet {
+            Packet::MapInfo(info) => {
+                self.status().map = Some(info.name.to_string());
+                self.send(Packet::Load(client::Load {
+                    char_id: self.config.char_id,
+                    from_arena: false,
+                }));
+            }
+            Packet::CreateSuccess(success) => {
+                self.status().player_id = Some(success.object_id);
+            }
+            Packet::Failure(failure) => {
+                return Err(ClientError::Failure {
+                    id: failure.error_id,
+                    description: failure.error_description.to_string(),
+                });
+            }
+            Packet::Update(update) => {
+                let player = update
+                    .new_objs
+                    .iter()
+                    .find(|obj| Some(obj.status.object_id) == player_id);
+
+                if let Some(player) = player {
+                    self.status().pos = Some(player.status.pos.clone());
+                }
+                self.send(Packet::UpdateAck(client::UpdateAck {}));
+            }
+            Packet::NewTick(tick) => {
+                self.status().tick_id = Some(tick.tick_id);
+
+                // the player isn't in the world until its position is known
+                if let Some(new_pos) = self.step(tick.tick_time) {
+                    self.send(Packet::Move(client::Move {
+                        tick_id: tick.tick_id,
+                        time,
+                        new_pos,
+                        records: RLE::new(Vec::new()),
+                    }));
+                }
+            }
+            Packet::Ping(ping) => self.send(Packet::Pong(client::Pong {
+                serial: ping.serial,
+                time,
+            })),
+            Packet::Goto(goto) => {
+                if Some(goto.object_id) == player_id {
+                    self.status().pos = Some(goto.pos);
+                }
+                self.send(Packet::GotoAck(client::GotoAck { time }));
+            }
+            Packet::EnemyShoot(_) => self.send(Packet::ShootAck(client::ShootAck { time })),
+            Packet::ServerPlayerShoot(shoot) if Some(shoot.owner_id) == player_id => {
+                self.send(Packet::ShootAck(client::ShootAck { time }))
+            }
+            Packet::Aoe(_) => {
+                if let Some(pos) = self.step(0) {
+                    self.send(Packet::AoeAck(client::AoeAck { time, pos }));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Carry out any requests made through client handles
+    fn poll_commands(&mut self) -> bool {
+        let mut received = false;
+
+        // receiving from an unbounded channel can't fail
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll() {
+            received = true;
+
+            match command {
+                ClientCommand::MoveTo(pos) => self.status().target = Some(pos),
+                ClientCommand::SendPacket(packet) => self.send(*packet),
+                ClientCommand::Disconnect => self.closing = true,
+            }
+        }
+
+        received
+    }
+
+    /// Send as many queued packets as possible, returning whether they've all
+    /// been flushed
+    fn flush(&mut self) -> Result<bool, ClientError> {
+        while let Some(raw) = self.queue.pop_front() {
+            if let AsyncSink::NotReady(raw) = self.conn.start_send(raw)? {
+                self.queue.push_front(raw);
+                break;
+            }
+        }
+
+        Ok(self.conn.poll_complete()?.is_ready() && self.queue.is_empty())
+    }
+}
+
+impl<T: Transport> Future for Client<T> {
+    type Item = ();
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut progress = self.poll_commands();
+
+            // once disconnecting, we just finish sending what's queued
+            if !self.closing {
+                match self.conn.poll()? {
+                    Async::Ready(Some(raw)) => {
+                        self.handle_packet(raw)?;
+                        progress = true;
+                    }
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady => {}
+                }
+            }
+
+            let flushed = self.flush()?;
+            if self.closing && flushed {
+                return Ok(Async::Ready(()));
+            } else if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
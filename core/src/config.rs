@@ -0,0 +1,464 @@
+//! Declarative configuration for a proxy, loaded from a TOML or JSON file.
+//!
+//! A `Config` describes everything needed to build a `Pipe`: where to listen,
+//! where to find the mappings, which servers to use and which plugins to
+//! enable. Plugins are created by name using a `PluginRegistry`.
+//!
+//! # Examples
+//!
+//! ```
+//! use realmpipe_core::config::{Config, MappingsSource};
+//!
+//! let config = Config::from_toml(r#"
+//!     listen = "127.0.0.1:2050"
+//!     default_server = "usw"
+//!
+//!     [mappings]
+//!     file = "mappings.json"
+//!
+//!     [servers]
+//!     official = false
+//!
+//!     [servers.overrides]
+//!     USWest = "127.0.0.1"
+//!
+//!     [[plugins]]
+//!     name = "example"
+//!     enabled = false
+//!
+//!     [plugins.settings]
+//!     greeting = "hello"
+//! "#).expect("error parsing config");
+//!
+//! assert_eq!(
+//!     config.mappings,
+//!     MappingsSource::File {
+//!         file: "mappings.json".into()
+//!     }
+//! );
+//! assert_eq!(config.plugins[0].name, "example");
+//! ```
+
+use crate::mappings::Mappings;
+use crate::pipe::{Pipe, PipeBuilder, Plugin, SettingError, SettingValue};
+use crate::serverlist::ServerList;
+use failure_derive::Fail;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Error as IoError};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An error loading or applying a config
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    /// An error reading a file
+    #[fail(display = "error reading {}: {}", _0, _1)]
+    IoError(String, IoError),
+
+    /// The config file has an unsupported extension
+    #[fail(
+        display = "unsupported config format: {} (expected .toml or .json)",
+        _0
+    )]
+    UnknownFormat(String),
+
+    /// The config isn't valid TOML, or has the wrong structure
+    #[fail(display = "invalid TOML config: {}", _0)]
+    TomlError(toml::de::Error),
+
+    /// The config or mappings file isn't valid JSON, or has the wrong
+    /// structure
+    #[fail(display = "invalid JSON: {}", _0)]
+    JsonError(serde_json::Error),
+
+    /// The mappings couldn't be loaded
+    #[fail(display = "error loading mappings: {}", _0)]
+    MappingsError(String),
+
+    /// The server list is empty
+    #[fail(display = "no servers configured")]
+    NoServers,
+
+    /// The default server isn't in the server list
+    #[fail(display = "unknown default server: {}", _0)]
+    UnknownServer(String),
+
+    /// No plugin with the given name has been registered
+    #[fail(display = "unknown plugin: {}", _0)]
+    UnknownPlugin(String),
+
+    /// The same plugin was configured more than once
+    #[fail(display = "plugin configured more than once: {}", _0)]
+    DuplicatePlugin(String),
+
+    /// A plugin rejected one of its settings
+    #[fail(display = "invalid settings for plugin {}: {}", _0, _1)]
+    SettingError(String, SettingError),
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::TomlError(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::JsonError(e)
+    }
+}
+
+/// The result of loading or applying a config
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 2050))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Where to get the mappings used to proxy traffic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MappingsSource {
+    /// A JSON file containing mappings previously generated by the extractor
+    File {
+        /// The path to the file
+        file: PathBuf,
+    },
+
+    /// A game client SWF to extract mappings from. Extraction is handled by
+    /// the extractor crate, so `MappingsSource::load` can't be used for this.
+    Swf {
+        /// The path to the game client
+        swf: PathBuf,
+    },
+}
+
+impl MappingsSource {
+    /// Resolve relative paths against the given directory
+    fn relative_to(&mut self, dir: &Path) {
+        let path = match self {
+            MappingsSource::File { file: path } | MappingsSource::Swf { swf: path } => path,
+        };
+
+        *path = dir.join(&*path);
+    }
+
+    /// Load mappings from a file. This can't be used to extract mappings
+    /// from a game client.
+    pub fn load(&self) -> Result<Mappings> {
+        match self {
+            MappingsSource::File { file: path } => {
+                let file = File::open(path)
+                    .map_err(|e| ConfigError::IoError(path.display().to_string(), e))?;
+                Ok(serde_json::from_reader(BufReader::new(file))?)
+            }
+            MappingsSource::Swf { .. } => Err(ConfigError::MappingsError(
+                "mappings must be extracted from the game client".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Which servers clients may connect to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Whether to use the official server list
+    #[serde(default = "default_true")]
+    pub official: bool,
+
+    /// Servers to add to the list, replacing official servers with the same
+    /// name
+    #[serde(default)]
+    pub overrides: BTreeMap<String, IpAddr>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            official: true,
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+/// The configuration of a single plugin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// The name the plugin is registered with
+    pub name: String,
+
+    /// Whether the plugin should start out enabled
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// Values for the plugin's settings
+    #[serde(default)]
+    pub settings: BTreeMap<String, SettingValue>,
+}
+
+/// The configuration for a whole proxy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen for clients on
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+
+    /// The name or abbreviation of the server to send clients to by default
+    pub default_server: String,
+
+    /// Where to get the mappings
+    pub mappings: MappingsSource,
+
+    /// Which servers to use
+    #[serde(default)]
+    pub servers: ServerConfig,
+
+    /// A directory to load plugin libraries, scripts and WASM modules from,
+    /// making their plugins available to be configured
+    #[serde(default)]
+    pub plugin_dir: Option<PathBuf>,
+
+    /// The plugins to use, in the order they should be invoked
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+}
+
+impl Config {
+    /// Parse a config from TOML
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Parse a config from JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Load a config from a `.toml` or `.json` file. Relative paths in the
+    /// config are resolved against the directory containing the file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::IoError(path.display().to_string(), e))?;
+
+        let mut config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml(&contents)?,
+            Some("json") => Self::from_json(&contents)?,
+            _ => return Err(ConfigError::UnknownFormat(path.display().to_string())),
+        };
+
+        if let Some(dir) = path.parent() {
+            config.mappings.relative_to(dir);
+            if let Some(plugin_dir) = &mut config.plugin_dir {
+                *plugin_dir = dir.join(&*plugin_dir);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Build the server list, adding the configured servers to the official
+    /// list if it's used
+    pub fn server_list(&self, official: Option<&ServerList>) -> Result<ServerList> {
+        let overrides: HashMap<_, _> = self
+            .servers
+            .overrides
+            .iter()
+            .map(|(name, ip)| (name, *ip))
+            .collect();
+        let mut servers = ServerList::new(&overrides).get_map().clone();
+
+        if self.servers.official {
+            for (name, ip) in official.map(ServerList::get_map).into_iter().flatten() {
+                servers.entry(name.clone()).or_insert(*ip);
+            }
+        }
+
+        if servers.is_empty() {
+            return Err(ConfigError::NoServers);
+        }
+
+        Ok(ServerList::new(&servers))
+    }
+
+    /// Create a pipe builder using this config, with the given mappings and
+    /// server list. Plugins are created using `registry`, then configured and
+    /// added to the builder in order.
+    pub fn builder(
+        &self,
+        mappings: Mappings,
+        servers: ServerList,
+        registry: &PluginRegistry,
+    ) -> Result<PipeBuilder> {
+        if servers.get_map().is_empty() {
+            return Err(ConfigError::NoServers);
+        } else if servers
+            .get_ip(&self.default_server.to_lowercase())
+            .is_none()
+        {
+            return Err(ConfigError::UnknownServer(self.default_server.clone()));
+        }
+
+        let mut builder = Pipe::builder()
+            .mappings(Arc::new(mappings))
+            .servers(servers, &self.default_server);
+
+        for (i, config) in self.plugins.iter().enumerate() {
+            if self.plugins[..i].iter().any(|c| c.name == config.name) {
+                return Err(ConfigError::DuplicatePlugin(config.name.clone()));
+            }
+
+            let mut plugin = registry
+                .create(&config.name)
+                .ok_or_else(|| ConfigError::UnknownPlugin(config.name.clone()))?;
+
+            for (name, value) in &config.settings {
+                plugin
+                    .update_setting(name, value.clone())
+                    .map_err(|e| ConfigError::SettingError(config.name.clone(), e))?;
+            }
+
+            // a plugin's own name may differ from the one it was registered
+            // with, and must be unique too
+            if builder.has_plugin(plugin.name()) {
+                return Err(ConfigError::DuplicatePlugin(plugin.name().to_owned()));
+            }
+
+            builder = builder.plugin_enabled(plugin, config.enabled);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// A function creating a new instance of a plugin
+pub type PluginFactory = Box<dyn Fn() -> Box<dyn Plugin> + Send + Sync>;
+
+/// A set of plugins which can be created by name, e.g. from a config file
+#[derive(Default)]
+pub struct PluginRegistry {
+    factories: BTreeMap<String, PluginFactory>,
+}
+
+impl PluginRegistry {
+    /// Register a plugin with the given name, replacing any plugin previously
+    /// registered with the same name
+    pub fn register<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn() -> Box<dyn Plugin> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+        self
+    }
+
+    /// Create a new instance of the plugin with the given name
+    pub fn create(&self, name: &str) -> Option<Box<dyn Plugin>> {
+        self.factories.get(name).map(|f| f())
+    }
+
+    /// Get the names of the registered plugins, in alphabetical order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::RC4_LEN;
+    use crate::pipe::{PluginState, SessionSetup};
+    use assert_matches::assert_matches;
+    use bimap::BiHashMap;
+
+    fn mappings() -> Mappings {
+        Mappings::new("00".repeat(RC4_LEN), BiHashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = Config::from_json(
+            r#"{
+                "default_server": "use",
+                "mappings": { "file": "mappings.json" },
+                "servers": { "official": false, "overrides": { "USWest": "127.0.0.1" } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, default_listen());
+
+        let registry = PluginRegistry::default();
+        let servers = config.server_list(None).unwrap();
+        assert_matches!(
+            config.builder(mappings(), servers.clone(), &registry).err(),
+            Some(ConfigError::UnknownServer(_))
+        );
+
+        config.default_server = "usw".to_owned();
+        config.plugins.push(PluginConfig {
+            name: "missing".to_owned(),
+            enabled: true,
+            settings: BTreeMap::new(),
+        });
+        assert_matches!(
+            config.builder(mappings(), servers, &registry).err(),
+            Some(ConfigError::UnknownPlugin(_))
+        );
+
+        config.servers.overrides.clear();
+        assert_matches!(config.server_list(None), Err(ConfigError::NoServers));
+    }
+
+    struct Named(&'static str);
+
+    impl Plugin for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn init_plugin(&mut self, _setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            Box::new(NoState)
+        }
+    }
+
+    struct NoState;
+
+    impl PluginState for NoState {}
+
+    #[test]
+    fn test_duplicate_plugin_names() {
+        let mut config = Config::from_json(
+            r#"{
+                "default_server": "usw",
+                "mappings": { "file": "mappings.json" },
+                "servers": { "official": false, "overrides": { "USWest": "127.0.0.1" } },
+                "plugins": [{ "name": "a" }, { "name": "b" }]
+            }"#,
+        )
+        .unwrap();
+        let servers = config.server_list(None).unwrap();
+
+        // two registry entries creating plugins with the same name
+        let mut registry = PluginRegistry::default();
+        registry
+            .register("a", || Box::new(Named("shared")))
+            .register("b", || Box::new(Named("shared")));
+        assert_matches!(
+            config.builder(mappings(), servers.clone(), &registry).err(),
+            Some(ConfigError::DuplicatePlugin(ref name)) if name == "shared"
+        );
+
+        config.plugins.pop();
+        let builder = config.builder(mappings(), servers, &registry).unwrap();
+        assert!(builder.has_plugin("shared"));
+        assert!(!builder.has_plugin("a"));
+    }
+}
@@ -0,0 +1,380 @@
+//! Loading plugins dynamically from shared libraries.
+//!
+//! Plugins may be built as separate `cdylib` crates depending on
+//! `realmpipe_core`, then loaded at startup. Each library declares its plugins
+//! using `declare_plugin!`, which records the versions it was built with.
+//! Since Rust has no stable ABI, a library is only loaded if it was built with
+//! the same compiler, the same version of this crate and the same packet
+//! definitions as the proxy loading it. The versions are stored with a fixed
+//! layout, so they can be checked before anything else in the library is used.
+//!
+//! # Examples
+//!
+//! A plugin library, built with `crate-type = ["cdylib"]` (see also
+//! `examples/plugin_library.rs`):
+//!
+//! ```
+//! use realmpipe_core::declare_plugin;
+//! use realmpipe_core::dynamic::PluginRegistrar;
+//! use realmpipe_core::pipe::{Plugin, PluginState, SessionSetup};
+//!
+//! struct Example;
+//!
+//! struct ExampleState;
+//!
+//! impl PluginState for ExampleState {}
+//!
+//! impl Plugin for Example {
+//!     fn name(&self) -> &str {
+//!         "example"
+//!     }
+//!
+//!     fn init_plugin(&mut self, _setup: &mut SessionSetup) -> Box<dyn PluginState> {
+//!         Box::new(ExampleState)
+//!     }
+//! }
+//!
+//! fn register(registrar: &mut dyn PluginRegistrar) {
+//!     registrar.register("example", Box::new(|| Box::new(Example)));
+//! }
+//!
+//! declare_plugin!(register);
+//! # fn main() {}
+//! ```
+
+use crate::config::{PluginFactory, PluginRegistry};
+use crate::pipe::{
+    AutoPacket, DisconnectReason, PacketContext, PacketFuture, PipeError, Plugin, PluginState,
+    SessionSetup, Setting, SettingError, SettingValue, TimerId,
+};
+use failure_derive::Fail;
+use libloading::Library;
+use log::info;
+use std::env::consts::DLL_EXTENSION;
+use std::ffi::{CStr, OsStr};
+use std::fs::read_dir;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The version of the compiler used to build this crate
+pub const RUSTC_VERSION: &str = env!("REALMPIPE_RUSTC_VERSION");
+
+/// The version of this crate
+pub const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A hash of the packet definitions in this crate
+pub const PACKETS_HASH: &str = env!("REALMPIPE_PACKETS_HASH");
+
+/// The versions above as NUL-terminated strings, as stored by
+/// `declare_plugin!`
+#[doc(hidden)]
+pub mod abi {
+    pub const RUSTC_VERSION: &str = concat!(env!("REALMPIPE_RUSTC_VERSION"), "\0");
+    pub const CORE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+    pub const PACKETS_HASH: &str = concat!(env!("REALMPIPE_PACKETS_HASH"), "\0");
+}
+
+/// The name of the symbol exported by `declare_plugin!`
+const DECLARATION_SYMBOL: &[u8] = b"REALMPIPE_PLUGIN_DECLARATION\0";
+
+/// Used by plugin libraries to register the plugins they provide
+pub trait PluginRegistrar {
+    /// Register a plugin with the given name
+    fn register(&mut self, name: &str, factory: PluginFactory);
+}
+
+/// The declaration exported by a plugin library. This should be created using
+/// `declare_plugin!`, rather than directly. The versions are NUL-terminated
+/// strings at the start of a `#[repr(C)]` struct, so they can be read whatever
+/// compiler built the library, while `register` may only be used once they
+/// match.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PluginDeclaration {
+    /// The version of the compiler used to build the library
+    pub rustc_version: *const c_char,
+
+    /// The version of `realmpipe_core` used to build the library
+    pub core_version: *const c_char,
+
+    /// The hash of the packet definitions used to build the library
+    pub packets_hash: *const c_char,
+
+    /// The function used to register the library's plugins
+    pub register: fn(&mut dyn PluginRegistrar),
+}
+
+// the versions point to static strings which are never modified
+unsafe impl Sync for PluginDeclaration {}
+
+impl PluginDeclaration {
+    /// Check that the library was built compatibly with this crate
+    fn check(&self, path: &Path) -> Result<(), LoadError> {
+        let checks = [
+            ("compiler", self.rustc_version, RUSTC_VERSION),
+            ("realmpipe_core", self.core_version, CORE_VERSION),
+            ("packet definitions", self.packets_hash, PACKETS_HASH),
+        ];
+
+        for (what, found, expected) in checks.iter() {
+            // safe as long as the declaration was created by `declare_plugin!`
+            let found = unsafe { CStr::from_ptr(*found) };
+
+            if found.to_bytes() != expected.as_bytes() {
+                return Err(LoadError::VersionMismatch {
+                    path: path.display().to_string(),
+                    what,
+                    found: found.to_string_lossy().into_owned(),
+                    expected: (*expected).to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Declare the plugins provided by a plugin library. The argument is a
+/// function taking a `&mut dyn PluginRegistrar`, which should register each
+/// plugin. See the module documentation for an example.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($register:expr) => {
+        #[doc(hidden)]
+        #[no_mangle]
+        pub static REALMPIPE_PLUGIN_DECLARATION: $crate::dynamic::PluginDeclaration =
+            $crate::dynamic::PluginDeclaration {
+                rustc_version: $crate::dynamic::abi::RUSTC_VERSION.as_ptr() as *const _,
+                core_version: $crate::dynamic::abi::CORE_VERSION.as_ptr() as *const _,
+                packets_hash: $crate::dynamic::abi::PACKETS_HASH.as_ptr() as *const _,
+                register: $register,
+            };
+    };
+}
+
+/// An error loading a plugin library
+#[derive(Debug, Fail)]
+pub enum LoadError {
+    /// An error reading the plugins directory
+    #[fail(display = "error reading {}: {}", _0, _1)]
+    IoError(String, IoError),
+
+    /// The library couldn't be loaded
+    #[fail(display = "error loading {}: {}", _0, _1)]
+    LibraryError(String, IoError),
+
+    /// The library doesn't declare any plugins
+    #[fail(display = "{} is not a realmpipe plugin library", _0)]
+    MissingDeclaration(String),
+
+    /// The library was built with an incompatible version of the compiler,
+    /// this crate or the packet definitions, and must be rebuilt
+    #[fail(
+        display = "{} was built with a different {} ({}, expected {}) and must be rebuilt",
+        path, what, found, expected
+    )]
+    VersionMismatch {
+        /// The path to the library
+        path: String,
+
+        /// What the mismatched version is for
+        what: &'static str,
+
+        /// The version the library was built with
+        found: String,
+
+        /// The version used by this proxy
+        expected: String,
+    },
+}
+
+/// Registers plugins from a library, wrapping them to keep the library loaded
+struct LibraryRegistrar<'a> {
+    library: Arc<Library>,
+    registry: &'a mut PluginRegistry,
+    names: Vec<String>,
+}
+
+impl PluginRegistrar for LibraryRegistrar<'_> {
+    fn register(&mut self, name: &str, factory: PluginFactory) {
+        let factory = DynamicFactory {
+            factory,
+            library: Arc::clone(&self.library),
+        };
+        self.registry
+            .register(name, move || -> Box<dyn Plugin> { factory.create() });
+        self.names.push(name.to_string());
+    }
+}
+
+/// A factory for plugins from a library, keeping the library loaded
+struct DynamicFactory {
+    // the factory must be dropped before the library
+    factory: PluginFactory,
+    library: Arc<Library>,
+}
+
+impl DynamicFactory {
+    /// Create a new plugin, wrapped to keep the library loaded
+    fn create(&self) -> Box<dyn Plugin> {
+        Box::new(DynamicPlugin {
+            plugin: (self.factory)(),
+            library: Arc::clone(&self.library),
+        })
+    }
+}
+
+/// Load a plugin library, adding its plugins to `registry`. The library will
+/// stay loaded as long as any of its plugins exist. Returns the names of the
+/// plugins which were registered.
+pub fn load_library(path: &Path, registry: &mut PluginRegistry) -> Result<Vec<String>, LoadError> {
+    let display = path.display().to_string();
+    let library = Library::new(path).map_err(|e| LoadError::LibraryError(display.clone(), e))?;
+
+    // safe as long as the symbol was exported by `declare_plugin!`, and only the
+    // versions are used until they've been checked
+    let declaration = unsafe {
+        *library
+            .get::<*const PluginDeclaration>(DECLARATION_SYMBOL)
+            .map_err(|_| LoadError::MissingDeclaration(display.clone()))?
+            .as_ref()
+            .ok_or_else(|| LoadError::MissingDeclaration(display.clone()))?
+    };
+
+    declaration.check(path)?;
+
+    let mut registrar = LibraryRegistrar {
+        library: Arc::new(library),
+        registry,
+        names: Vec::new(),
+    };
+    (declaration.register)(&mut registrar);
+
+    info!("Loaded plugins from {}: {:?}", display, registrar.names);
+    Ok(registrar.names)
+}
+
+/// Load every plugin library in the given directory, adding their plugins to
+/// `registry`. Only files with the platform's shared library extension are
+/// loaded. Returns the names of the plugins which were registered.
+pub fn load_directory(dir: &Path, registry: &mut PluginRegistry) -> Result<Vec<String>, LoadError> {
+    let io_error = |e| LoadError::IoError(dir.display().to_string(), e);
+
+    let mut paths = Vec::new();
+    for entry in read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension() == Some(OsStr::new(DLL_EXTENSION)) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut names = Vec::new();
+    for path in paths {
+        names.extend(load_library(&path, registry)?);
+    }
+
+    Ok(names)
+}
+
+/// A plugin from a dynamically loaded library, which keeps the library loaded
+/// until the plugin is dropped
+struct DynamicPlugin {
+    // the plugin must be dropped before the library
+    plugin: Box<dyn Plugin>,
+    library: Arc<Library>,
+}
+
+impl Plugin for DynamicPlugin {
+    fn name(&self) -> &str {
+        self.plugin.name()
+    }
+
+    fn description(&self) -> &str {
+        self.plugin.description()
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        self.plugin.settings()
+    }
+
+    fn update_setting(&mut self, name: &str, value: SettingValue) -> Result<(), SettingError> {
+        self.plugin.update_setting(name, value)
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        Box::new(DynamicPluginState {
+            state: self.plugin.init_plugin(setup),
+            _library: Arc::clone(&self.library),
+        })
+    }
+}
+
+/// A plugin state from a dynamically loaded library, which keeps the library
+/// loaded until the state is dropped
+struct DynamicPluginState {
+    // the state must be dropped before the library
+    state: Box<dyn PluginState>,
+    _library: Arc<Library>,
+}
+
+impl PluginState for DynamicPluginState {
+    fn on_connect(&mut self, client: SocketAddr, server: SocketAddr) {
+        self.state.on_connect(client, server)
+    }
+
+    fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        self.state.on_packet(packet, context)
+    }
+
+    fn on_packet_async(
+        &mut self,
+        packet: &mut AutoPacket,
+        context: &mut PacketContext,
+    ) -> Option<PacketFuture> {
+        self.state.on_packet_async(packet, context)
+    }
+
+    fn on_timer(&mut self, timer: TimerId, context: &mut PacketContext) {
+        self.state.on_timer(timer, context)
+    }
+
+    fn on_error(&mut self, error: &PipeError) {
+        self.state.on_error(error)
+    }
+
+    fn on_disconnect(&mut self, reason: DisconnectReason) {
+        self.state.on_disconnect(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    fn register(_registrar: &mut dyn PluginRegistrar) {}
+
+    #[test]
+    fn test_version_check() {
+        let mut declaration = PluginDeclaration {
+            rustc_version: abi::RUSTC_VERSION.as_ptr() as *const c_char,
+            core_version: abi::CORE_VERSION.as_ptr() as *const c_char,
+            packets_hash: abi::PACKETS_HASH.as_ptr() as *const c_char,
+            register,
+        };
+        assert!(declaration.check(Path::new("plugin.so")).is_ok());
+
+        declaration.packets_hash = b"0000000000000000\0".as_ptr() as *const c_char;
+        assert_matches!(
+            declaration.check(Path::new("plugin.so")),
+            Err(LoadError::VersionMismatch {
+                what: "packet definitions",
+                ..
+            })
+        );
+    }
+}
@@ -4,6 +4,7 @@
 
 use super::stat::StatData;
 use crate::adapters::prelude::*;
+use serde::{Deserialize, Serialize};
 
 macro_rules! auto_data {
     ($name:ident {
@@ -11,7 +12,7 @@ macro_rules! auto_data {
             $fieldname:ident: $fieldtype:ty
         ),* $(,)?
     }) => {
-        #[derive(Debug, PartialEq, Clone)]
+        #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
         pub struct $name {
             $(
                 pub $fieldname: $fieldtype
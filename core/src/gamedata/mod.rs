@@ -1,7 +1,9 @@
 //! Definitions and adapters for ROTMG data types
 
 mod basic;
+mod objectstats;
 mod stat;
 
 pub use basic::*;
+pub use objectstats::*;
 pub use stat::*;
@@ -0,0 +1,614 @@
+//! Typed stats for objects and players, built by folding the `StatData` sent
+//! in `ObjectStatusData`, so consumers don't need to match on raw `StatType`
+//! values
+
+use super::{StatData, StatType};
+use bitflags::bitflags;
+
+/// The value sent for an item slot which is empty
+const EMPTY_SLOT: u32 = u32::MAX;
+
+/// The number of inventory slots a player has, including equipment
+pub const INVENTORY_SLOTS: usize = 12;
+
+/// The number of slots in a player's backpack
+pub const BACKPACK_SLOTS: usize = 8;
+
+bitflags! {
+    /// The condition effects affecting an object. The lower 32 bits are sent
+    /// in `CONDITION_STAT`, and the upper 32 bits in `NEW_CON_STAT`.
+    #[derive(Default)]
+    pub struct ConditionEffects: u64 {
+        /// The object is dead
+        const DEAD = 1 << 0;
+        /// The object can't use its ability
+        const QUIET = 1 << 1;
+        /// The object's attack is reduced
+        const WEAK = 1 << 2;
+        /// The object's speed is reduced
+        const SLOWED = 1 << 3;
+        /// The object can't heal
+        const SICK = 1 << 4;
+        /// The object's dexterity is reduced
+        const DAZED = 1 << 5;
+        /// The object can't shoot
+        const STUNNED = 1 << 6;
+        /// The object can't see
+        const BLIND = 1 << 7;
+        /// The object is hallucinating
+        const HALLUCINATING = 1 << 8;
+        /// The object's view is distorted
+        const DRUNK = 1 << 9;
+        /// The object's controls are reversed
+        const CONFUSED = 1 << 10;
+        /// The object can't be stunned
+        const STUN_IMMUNE = 1 << 11;
+        /// The object can't be seen
+        const INVISIBLE = 1 << 12;
+        /// The object can't move
+        const PARALYZED = 1 << 13;
+        /// The object's speed is increased
+        const SPEEDY = 1 << 14;
+        /// The object is losing health
+        const BLEEDING = 1 << 15;
+        /// The object's defense can't be reduced to zero
+        const ARMOR_BROKEN_IMMUNE = 1 << 16;
+        /// The object is being healed
+        const HEALING = 1 << 17;
+        /// The object's attack is increased
+        const DAMAGING = 1 << 18;
+        /// The object's dexterity is increased
+        const BERSERK = 1 << 19;
+        /// The object is paused
+        const PAUSED = 1 << 20;
+        /// The object can't move or be damaged
+        const STASIS = 1 << 21;
+        /// The object can't be put in stasis
+        const STASIS_IMMUNE = 1 << 22;
+        /// The object can't be damaged
+        const INVINCIBLE = 1 << 23;
+        /// The object can't be damaged by projectiles
+        const INVULNERABLE = 1 << 24;
+        /// The object's defense is increased
+        const ARMORED = 1 << 25;
+        /// The object's defense is reduced to zero
+        const ARMOR_BROKEN = 1 << 26;
+        /// The object takes more damage
+        const HEXED = 1 << 27;
+        /// The object's speed is increased by a ninja ability
+        const NINJA_SPEEDY = 1 << 28;
+        /// The object's shots are inaccurate
+        const UNSTABLE = 1 << 29;
+        /// The object's view is reduced
+        const DARKNESS = 1 << 30;
+        /// The object can't be slowed
+        const SLOWED_IMMUNE = 1 << 32;
+        /// The object can't be dazed
+        const DAZED_IMMUNE = 1 << 33;
+        /// The object can't be paralyzed
+        const PARALYZED_IMMUNE = 1 << 34;
+        /// The object can't move or shoot
+        const PETRIFIED = 1 << 35;
+        /// The object can't be petrified
+        const PETRIFIED_IMMUNE = 1 << 36;
+        /// The object is affected by a pet
+        const PET_EFFECT_ICON = 1 << 37;
+        /// The object takes more damage from a curse
+        const CURSE = 1 << 38;
+        /// The object can't be cursed
+        const CURSE_IMMUNE = 1 << 39;
+        /// The object's maximum HP is boosted
+        const HP_BOOST = 1 << 40;
+        /// The object's maximum MP is boosted
+        const MP_BOOST = 1 << 41;
+        /// The object's defense is boosted
+        const DEF_BOOST = 1 << 42;
+        /// The object's attack is boosted
+        const ATT_BOOST = 1 << 43;
+        /// The object's speed is boosted
+        const SPD_BOOST = 1 << 44;
+        /// The object's vitality is boosted
+        const VIT_BOOST = 1 << 45;
+        /// The object's wisdom is boosted
+        const WIS_BOOST = 1 << 46;
+        /// The object's dexterity is boosted
+        const DEX_BOOST = 1 << 47;
+        /// The object can't use its ability or regenerate MP
+        const SILENCED = 1 << 48;
+        /// The object's defense is reduced
+        const EXPOSED = 1 << 49;
+        /// The object's MP regeneration is increased
+        const ENERGIZED = 1 << 50;
+    }
+}
+
+impl ConditionEffects {
+    /// Replace the lower 32 bits, as sent in `CONDITION_STAT`
+    fn set_low(&mut self, bits: u32) {
+        *self = Self::from_bits_truncate((self.bits() & !0xffff_ffff) | u64::from(bits));
+    }
+
+    /// Replace the upper 32 bits, as sent in `NEW_CON_STAT`
+    fn set_high(&mut self, bits: u32) {
+        *self = Self::from_bits_truncate((self.bits() & 0xffff_ffff) | (u64::from(bits) << 32));
+    }
+}
+
+/// A stat which may be boosted above its base value, e.g. by equipment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BoostedStat {
+    /// The total value, including the boost
+    pub value: u32,
+
+    /// The amount the value is boosted by
+    pub boost: u32,
+}
+
+impl BoostedStat {
+    /// Get the value without the boost
+    pub fn base(self) -> u32 {
+        self.value.saturating_sub(self.boost)
+    }
+}
+
+/// A single ability of a pet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PetAbility {
+    /// The type of the ability
+    pub ability_type: u32,
+
+    /// The power level of the ability
+    pub power: u32,
+
+    /// The points put into the ability
+    pub points: u32,
+}
+
+/// The details of a pet, sent in the stats of the pet object
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PetInfo {
+    /// The unique ID of the pet
+    pub instance_id: u32,
+
+    /// The name of the pet
+    pub name: String,
+
+    /// The type of the pet
+    pub pet_type: u32,
+
+    /// The rarity of the pet
+    pub rarity: u32,
+
+    /// The family of the pet
+    pub family: u32,
+
+    /// The maximum power of the pet's abilities
+    pub max_ability_power: u32,
+
+    /// The pet's three abilities
+    pub abilities: [PetAbility; 3],
+}
+
+/// A field of `ObjectStats` or `PlayerStats`, used to report which fields have
+/// changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StatField {
+    /// The name of the object
+    Name,
+    /// The current HP
+    Hp,
+    /// The maximum HP
+    MaxHp,
+    /// The size of the object
+    Size,
+    /// The condition effects
+    Conditions,
+    /// The details of a pet
+    Pet,
+    /// The player's account ID
+    AccountId,
+    /// The level of the character
+    Level,
+    /// The experience of the character
+    Exp,
+    /// The experience needed for the next level
+    NextLevelExp,
+    /// The current MP
+    Mp,
+    /// The maximum MP
+    MaxMp,
+    /// The attack stat
+    Attack,
+    /// The defense stat
+    Defense,
+    /// The speed stat
+    Speed,
+    /// The dexterity stat
+    Dexterity,
+    /// The vitality stat
+    Vitality,
+    /// The wisdom stat
+    Wisdom,
+    /// An inventory slot, by index
+    Inventory(usize),
+    /// A backpack slot, by index
+    Backpack(usize),
+    /// Whether the character has a backpack
+    HasBackpack,
+    /// The number of health potions
+    HealthPotions,
+    /// The number of magic potions
+    MagicPotions,
+    /// The character's fame
+    Fame,
+    /// The account's fame
+    AccountFame,
+    /// The number of class stars
+    Stars,
+    /// The number of gold credits
+    Credits,
+    /// The name of the guild
+    GuildName,
+    /// The rank within the guild
+    GuildRank,
+}
+
+/// Typed stats which can be built from `StatData`
+pub trait TypedStats: Clone + Default {
+    /// Apply a single stat, returning whether it's one of these stats.
+    /// Unrelated stats are ignored.
+    fn apply(&mut self, stat: &StatData) -> bool;
+
+    /// Get the fields which differ from an older version of these stats
+    fn diff(&self, old: &Self) -> Vec<StatField>;
+
+    /// Build stats from a full list of stats, such as those sent when an
+    /// object is added to the world
+    fn from_stats<'a>(stats: impl IntoIterator<Item = &'a StatData>) -> Self {
+        let mut typed = Self::default();
+        typed.update(stats);
+        typed
+    }
+
+    /// Apply an update to these stats, such as those sent each tick,
+    /// returning the fields which changed
+    fn update<'a>(&mut self, stats: impl IntoIterator<Item = &'a StatData>) -> Vec<StatField> {
+        let old = self.clone();
+        for stat in stats {
+            self.apply(stat);
+        }
+        self.diff(&old)
+    }
+}
+
+/// Push the given fields onto a list of changes if they differ between two
+/// versions of the stats
+macro_rules! diff_fields {
+    ($new:expr, $old:expr, $changes:expr, { $($($field:ident).+ => $name:expr),* $(,)? }) => {
+        $(
+            if $new.$($field).+ != $old.$($field).+ {
+                $changes.push($name);
+            }
+        )*
+    };
+}
+
+/// The stats shared by every object, such as enemies, pets and players
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ObjectStats {
+    /// The name of the object
+    pub name: String,
+
+    /// The current HP of the object
+    pub hp: u32,
+
+    /// The maximum HP of the object
+    pub max_hp: BoostedStat,
+
+    /// The size of the object, as a percentage
+    pub size: u32,
+
+    /// The condition effects affecting the object
+    pub conditions: ConditionEffects,
+
+    /// The details of the object, if it's a pet
+    pub pet: Option<PetInfo>,
+}
+
+impl ObjectStats {
+    fn pet_mut(&mut self) -> &mut PetInfo {
+        self.pet.get_or_insert_with(PetInfo::default)
+    }
+}
+
+impl TypedStats for ObjectStats {
+    fn apply(&mut self, stat: &StatData) -> bool {
+        use StatType::*;
+
+        match stat {
+            StatData::String(NAME_STAT, name) => self.name = name.clone(),
+            StatData::String(PET_NAME_STAT, name) => self.pet_mut().name = name.clone(),
+            StatData::Integer(stat_type, value) => {
+                let value = *value;
+                match stat_type {
+                    HP_STAT => self.hp = value,
+                    MAX_HP_STAT => self.max_hp.value = value,
+                    MAX_HP_BOOST_STAT => self.max_hp.boost = value,
+                    SIZE_STAT => self.size = value,
+                    CONDITION_STAT => self.conditions.set_low(value),
+                    NEW_CON_STAT => self.conditions.set_high(value),
+                    PET_INSTANCEID_STAT => self.pet_mut().instance_id = value,
+                    PET_TYPE_STAT => self.pet_mut().pet_type = value,
+                    PET_RARITY_STAT => self.pet_mut().rarity = value,
+                    PET_FAMILY_STAT => self.pet_mut().family = value,
+                    PET_MAXABILITYPOWER_STAT => self.pet_mut().max_ability_power = value,
+                    PET_FIRSTABILITY_TYPE_STAT => self.pet_mut().abilities[0].ability_type = value,
+                    PET_FIRSTABILITY_POWER_STAT => self.pet_mut().abilities[0].power = value,
+                    PET_FIRSTABILITY_POINT_STAT => self.pet_mut().abilities[0].points = value,
+                    PET_SECONDABILITY_TYPE_STAT => self.pet_mut().abilities[1].ability_type = value,
+                    PET_SECONDABILITY_POWER_STAT => self.pet_mut().abilities[1].power = value,
+                    PET_SECONDABILITY_POINT_STAT => self.pet_mut().abilities[1].points = value,
+                    PET_THIRDABILITY_TYPE_STAT => self.pet_mut().abilities[2].ability_type = value,
+                    PET_THIRDABILITY_POWER_STAT => self.pet_mut().abilities[2].power = value,
+                    PET_THIRDABILITY_POINT_STAT => self.pet_mut().abilities[2].points = value,
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn diff(&self, old: &Self) -> Vec<StatField> {
+        let mut changes = Vec::new();
+        diff_fields!(self, old, changes, {
+            name => StatField::Name,
+            hp => StatField::Hp,
+            max_hp => StatField::MaxHp,
+            size => StatField::Size,
+            conditions => StatField::Conditions,
+            pet => StatField::Pet,
+        });
+        changes
+    }
+}
+
+/// The stats of a player, including those shared by every object
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PlayerStats {
+    /// The stats shared by every object
+    pub object: ObjectStats,
+
+    /// The ID of the player's account
+    pub account_id: String,
+
+    /// The level of the character
+    pub level: u32,
+
+    /// The experience of the character
+    pub exp: u32,
+
+    /// The experience needed for the character's next level
+    pub next_level_exp: u32,
+
+    /// The current MP of the character
+    pub mp: u32,
+
+    /// The maximum MP of the character
+    pub max_mp: BoostedStat,
+
+    /// The attack of the character
+    pub attack: BoostedStat,
+
+    /// The defense of the character
+    pub defense: BoostedStat,
+
+    /// The speed of the character
+    pub speed: BoostedStat,
+
+    /// The dexterity of the character
+    pub dexterity: BoostedStat,
+
+    /// The vitality of the character
+    pub vitality: BoostedStat,
+
+    /// The wisdom of the character
+    pub wisdom: BoostedStat,
+
+    /// The item type in each inventory slot, starting with the four equipment
+    /// slots
+    pub inventory: [Option<u32>; INVENTORY_SLOTS],
+
+    /// The item type in each backpack slot
+    pub backpack: [Option<u32>; BACKPACK_SLOTS],
+
+    /// Whether the character has a backpack
+    pub has_backpack: bool,
+
+    /// The number of health potions in the character's potion slot
+    pub health_potions: u32,
+
+    /// The number of magic potions in the character's potion slot
+    pub magic_potions: u32,
+
+    /// The fame earned by the character
+    pub fame: u32,
+
+    /// The fame of the player's account
+    pub account_fame: u32,
+
+    /// The number of class stars the player has
+    pub stars: u32,
+
+    /// The number of gold credits the player has
+    pub credits: u32,
+
+    /// The name of the player's guild, or an empty string if they aren't in
+    /// one
+    pub guild_name: String,
+
+    /// The player's rank within their guild
+    pub guild_rank: u32,
+}
+
+/// Get the item type in an item slot
+fn item(value: u32) -> Option<u32> {
+    if value == EMPTY_SLOT {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Get the indices of the item slots which differ between two versions
+fn changed_slots<'a>(
+    new: &'a [Option<u32>],
+    old: &'a [Option<u32>],
+) -> impl Iterator<Item = usize> + 'a {
+    (0..new.len()).filter(move |&i| new[i] != old[i])
+}
+
+impl TypedStats for PlayerStats {
+    fn apply(&mut self, stat: &StatData) -> bool {
+        use StatType::*;
+
+        if self.object.apply(stat) {
+            return true;
+        }
+
+        match stat {
+            StatData::String(ACCOUNT_ID_STAT, id) => self.account_id = id.clone(),
+            StatData::String(GUILD_NAME_STAT, name) => self.guild_name = name.clone(),
+            StatData::Integer(stat_type, value) => {
+                let (byte, value) = (*stat_type as usize, *value);
+                let inventory = INVENTORY_0_STAT as usize..=INVENTORY_11_STAT as usize;
+                let backpack = BACKPACK_0_STAT as usize..=BACKPACK_7_STAT as usize;
+
+                if inventory.contains(&byte) {
+                    self.inventory[byte - inventory.start()] = item(value);
+                    return true;
+                } else if backpack.contains(&byte) {
+                    self.backpack[byte - backpack.start()] = item(value);
+                    return true;
+                }
+
+                match stat_type {
+                    LEVEL_STAT => self.level = value,
+                    EXP_STAT => self.exp = value,
+                    NEXT_LEVEL_EXP_STAT => self.next_level_exp = value,
+                    MP_STAT => self.mp = value,
+                    MAX_MP_STAT => self.max_mp.value = value,
+                    MAX_MP_BOOST_STAT => self.max_mp.boost = value,
+                    ATTACK_STAT => self.attack.value = value,
+                    ATTACK_BOOST_STAT => self.attack.boost = value,
+                    DEFENSE_STAT => self.defense.value = value,
+                    DEFENSE_BOOST_STAT => self.defense.boost = value,
+                    SPEED_STAT => self.speed.value = value,
+                    SPEED_BOOST_STAT => self.speed.boost = value,
+                    DEXTERITY_STAT => self.dexterity.value = value,
+                    DEXTERITY_BOOST_STAT => self.dexterity.boost = value,
+                    VITALITY_STAT => self.vitality.value = value,
+                    VITALITY_BOOST_STAT => self.vitality.boost = value,
+                    WISDOM_STAT => self.wisdom.value = value,
+                    WISDOM_BOOST_STAT => self.wisdom.boost = value,
+                    HASBACKPACK_STAT => self.has_backpack = value != 0,
+                    HEALTH_POTION_STACK_STAT => self.health_potions = value,
+                    MAGIC_POTION_STACK_STAT => self.magic_potions = value,
+                    CURR_FAME_STAT => self.fame = value,
+                    FAME_STAT => self.account_fame = value,
+                    NUM_STARS_STAT => self.stars = value,
+                    CREDITS_STAT => self.credits = value,
+                    GUILD_RANK_STAT => self.guild_rank = value,
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn diff(&self, old: &Self) -> Vec<StatField> {
+        let mut changes = self.object.diff(&old.object);
+        diff_fields!(self, old, changes, {
+            account_id => StatField::AccountId,
+            level => StatField::Level,
+            exp => StatField::Exp,
+            next_level_exp => StatField::NextLevelExp,
+            mp => StatField::Mp,
+            max_mp => StatField::MaxMp,
+            attack => StatField::Attack,
+            defense => StatField::Defense,
+            speed => StatField::Speed,
+            dexterity => StatField::Dexterity,
+            vitality => StatField::Vitality,
+            wisdom => StatField::Wisdom,
+        });
+
+        changes.extend(changed_slots(&self.inventory, &old.inventory).map(StatField::Inventory));
+        changes.extend(changed_slots(&self.backpack, &old.backpack).map(StatField::Backpack));
+
+        diff_fields!(self, old, changes, {
+            has_backpack => StatField::HasBackpack,
+            health_potions => StatField::HealthPotions,
+            magic_potions => StatField::MagicPotions,
+            fame => StatField::Fame,
+            account_fame => StatField::AccountFame,
+            stars => StatField::Stars,
+            credits => StatField::Credits,
+            guild_name => StatField::GuildName,
+            guild_rank => StatField::GuildRank,
+        });
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_player_stats() {
+        let mut stats = PlayerStats::from_stats(&[
+            StatData::String(StatType::NAME_STAT, "Tester".to_owned()),
+            StatData::Integer(StatType::HP_STAT, 100),
+            StatData::Integer(StatType::MAX_HP_STAT, 150),
+            StatData::Integer(StatType::MAX_HP_BOOST_STAT, 50),
+            StatData::Integer(StatType::INVENTORY_0_STAT, 0x0a00),
+            StatData::Integer(StatType::INVENTORY_1_STAT, EMPTY_SLOT),
+            StatData::Integer(StatType::BACKPACK_7_STAT, 0x0a22),
+            StatData::Integer(StatType::CONDITION_STAT, 1 << 3),
+            StatData::Integer(StatType::NEW_CON_STAT, 1 << 1),
+        ]);
+
+        assert_eq!(stats.object.name, "Tester");
+        assert_eq!(stats.object.max_hp.base(), 100);
+        assert_eq!(stats.inventory[0], Some(0x0a00));
+        assert_eq!(stats.inventory[1], None);
+        assert_eq!(stats.backpack[7], Some(0x0a22));
+        assert_eq!(
+            stats.object.conditions,
+            ConditionEffects::SLOWED | ConditionEffects::DAZED_IMMUNE
+        );
+
+        let changes = stats.update(&[
+            StatData::Integer(StatType::HP_STAT, 90),
+            StatData::Integer(StatType::MAX_HP_STAT, 150),
+            StatData::Integer(StatType::INVENTORY_2_STAT, 0x0b00),
+            StatData::Integer(StatType::CONDITION_STAT, 0),
+            StatData::String(StatType::GUILD_NAME_STAT, "Guild".to_owned()),
+        ]);
+
+        assert_eq!(
+            changes,
+            vec![
+                StatField::Hp,
+                StatField::Conditions,
+                StatField::Inventory(2),
+                StatField::GuildName
+            ]
+        );
+        assert_eq!(stats.object.conditions, ConditionEffects::DAZED_IMMUNE);
+    }
+}
@@ -3,11 +3,12 @@
 #![allow(missing_docs)]
 
 use crate::adapters::prelude::*;
+use serde::{Deserialize, Serialize};
 
 macro_rules! stat_types {
     ($($name:ident = $value:expr),* $(,)?) => {
         /// The type of a stat specified within `StatData`
-        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Deserialize, Serialize)]
         #[repr(u8)]
         #[allow(non_camel_case_types)]
         pub enum StatType {
@@ -162,7 +163,7 @@ impl NetworkAdapter for StatType {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub enum StatData {
     Integer(StatType, u32),
     String(StatType, String),
@@ -6,6 +6,10 @@
 #![deny(missing_docs)]
 
 pub mod adapters;
+pub mod capture;
+pub mod client;
+pub mod config;
+pub mod dynamic;
 mod ext;
 pub mod gamedata;
 pub mod mappings;
@@ -13,4 +17,9 @@ pub mod packets;
 pub mod pipe;
 pub mod proxy;
 pub mod rc4;
+#[cfg(feature = "scripting")]
+pub mod scripting;
 pub mod serverlist;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod world;
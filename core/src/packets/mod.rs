@@ -8,7 +8,7 @@ macro_rules! define_packet_structure {
             $fieldname: ident : $fieldtype:ty
         ),* $(,)?
     }) => {
-        #[derive(Debug, PartialEq, Clone)]
+        #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
         #[allow(missing_docs)]
         pub struct $name {
             $(
@@ -75,10 +75,10 @@ macro_rules! define_single_packet {
 
 macro_rules! is_serverside {
     (Client) => {
-        true
+        false
     };
     (Server) => {
-        false
+        true
     };
 }
 
@@ -124,8 +124,10 @@ macro_rules! define_packets {
         )*
 
         // next, define the all-powerful Packet enum
-        /// A packet of any type from either the server or the client
-        #[derive(Debug, PartialEq, Clone)]
+        /// A packet of any type from either the server or the client. When
+        /// serialized, packets are represented as a map from the name of the
+        /// packet type to its fields.
+        #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
         #[allow(missing_docs)]
         pub enum Packet {
             $( // each side
@@ -164,6 +166,15 @@ macro_rules! define_packets {
                         }
                     }
                 }
+
+                impl<'a> Downcast<&'a mut $name> for &'a mut Packet {
+                    fn downcast(self) -> Option<&'a mut $name> {
+                        match self {
+                            Packet::$name(v) => Some(v),
+                            _ => None
+                        }
+                    }
+                }
             )*
         )*
 
@@ -194,6 +205,29 @@ macro_rules! define_packets {
             pub fn downcast_ref<'a, T>(&'a self) -> Option<&'a T> where &'a Self: Downcast<&'a T> {
                 Downcast::downcast(self)
             }
+
+            /// Attempt to downcast this packet to a specific type by mutable
+            /// reference.
+            ///
+            /// # Example
+            ///
+            /// ```
+            /// use realmpipe_core::packets::{Packet, client};
+            ///
+            /// let mut pkt = Packet::Teleport(client::Teleport { object_id: 1 });
+            ///
+            /// if let Some(teleport) = pkt.downcast_mut::<client::Teleport>() {
+            ///     teleport.object_id = 2;
+            /// }
+            ///
+            /// assert_eq!(pkt, Packet::Teleport(client::Teleport { object_id: 2 }));
+            /// ```
+            pub fn downcast_mut<'a, T>(&'a mut self) -> Option<&'a mut T>
+            where
+                &'a mut Self: Downcast<&'a mut T>
+            {
+                Downcast::downcast(self)
+            }
         }
 
         // define an enum for internal packet ids...
@@ -331,6 +365,16 @@ macro_rules! define_packets {
             pub fn get_name(self) -> &'static str {
                 Self::get_name_mappings()[&self]
             }
+
+            /// Get the packet type with the given numeric value, as given by
+            /// `id as u8`
+            pub fn from_byte(byte: u8) -> Option<Self> {
+                const ALL: &[InternalPacketId] = &[
+                    $( $( InternalPacketId::$name ),* ),*
+                ];
+
+                ALL.get(byte as usize).copied()
+            }
         }
 
         impl Packet {
@@ -630,3 +674,16 @@ mod unified_definitions {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_sides() {
+        assert!(InternalPacketId::Ping.is_server());
+        assert!(!InternalPacketId::Ping.is_client());
+        assert!(!InternalPacketId::Pong.is_server());
+        assert!(InternalPacketId::Pong.is_client());
+    }
+}
@@ -9,6 +9,8 @@ pub struct AutoPacket<'a> {
     raw: RawPacket,
     mappings: &'a Mappings,
     decoded: Option<PacketResult<Packet>>,
+    modified: bool,
+    decodable: bool,
 }
 
 impl<'a> AutoPacket<'a> {
@@ -18,17 +20,58 @@ impl<'a> AutoPacket<'a> {
             raw,
             mappings,
             decoded: None,
+            modified: false,
+            decodable: true,
         }
     }
 
-    /// Get the underlying `RawPacket` instance
+    /// Get the underlying `RawPacket` instance. Note that this reflects the
+    /// packet as it was received, even if it has since been modified. Use
+    /// `to_raw` to get the packet including any modifications.
     pub fn get_raw(&self) -> &RawPacket {
         &self.raw
     }
 
-    /// Consume this packet and return the raw packet
+    /// Whether this packet may have been modified through `get_any_mut` or
+    /// `downcast_mut`, meaning it will be re-encoded when converted back to a
+    /// raw packet
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Get the packet as a raw packet, reflecting any modifications. If the
+    /// packet was modified, it will be re-encoded. If an error occurs encoding
+    /// the packet, the error will be emitted as a warning, and the original
+    /// packet will be returned instead.
+    pub fn to_raw(&self) -> RawPacket {
+        match &self.decoded {
+            Some(Ok(packet)) if self.modified => {
+                encode_modified(packet.clone(), self.mappings).unwrap_or_else(|| self.raw.clone())
+            }
+            _ => self.raw.clone(),
+        }
+    }
+
+    /// Consume this packet and return the raw packet, as with `to_raw`
     pub fn into_raw(self) -> RawPacket {
-        self.raw
+        match self.decoded {
+            Some(Ok(packet)) if self.modified => {
+                encode_modified(packet, self.mappings).unwrap_or(self.raw)
+            }
+            _ => self.raw,
+        }
+    }
+
+    /// Set whether this packet may be decoded. The pipe prevents packets from
+    /// being decoded if no plugin has subscribed to their type.
+    pub(crate) fn set_decodable(&mut self, decodable: bool) {
+        self.decodable = decodable;
+    }
+
+    /// Whether this packet may be decoded. If not, `get_any`, `downcast` and
+    /// their mutable versions will always return `None`.
+    pub fn is_decodable(&self) -> bool {
+        self.decodable
     }
 
     /// Get the mappings used by this `AutoPacket`
@@ -36,36 +79,46 @@ impl<'a> AutoPacket<'a> {
         self.mappings
     }
 
-    /// Get this packet as a `Packet`
-    pub fn get_any(&mut self) -> Option<&Packet> {
-        let id = self.mappings.get_internal_id(self.raw.game_id());
-
-        if let Some(id) = id {
-            if self.decoded.is_none() {
-                // decode the packet
-                self.decoded = Some(self.raw.to_packet(self.mappings));
-
-                // if the result was an error, log it
-                if let Some(Err(e)) = &self.decoded {
-                    warn!(
-                        "Error decoding packet of type {:?}: {:?}. Contents: {:#x?}",
-                        id,
-                        e,
-                        self.raw.contents()
-                    )
-                }
-            }
+    /// Decode this packet if it hasn't been decoded already, returning it if
+    /// successful
+    fn decode(&mut self) -> Option<&mut Packet> {
+        if !self.decodable {
+            return None;
+        }
 
-            // by this point, we have a packet result
-            if self.decoded.as_ref().unwrap().is_ok() {
-                // we have a packet, unwrap and downcast it
-                Some(self.decoded.as_ref().unwrap().as_ref().unwrap())
-            } else {
-                None
+        let id = self.mappings.get_internal_id(self.raw.game_id())?;
+
+        if self.decoded.is_none() {
+            // decode the packet
+            self.decoded = Some(self.raw.to_packet(self.mappings));
+
+            // if the result was an error, log it
+            if let Some(Err(e)) = &self.decoded {
+                warn!(
+                    "Error decoding packet of type {:?}: {:?}. Contents: {:#x?}",
+                    id,
+                    e,
+                    self.raw.contents()
+                )
             }
-        } else {
-            None
         }
+
+        // by this point, we have a packet result
+        self.decoded.as_mut().unwrap().as_mut().ok()
+    }
+
+    /// Get this packet as a `Packet`
+    pub fn get_any(&mut self) -> Option<&Packet> {
+        self.decode().map(|p| &*p)
+    }
+
+    /// Get this packet as a mutable `Packet`. The packet is marked as modified,
+    /// so any changes will be seen by later plugins and sent to the other side
+    /// of the connection.
+    pub fn get_any_mut(&mut self) -> Option<&mut Packet> {
+        self.decode()?;
+        self.modified = true;
+        self.decoded.as_mut().unwrap().as_mut().ok()
     }
 
     /// Attempt to downcast this packet into a concrete type
@@ -85,4 +138,72 @@ impl<'a> AutoPacket<'a> {
         // decode (if necessary) and downcast the packet
         self.get_any().and_then(|p| p.downcast_ref())
     }
+
+    /// Attempt to downcast this packet into a concrete type which may be
+    /// modified. See `get_any_mut` for details.
+    pub fn downcast_mut<'b, T>(&'b mut self) -> Option<&'b mut T>
+    where
+        T: PacketData + 'b,
+        &'b mut Packet: Downcast<&'b mut T>,
+    {
+        let id = self.mappings.get_internal_id(self.raw.game_id())?;
+
+        if id != T::INTERNAL_ID {
+            return None;
+        }
+
+        self.get_any_mut().and_then(|p| p.downcast_mut())
+    }
+}
+
+/// Re-encode a modified packet, emitting a warning if an error occurs
+fn encode_modified(packet: Packet, mappings: &Mappings) -> Option<RawPacket> {
+    match RawPacket::from_packet(packet, mappings) {
+        Ok(raw) => Some(raw),
+        Err(e) => {
+            warn!("Error re-encoding modified packet: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::mappings::RC4_LEN;
+    use crate::packets::{client, InternalPacketId};
+    use bimap::BiHashMap;
+
+    #[test]
+    fn test_modify_packet() {
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        let mappings = Mappings::new("00".repeat(RC4_LEN), ids).unwrap();
+
+        let original = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("hello".to_owned()),
+        });
+        let raw = RawPacket::from_packet(original, &mappings).unwrap();
+
+        // reading the packet shouldn't cause it to be re-encoded
+        let mut auto = AutoPacket::new(raw, &mappings);
+        assert!(auto.downcast::<client::PlayerText>().is_some());
+        assert!(!auto.is_modified());
+
+        auto.downcast_mut::<client::PlayerText>().unwrap().text = RLE::new("world".to_owned());
+        assert!(auto.is_modified());
+        assert_eq!(
+            &**auto.downcast::<client::PlayerText>().unwrap().text,
+            "world"
+        );
+
+        let expected = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("world".to_owned()),
+        });
+        assert_eq!(auto.to_raw().to_packet(&mappings).unwrap(), expected);
+
+        let modified = auto.into_raw().to_packet(&mappings).unwrap();
+        assert_eq!(modified, expected);
+    }
 }
@@ -1,9 +1,19 @@
+use super::{PacketSide, Scheduler};
 use crate::packets::Packet;
+use failure::Error;
+use futures::Future;
 
-/// Context for a received packet
+/// A future returned by an asynchronous plugin callback. It resolves to a new
+/// context, which may be used to cancel the packet or send additional packets
+/// once the asynchronous work is done.
+pub type PacketFuture = Box<dyn Future<Item = PacketContext, Error = Error> + Send>;
+
+/// Context for a received packet or fired timer
 pub struct PacketContext {
     pub(crate) cancelled: bool,
     pub(crate) extra: Vec<Packet>,
+    pub(crate) timers: Scheduler,
+    pub(crate) side: Option<PacketSide>,
 }
 
 impl PacketContext {
@@ -23,6 +33,18 @@ impl PacketContext {
     pub fn send_packet(&mut self, packet: Packet) {
         self.extra.push(packet);
     }
+
+    /// Get the side of the connection the packet being handled was sent from.
+    /// This is `None` for timer callbacks, where there is no packet.
+    pub fn side(&self) -> Option<PacketSide> {
+        self.side
+    }
+
+    /// Get the scheduler for this session, used to send packets or invoke
+    /// `PluginState::on_timer` later on
+    pub fn timers(&mut self) -> &mut Scheduler {
+        &mut self.timers
+    }
 }
 
 impl Default for PacketContext {
@@ -30,6 +52,8 @@ impl Default for PacketContext {
         Self {
             cancelled: false,
             extra: Vec::with_capacity(0),
+            timers: Scheduler::default(),
+            side: None,
         }
     }
 }
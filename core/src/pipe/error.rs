@@ -2,6 +2,7 @@ use crate::proxy::codec::CodecError;
 use failure_derive::Fail;
 use std::convert::From;
 use std::io::Error as IoError;
+use tokio::timer::Error as TimerError;
 
 /// An error that occurred while setting up or using a connection betweeen the
 /// client and server
@@ -14,6 +15,10 @@ pub enum PipeError {
     /// A generic IO error
     #[fail(display = "io error: {}", _0)]
     IoError(IoError),
+
+    /// An error with the timer used to schedule plugin actions
+    #[fail(display = "timer error: {}", _0)]
+    TimerError(TimerError),
 }
 
 impl From<CodecError> for PipeError {
@@ -27,3 +32,9 @@ impl From<IoError> for PipeError {
         PipeError::IoError(e)
     }
 }
+
+impl From<TimerError> for PipeError {
+    fn from(e: TimerError) -> Self {
+        PipeError::TimerError(e)
+    }
+}
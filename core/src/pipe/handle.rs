@@ -0,0 +1,280 @@
+use super::session::MAX_QUEUED;
+use super::timer::{check_timer, TimerCommand};
+use super::{DelayTooLong, TimerAction, TimerId};
+use crate::packets::Packet;
+use failure_derive::Fail;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// A request sent to a session through a `SessionHandle`
+#[derive(Debug)]
+pub(crate) enum SessionCommand {
+    /// Send a packet to the appropriate side of the connection
+    SendPacket(Box<Packet>),
+
+    /// Schedule or cancel a timer which isn't owned by any plugin
+    Timer(TimerCommand),
+
+    /// Close the session
+    Disconnect,
+
+    /// Enable or disable the plugin with the given index for this session
+    SetPluginEnabled(usize, bool),
+}
+
+/// An error returned when using a `SessionHandle` for a session which has
+/// already ended
+#[derive(Debug, Fail)]
+#[fail(display = "session closed")]
+pub struct SessionClosed;
+
+/// An error returned when a packet can't be queued through a `SessionHandle`
+#[derive(Debug, Fail)]
+pub enum SendPacketError {
+    /// The session has already ended
+    #[fail(display = "session closed")]
+    Closed,
+
+    /// Too many packets sent through handles are already waiting for the
+    /// session to pick them up, or too many timers scheduled through handles
+    /// haven't finished yet
+    #[fail(display = "too many packets queued")]
+    QueueFull,
+
+    /// A timer was requested with a delay or interval longer than `MAX_DELAY`
+    #[fail(display = "{}", _0)]
+    DelayTooLong(DelayTooLong),
+}
+
+impl From<SessionClosed> for SendPacketError {
+    fn from(_: SessionClosed) -> Self {
+        SendPacketError::Closed
+    }
+}
+
+impl From<DelayTooLong> for SendPacketError {
+    fn from(e: DelayTooLong) -> Self {
+        SendPacketError::DelayTooLong(e)
+    }
+}
+
+/// The receiving end of the requests made through a session's handles
+pub(crate) struct SessionCommands {
+    receiver: UnboundedReceiver<SessionCommand>,
+    queued: Arc<AtomicUsize>,
+    timers: Arc<AtomicUsize>,
+}
+
+impl SessionCommands {
+    /// Note that a timer scheduled through a handle has fired for the last
+    /// time or been cancelled, making room for another
+    pub fn timer_done(&self) {
+        self.timers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Stream for SessionCommands {
+    type Item = SessionCommand;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let command = self.receiver.poll()?;
+
+        if let Async::Ready(Some(SessionCommand::SendPacket(_))) = command {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        Ok(command)
+    }
+}
+
+/// A cloneable handle to a live session, which may be used to interact with
+/// it from outside of plugin callbacks, e.g. from another thread or task.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    sender: UnboundedSender<SessionCommand>,
+
+    /// The number of packets sent through this handle or its clones which the
+    /// session hasn't received yet
+    queued: Arc<AtomicUsize>,
+
+    /// The number of timers scheduled through this handle or its clones which
+    /// haven't finished yet
+    timers: Arc<AtomicUsize>,
+}
+
+impl SessionHandle {
+    /// Create a new handle, along with the receiver for its requests
+    pub(crate) fn new() -> (Self, SessionCommands) {
+        let (sender, receiver) = unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let timers = Arc::new(AtomicUsize::new(0));
+        let commands = SessionCommands {
+            receiver,
+            queued: Arc::clone(&queued),
+            timers: Arc::clone(&timers),
+        };
+
+        (
+            Self {
+                sender,
+                queued,
+                timers,
+            },
+            commands,
+        )
+    }
+
+    /// Send a request to the session
+    pub(crate) fn command(&self, command: SessionCommand) -> Result<(), SessionClosed> {
+        self.sender
+            .unbounded_send(command)
+            .map_err(|_| SessionClosed)
+    }
+
+    /// Send the given packet to the appropriate side of the connection. The
+    /// packet will not trigger plugin callbacks. If an error occurs encoding
+    /// the packet, the error will be emitted as a warning, and the packet will
+    /// be skipped. Fails if too many packets sent through handles are already
+    /// waiting for the session.
+    pub fn send_packet(&self, packet: Packet) -> Result<(), SendPacketError> {
+        // reserve a place in the queue before sending the packet
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= MAX_QUEUED {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(SendPacketError::QueueFull);
+        }
+
+        self.command(SessionCommand::SendPacket(Box::new(packet)))
+            .map_err(|e| {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                e.into()
+            })
+    }
+
+    /// Schedule a timer to send a packet, returning the ID of the new timer
+    fn schedule(
+        &self,
+        delay: Duration,
+        interval: Option<Duration>,
+        packet: Packet,
+    ) -> Result<TimerId, SendPacketError> {
+        let (delay, interval) = check_timer(delay, interval)?;
+
+        // reserve a place for the timer before scheduling it
+        if self.timers.fetch_add(1, Ordering::SeqCst) >= MAX_QUEUED {
+            self.timers.fetch_sub(1, Ordering::SeqCst);
+            return Err(SendPacketError::QueueFull);
+        }
+
+        let id = TimerId::next();
+        let command = SessionCommand::Timer(TimerCommand::Schedule {
+            id,
+            delay,
+            interval,
+            action: TimerAction::SendPacket(Box::new(packet)),
+        });
+
+        if let Err(e) = self.command(command) {
+            self.timers.fetch_sub(1, Ordering::SeqCst);
+            return Err(e.into());
+        }
+        Ok(id)
+    }
+
+    /// Send the given packet once, after `delay` has elapsed, as with
+    /// `send_packet`. Fails if too many timers scheduled through handles
+    /// haven't finished yet, or if the delay is longer than `MAX_DELAY`.
+    pub fn send_after(&self, delay: Duration, packet: Packet) -> Result<TimerId, SendPacketError> {
+        self.schedule(delay, None, packet)
+    }
+
+    /// Send the given packet repeatedly, each time `interval` elapses, until
+    /// the timer is cancelled with `cancel_timer`. Fails in the same cases as
+    /// `send_after`, and intervals shorter than a millisecond are rounded up.
+    pub fn send_every(
+        &self,
+        interval: Duration,
+        packet: Packet,
+    ) -> Result<TimerId, SendPacketError> {
+        self.schedule(interval, Some(interval), packet)
+    }
+
+    /// Cancel a timer scheduled through a session handle, if it hasn't fired
+    /// yet
+    pub fn cancel_timer(&self, id: TimerId) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::Timer(TimerCommand::Cancel(id)))
+    }
+
+    /// Forcibly disconnect the session. Packets which have already been queued
+    /// will still be sent, but no more will be received from either side, and
+    /// packets still waiting on asynchronous callbacks are dropped.
+    pub fn disconnect(&self) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::Disconnect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::server::Ping;
+
+    fn ping() -> Packet {
+        Packet::Ping(Ping { serial: 0 })
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let (handle, mut commands) = SessionHandle::new();
+
+        for _ in 0..MAX_QUEUED {
+            handle.send_packet(ping()).unwrap();
+        }
+        match handle.send_packet(ping()) {
+            Err(SendPacketError::QueueFull) => {}
+            other => panic!("expected a full queue, got {:?}", other),
+        }
+
+        // once the session picks up a packet, there's room for another
+        commands.poll().unwrap();
+        handle.send_packet(ping()).unwrap();
+    }
+
+    #[test]
+    fn test_timer_limit() {
+        let (handle, commands) = SessionHandle::new();
+        let second = Duration::from_secs(1);
+
+        for _ in 0..MAX_QUEUED {
+            handle.send_after(second, ping()).unwrap();
+        }
+        match handle.send_every(second, ping()) {
+            Err(SendPacketError::QueueFull) => {}
+            other => panic!("expected a full queue, got {:?}", other),
+        }
+
+        // once a timer is done, there's room for another
+        commands.timer_done();
+        handle.send_every(second, ping()).unwrap();
+
+        match handle.send_after(Duration::from_secs(u64::MAX), ping()) {
+            Err(SendPacketError::DelayTooLong(_)) => {}
+            other => panic!("expected the delay to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_closed() {
+        let (handle, commands) = SessionHandle::new();
+        handle.send_packet(ping()).unwrap();
+        drop(commands);
+
+        match handle.send_packet(ping()) {
+            Err(SendPacketError::Closed) => {}
+            other => panic!("expected the session to be closed, got {:?}", other),
+        }
+        assert!(handle.disconnect().is_err());
+    }
+}
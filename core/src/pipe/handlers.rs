@@ -0,0 +1,112 @@
+use super::{AutoPacket, PacketContext};
+use crate::packets::{Downcast, InternalPacketId, Packet, PacketData};
+use std::collections::HashMap;
+
+/// A type-erased packet handler
+type Handler = Box<dyn FnMut(&mut AutoPacket, &mut PacketContext) + Send>;
+
+/// A table of handlers for specific packet types, built by a plugin when it's
+/// initialized for a session. The pipe only decodes packets of the types which
+/// at least one plugin has subscribed to, either by registering a handler or
+/// by calling `subscribe` or `subscribe_all`. Other packets are still passed
+/// to `PluginState::on_packet`, but can't be decoded.
+///
+/// # Example
+///
+/// ```
+/// use realmpipe_core::packets::{client, server};
+/// use realmpipe_core::pipe::PacketHandlers;
+///
+/// let mut handlers = PacketHandlers::default();
+///
+/// handlers
+///     .on(|text: &client::PlayerText, ctx| {
+///         if text.text.starts_with('/') {
+///             ctx.cancel_packet();
+///         }
+///     })
+///     .on_mut(|notification: &mut server::Notification, _ctx| {
+///         notification.color = 0xff0000;
+///     });
+/// ```
+#[derive(Default)]
+pub struct PacketHandlers {
+    handlers: HashMap<InternalPacketId, Vec<Handler>>,
+    all: bool,
+}
+
+impl PacketHandlers {
+    fn register(&mut self, id: InternalPacketId, handler: Handler) {
+        self.handlers.entry(id).or_default().push(handler);
+    }
+
+    /// Register a handler for packets of type `T`
+    pub fn on<T, F>(&mut self, mut handler: F) -> &mut Self
+    where
+        T: PacketData + 'static,
+        F: FnMut(&T, &mut PacketContext) + Send + 'static,
+        for<'a> &'a Packet: Downcast<&'a T>,
+    {
+        self.register(
+            T::INTERNAL_ID,
+            Box::new(move |packet, ctx| {
+                if let Some(packet) = packet.downcast::<T>() {
+                    handler(packet, ctx);
+                }
+            }),
+        );
+        self
+    }
+
+    /// Register a handler for packets of type `T` which may modify them. Any
+    /// packet passed to this handler will be re-encoded before being sent, so
+    /// `on` should be preferred for handlers which only read packets.
+    pub fn on_mut<T, F>(&mut self, mut handler: F) -> &mut Self
+    where
+        T: PacketData + 'static,
+        F: FnMut(&mut T, &mut PacketContext) + Send + 'static,
+        for<'a> &'a mut Packet: Downcast<&'a mut T>,
+    {
+        self.register(
+            T::INTERNAL_ID,
+            Box::new(move |packet, ctx| {
+                if let Some(packet) = packet.downcast_mut::<T>() {
+                    handler(packet, ctx);
+                }
+            }),
+        );
+        self
+    }
+
+    /// Subscribe to packets of the given type without registering a handler,
+    /// so they can be decoded in `PluginState::on_packet`
+    pub fn subscribe(&mut self, id: InternalPacketId) -> &mut Self {
+        self.handlers.entry(id).or_default();
+        self
+    }
+
+    /// Subscribe to packets of every type, so any packet can be decoded in
+    /// `PluginState::on_packet`. This prevents the pipe from skipping decoding
+    /// for any packets, so should be avoided where possible.
+    pub fn subscribe_all(&mut self) -> &mut Self {
+        self.all = true;
+        self
+    }
+
+    /// Check whether packets of the given type have been subscribed to, either
+    /// through a handler or explicitly
+    pub fn is_subscribed(&self, id: InternalPacketId) -> bool {
+        self.all || self.handlers.contains_key(&id)
+    }
+
+    /// Invoke the handlers registered for the given packet, if any
+    pub(crate) fn handle(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        let id = packet
+            .get_mappings()
+            .get_internal_id(packet.get_raw().game_id());
+
+        if let Some(handlers) = id.and_then(|id| self.handlers.get_mut(&id)) {
+            handlers.iter_mut().for_each(|h| h(packet, context));
+        }
+    }
+}
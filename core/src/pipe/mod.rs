@@ -3,11 +3,26 @@
 mod autopacket;
 mod context;
 mod error;
+mod handle;
+mod handlers;
 mod pipe;
 mod plugin;
+mod reconnect;
+mod registry;
+mod replay;
+mod session;
+mod settings;
+mod timer;
 
 pub use self::autopacket::AutoPacket;
-pub use self::context::PacketContext;
+pub use self::context::{PacketContext, PacketFuture};
 pub use self::error::PipeError;
-pub use self::pipe::{Pipe, PipeBuilder};
-pub use self::plugin::{Plugin, PluginState};
+pub use self::handle::{SendPacketError, SessionClosed, SessionHandle};
+pub use self::handlers::PacketHandlers;
+pub use self::pipe::{PacketSide, Pipe, PipeBuilder};
+pub use self::plugin::{Plugin, PluginInfo, PluginState, SessionSetup};
+pub use self::registry::{SessionId, SessionInfo};
+pub use self::replay::{PluginReport, Replay, ReplayAction, ReplayEvent, ReplayReport};
+pub use self::session::DisconnectReason;
+pub use self::settings::{Setting, SettingError, SettingValue};
+pub use self::timer::{DelayTooLong, Scheduler, TimerAction, TimerId, MAX_DELAY};
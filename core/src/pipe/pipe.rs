@@ -1,15 +1,27 @@
 #![allow(missing_docs)]
 
-use super::{AutoPacket, PacketContext, PipeError, Plugin};
+use super::handle::SessionCommand;
+use super::reconnect::{PendingReconnects, ReconnectKey};
+use super::session::Session;
+use super::{
+    PipeError, Plugin, PluginInfo, PluginState, SessionHandle, SessionId, SessionInfo,
+    SessionSetup, SettingError, SettingValue,
+};
+use crate::adapters::RLE;
 use crate::mappings::Mappings;
+use crate::packets::{client, server};
 use crate::proxy::raw::RawPacket;
-use crate::proxy::{server_connection, Connection};
+use crate::proxy::{server_connection, Transport};
 use crate::serverlist::ServerList;
 use derive_builder::Builder;
-use log::warn;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::default::Default;
-use std::net::SocketAddr;
-use std::ops::Deref;
+use std::io::Error as IoError;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::prelude::*;
@@ -24,27 +36,75 @@ pub enum PacketSide {
     Client,
 }
 
+impl PacketSide {
+    /// Get the opposite side of the connection
+    pub fn other(self) -> Self {
+        match self {
+            PacketSide::Server => PacketSide::Client,
+            PacketSide::Client => PacketSide::Server,
+        }
+    }
+}
+
+/// A plugin used by a pipe, along with whether it's enabled for new sessions
+struct PluginEntry {
+    plugin: Box<dyn Plugin>,
+    enabled: bool,
+}
+
 /// Represents a
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub struct Pipe {
-    #[builder(default = "Mutex::new(Vec::new())")]
-    plugins: Mutex<Vec<Box<dyn Plugin>>>,
+    #[builder(private, default = "Mutex::new(Vec::new())")]
+    plugins: Mutex<Vec<PluginEntry>>,
     mappings: Arc<Mappings>,
     #[builder(private, setter(name = "internal_servers"))]
     servers: ServerList,
     #[builder(private, setter(name = "internal_default_server"))]
     default_server: String,
+    #[builder(setter(skip))]
+    reconnects: Mutex<PendingReconnects>,
+    #[builder(setter(skip))]
+    sessions: Mutex<BTreeMap<SessionId, Arc<SessionInfo>>>,
+    #[builder(setter(skip))]
+    next_session_id: AtomicUsize,
 }
 
 impl PipeBuilder {
-    /// Add a single plugin
-    pub fn plugin(mut self, plugin: Box<dyn Plugin>) -> Self {
-        if let Some(plugins) = &self.plugins {
-            plugins.lock().unwrap().push(plugin);
-        } else {
-            self.plugins = Some(Mutex::new(vec![plugin]));
+    /// Add a single plugin, which will be enabled. The name of the plugin
+    /// must be unique.
+    pub fn plugin(self, plugin: Box<dyn Plugin>) -> Self {
+        self.plugin_enabled(plugin, true)
+    }
+
+    /// Check whether a plugin with the given name has already been added
+    pub fn has_plugin(&self, name: &str) -> bool {
+        self.plugins
+            .as_ref()
+            .map(|p| {
+                p.lock()
+                    .expect("error acquiring plugin lock")
+                    .iter()
+                    .any(|p| p.plugin.name() == name)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Add a single plugin, which may start out disabled. The name of the
+    /// plugin must be unique, which can be checked with `has_plugin`.
+    pub fn plugin_enabled(mut self, plugin: Box<dyn Plugin>, enabled: bool) -> Self {
+        let plugins = self
+            .plugins
+            .get_or_insert_with(|| Mutex::new(Vec::new()))
+            .get_mut()
+            .unwrap();
+
+        if plugins.iter().any(|p| p.plugin.name() == plugin.name()) {
+            panic!("plugin names must be unique: {}", plugin.name());
         }
+
+        plugins.push(PluginEntry { plugin, enabled });
         self
     }
 
@@ -72,111 +132,360 @@ impl Pipe {
         PipeBuilder::default()
     }
 
+    /// Get the mappings used by this pipe
+    pub fn get_mappings(&self) -> &Arc<Mappings> {
+        &self.mappings
+    }
+
     /// Get the socket address for the default server
     pub fn get_default_server(&self) -> SocketAddr {
         self.servers.get_socket(&self.default_server).unwrap()
     }
 
+    /// Get the live sessions running through this pipe, ordered by ID
+    pub fn sessions(&self) -> Vec<Arc<SessionInfo>> {
+        self.sessions
+            .lock()
+            .expect("error acquiring session lock")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Get a live session by its ID
+    pub fn get_session(&self, id: SessionId) -> Option<Arc<SessionInfo>> {
+        self.sessions
+            .lock()
+            .expect("error acquiring session lock")
+            .get(&id)
+            .cloned()
+    }
+
+    /// Forcibly disconnect a live session, returning whether it was found
+    pub fn disconnect(&self, id: SessionId) -> bool {
+        self.get_session(id)
+            .map(|s| s.disconnect().is_ok())
+            .unwrap_or(false)
+    }
+
+    /// Forcibly disconnect all live sessions
+    pub fn disconnect_all(&self) {
+        for session in self.sessions() {
+            // the session may have ended in the meantime, which is fine
+            let _ = session.disconnect();
+        }
+    }
+
+    /// Add a new session to the list of live sessions
+    pub(super) fn register_session(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+        handle: SessionHandle,
+    ) -> Arc<SessionInfo> {
+        let id = SessionId(self.next_session_id.fetch_add(1, Ordering::Relaxed));
+        let info = Arc::new(SessionInfo::new(id, client, server, handle));
+
+        self.sessions
+            .lock()
+            .expect("error acquiring session lock")
+            .insert(id, Arc::clone(&info));
+
+        info
+    }
+
+    /// Remove a session from the list of live sessions once it has ended
+    pub(super) fn unregister_session(&self, id: SessionId) {
+        self.sessions
+            .lock()
+            .expect("error acquiring session lock")
+            .remove(&id);
+    }
+
+    /// Initialize a new state of each plugin for a session, along with the
+    /// setup containing its typed packet handlers and initial timers, and
+    /// whether the plugin is enabled. Disabled plugins are still initialized,
+    /// so they can be enabled for the session later on.
+    pub(super) fn init_plugins(
+        &self,
+        info: &Arc<SessionInfo>,
+    ) -> Vec<(Box<dyn PluginState>, SessionSetup, bool)> {
+        self.plugins
+            .lock()
+            .expect("error acquiring plugin lock")
+            .iter_mut()
+            .map(|p| {
+                let mut setup = SessionSetup::new(Arc::clone(info));
+                let state = p.plugin.init_plugin(&mut setup);
+                (state, setup, p.enabled)
+            })
+            .collect()
+    }
+
+    /// Get the details of the plugins used by this pipe, in the order they're
+    /// invoked
+    pub fn plugins(&self) -> Vec<PluginInfo> {
+        self.plugins
+            .lock()
+            .expect("error acquiring plugin lock")
+            .iter()
+            .map(|p| PluginInfo {
+                name: p.plugin.name().to_string(),
+                description: p.plugin.description().to_string(),
+                enabled: p.enabled,
+                settings: p.plugin.settings(),
+            })
+            .collect()
+    }
+
+    /// Enable or disable the plugin with the given name for new sessions, and
+    /// also for existing sessions if `existing` is true. Disabled plugins
+    /// receive no packet or timer callbacks. Returns whether a plugin with the
+    /// given name was found.
+    pub fn set_plugin_enabled(&self, name: &str, enabled: bool, existing: bool) -> bool {
+        let mut plugins = self.plugins.lock().expect("error acquiring plugin lock");
+        let index = match plugins.iter().position(|p| p.plugin.name() == name) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        plugins[index].enabled = enabled;
+        debug!(
+            "Plugin {} {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+
+        if existing {
+            for info in self.sessions() {
+                // the session may have just ended, which is fine
+                let _ = info
+                    .handle()
+                    .command(SessionCommand::SetPluginEnabled(index, enabled));
+            }
+        }
+
+        true
+    }
+
+    /// Change a setting of the plugin with the given name
+    pub fn update_plugin_setting(
+        &self,
+        plugin: &str,
+        setting: &str,
+        value: SettingValue,
+    ) -> Result<(), SettingError> {
+        self.plugins
+            .lock()
+            .expect("error acquiring plugin lock")
+            .iter_mut()
+            .find(|p| p.plugin.name() == plugin)
+            .ok_or_else(|| SettingError::UnknownPlugin(plugin.to_string()))?
+            .plugin
+            .update_setting(setting, value)
+    }
+
+    /// Get the server that a new client connection should be sent to, based
+    /// on the first packet it sent. If the client is following a `Reconnect`
+    /// which was redirected through the proxy, this will be the server from the
+    /// original packet; otherwise it will be the default server.
+    fn get_target_server(&self, first: Option<&RawPacket>) -> SocketAddr {
+        let hello = first
+            .and_then(|raw| raw.to_packet(&self.mappings).ok())
+            .and_then(|pkt| pkt.downcast::<client::Hello>());
+
+        if let Some(hello) = hello {
+            let key = ReconnectKey::new(&hello.guid, hello.game_id, hello.key_time, &hello.key);
+            let target = self
+                .reconnects
+                .lock()
+                .expect("error acquiring reconnect lock")
+                .take(&key);
+
+            if let Some(target) = target {
+                debug!("Client following reconnect to {}", target);
+                return target;
+            }
+        }
+
+        self.get_default_server()
+    }
+
+    /// Rewrite a `Reconnect` packet sent by the server so the client connects
+    /// back to the proxy at `proxy` instead of directly to the new server,
+    /// remembering the real target so the next connection can be sent there.
+    /// `current` is the address of the server which sent the packet, used to
+    /// fill in an omitted host or port, and `guid` is the account the client
+    /// logged in with.
+    pub(super) fn redirect_reconnect(
+        &self,
+        reconnect: &mut server::Reconnect,
+        guid: &str,
+        current: SocketAddr,
+        proxy: SocketAddr,
+    ) {
+        // an empty host means the client should stay on the current server
+        let ip = if reconnect.host.is_empty() {
+            Some(current.ip())
+        } else {
+            IpAddr::from_str(&reconnect.host)
+                .ok()
+                .or_else(|| self.servers.get_ip(&reconnect.host.to_lowercase()))
+        };
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                warn!("Unable to resolve reconnect host {:?}", reconnect.host);
+                return;
+            }
+        };
+
+        // likewise, an invalid port (usually -1) means the current port
+        let port = match u16::try_from(reconnect.port) {
+            Ok(port) if port != 0 => port,
+            _ => current.port(),
+        };
+
+        let target = SocketAddr::new(ip, port);
+        debug!("Redirecting reconnect to {} through proxy", target);
+
+        self.reconnects
+            .lock()
+            .expect("error acquiring reconnect lock")
+            .insert(
+                ReconnectKey::new(guid, reconnect.game_id, reconnect.key_time, &reconnect.key),
+                target,
+            );
+
+        reconnect.host = RLE::new(proxy.ip().to_string());
+        reconnect.port = u32::from(proxy.port());
+    }
+
     /// Accept a given client connection using this pipe, opening the server
-    /// connection, then processing packets with plugins until closure
-    pub fn accept_client(
+    /// connection, then processing packets with plugins until closure. The
+    /// server is chosen based on the `Hello` packet sent by the client.
+    pub fn accept_client<C: Transport>(
         self: Arc<Self>,
-        client: Connection,
+        client: C,
     ) -> impl Future<Item = (), Error = PipeError> + Send {
-        server_connection(&self.get_default_server(), Arc::clone(&self.mappings))
-            .from_err()
-            .and_then(move |server| {
-                // by now, both halves of the pipe have been connected
+        let mappings = Arc::clone(&self.mappings);
+        self.accept_client_with(client, move |addr| server_connection(&addr, mappings))
+    }
 
-                // start by initializing the plugins
-                let mut plugins = self
-                    .plugins
-                    .lock()
-                    .expect("error acquiring plugin lock")
-                    .iter_mut()
-                    .map(|p| p.init_plugin(&client, &server))
-                    .collect::<Vec<_>>();
-
-                // split both connections
-                let (client_sink, client_stream) = client.split();
-                let (server_sink, server_stream) = server.split();
-
-                // map the streams to include an indicator of which side sent the packet
-                let client_stream = client_stream.map(|p| (PacketSide::Client, p));
-                let server_stream = server_stream.map(|p| (PacketSide::Server, p));
-
-                // combine the two streams
-                let stream = client_stream.select(server_stream);
-
-                // map the sinks to filter to the packets from the appropriate side
-                let client_sink = client_sink.with_flat_map(
-                    |(side, pkt)| -> Box<dyn Stream<Item = _, Error = _> + Send> {
-                        match side {
-                            PacketSide::Client => Box::new(futures::stream::empty()),
-                            PacketSide::Server => Box::new(futures::stream::once(Ok(pkt))),
-                        }
-                    },
-                );
-
-                let server_sink = server_sink.with_flat_map(
-                    |(side, pkt)| -> Box<dyn Stream<Item = _, Error = _> + Send> {
-                        match side {
-                            PacketSide::Client => Box::new(futures::stream::once(Ok(pkt))),
-                            PacketSide::Server => Box::new(futures::stream::empty()),
-                        }
-                    },
-                );
-
-                // combine the two sinks
-                let sink = client_sink.fanout(server_sink);
-
-                // finally, tie it all together into one future
-                stream
-                    .map(
-                        move |(side, raw)| -> Box<dyn Stream<Item = _, Error = PipeError> + Send> {
-                            // wrap the raw packet as an auto packet for easy downcasting
-                            let mut auto = AutoPacket::new(raw, self.mappings.deref());
-
-                            // create a packet context
-                            let mut ctx = PacketContext::default();
-
-                            // invoke plugin callbacks
-                            plugins
-                                .iter_mut()
-                                .for_each(|p| p.on_packet(&mut auto, &mut ctx));
-
-                            // queue up packets to send
-                            let mut queue = Vec::with_capacity(1 + ctx.extra.len());
-
-                            // if any plugin requested to cancel this packet, we don't send it
-                            if !ctx.cancelled {
-                                queue.push((side, auto.into_raw()));
-                            }
-
-                            // next, we add any packets that plugins requested to be sent
-                            for pkt in ctx.extra {
-                                let side = if pkt.get_internal_id().is_server() {
-                                    PacketSide::Client
-                                } else {
-                                    PacketSide::Server
-                                };
-
-                                let raw = RawPacket::from_packet(pkt, self.mappings.deref());
-
-                                match raw {
-                                    Ok(raw) => queue.push((side, raw)),
-                                    Err(e) => warn!("Error encoding packet: {:?}", e),
-                                }
-                            }
-
-                            // finally, return the queue
-                            Box::new(futures::stream::iter_ok(queue))
-                        },
-                    )
-                    .flatten()
-                    .forward(sink)
+    /// Accept a given client connection like `accept_client`, but using
+    /// `connect` to open the connection to the chosen server. This allows the
+    /// server to be replaced, e.g. by a `MemoryConnection` in tests.
+    pub fn accept_client_with<C, S, F, R>(
+        self: Arc<Self>,
+        client: C,
+        connect: F,
+    ) -> impl Future<Item = (), Error = PipeError> + Send
+    where
+        C: Transport,
+        S: Transport,
+        F: FnOnce(SocketAddr) -> R + Send + 'static,
+        R: IntoFuture<Item = S, Error = IoError>,
+        R::Future: Send + 'static,
+    {
+        // wait for the first packet from the client before connecting
+        client
+            .into_future()
+            .map_err(|(e, _)| PipeError::from(e))
+            .and_then(move |(first, client)| {
+                let server_addr = self.get_target_server(first.as_ref());
+
+                connect(server_addr)
+                    .into_future()
                     .from_err()
-                    .map(|_| ())
+                    .map(move |server| (self, first, client, server))
+            })
+            .and_then(|(pipe, first, client, server)| {
+                // by now, both halves of the pipe have been connected
+                Session::new(pipe, client, server, first).map_err(PipeError::from)
             })
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::RC4_LEN;
+    use crate::pipe::Setting;
+    use bimap::BiHashMap;
+    use std::collections::HashMap;
+
+    struct Greeter {
+        greeting: String,
+    }
+
+    struct GreeterState;
+
+    impl PluginState for GreeterState {}
+
+    impl Plugin for Greeter {
+        fn name(&self) -> &str {
+            "greeter"
+        }
+
+        fn settings(&self) -> Vec<Setting> {
+            vec![Setting::new(
+                "greeting",
+                "The greeting to use",
+                SettingValue::Text(self.greeting.clone()),
+            )]
+        }
+
+        fn update_setting(&mut self, name: &str, value: SettingValue) -> Result<(), SettingError> {
+            match (name, value) {
+                ("greeting", SettingValue::Text(greeting)) => {
+                    self.greeting = greeting;
+                    Ok(())
+                }
+                ("greeting", value) => Err(SettingError::InvalidValue(
+                    name.to_string(),
+                    value.to_string(),
+                )),
+                _ => Err(SettingError::UnknownSetting(name.to_string())),
+            }
+        }
+
+        fn init_plugin(&mut self, _setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            Box::new(GreeterState)
+        }
+    }
+
+    #[test]
+    fn test_plugin_settings() {
+        let mappings = Mappings::new("00".repeat(RC4_LEN), BiHashMap::new()).unwrap();
+        let mut servers = HashMap::new();
+        servers.insert("USWest", IpAddr::from([127, 0, 0, 1]));
+
+        let pipe = Pipe::builder()
+            .mappings(Arc::new(mappings))
+            .servers(ServerList::new(&servers), "usw")
+            .plugin(Box::new(Greeter {
+                greeting: "hello".to_owned(),
+            }))
+            .build()
+            .unwrap();
+
+        assert!(pipe.set_plugin_enabled("greeter", false, true));
+        assert!(!pipe.set_plugin_enabled("unknown", false, true));
+
+        pipe.update_plugin_setting("greeter", "greeting", SettingValue::Text("hi".to_owned()))
+            .unwrap();
+        assert!(pipe
+            .update_plugin_setting("greeter", "greeting", SettingValue::Bool(true))
+            .is_err());
+
+        let plugins = pipe.plugins();
+        assert_eq!(plugins.len(), 1);
+        assert!(!plugins[0].enabled);
+        assert_eq!(
+            plugins[0].settings[0].value,
+            SettingValue::Text("hi".to_owned())
+        );
     }
 }
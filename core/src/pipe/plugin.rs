@@ -1,15 +1,148 @@
-use super::{AutoPacket, PacketContext};
-use crate::proxy::Connection;
+use super::{
+    AutoPacket, DisconnectReason, PacketContext, PacketFuture, PacketHandlers, PipeError,
+    Scheduler, SessionHandle, SessionId, SessionInfo, Setting, SettingError, SettingValue, TimerId,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 /// A plugin to handle events
+#[allow(unused_variables)]
 pub trait Plugin: Send {
-    /// Handle a new connection, initializing a new plugin state for it
-    fn init_plugin(&mut self, client: &Connection, server: &Connection) -> Box<dyn PluginState>;
+    /// Get the name of this plugin, which must be unique within a pipe
+    fn name(&self) -> &str;
+
+    /// Get a short description of what this plugin does
+    fn description(&self) -> &str {
+        ""
+    }
+
+    /// Get the current values of the settings exposed by this plugin
+    fn settings(&self) -> Vec<Setting> {
+        Vec::new()
+    }
+
+    /// Change one of the settings exposed by this plugin. Plugin states which
+    /// have already been initialized for existing sessions are unaffected,
+    /// unless the plugin shares the setting with them.
+    fn update_setting(&mut self, name: &str, value: SettingValue) -> Result<(), SettingError> {
+        Err(SettingError::UnknownSetting(name.to_string()))
+    }
+
+    /// Handle a new connection, initializing a new plugin state for it. Typed
+    /// packet handlers for the session may be registered using `setup`.
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState>;
+}
+
+/// A snapshot of the details of a plugin, as returned by `Pipe::plugins`
+#[derive(Debug, Clone)]
+pub struct PluginInfo {
+    /// The name of the plugin
+    pub name: String,
+
+    /// A short description of the plugin
+    pub description: String,
+
+    /// Whether the plugin is enabled for new sessions
+    pub enabled: bool,
+
+    /// The current values of the plugin's settings
+    pub settings: Vec<Setting>,
 }
 
 /// An instance of a plugin for a single connection
 #[allow(unused_variables)]
 pub trait PluginState: Send {
-    /// Handle an intercepted packet
+    /// Handle the start of the session, once both the client and server are
+    /// connected
+    fn on_connect(&mut self, client: SocketAddr, server: SocketAddr) {}
+
+    /// Handle an intercepted packet. This is called for every packet, after
+    /// any typed handlers registered by the same plugin. Packets can only be
+    /// decoded if some plugin has subscribed to their type through
+    /// `SessionSetup::handlers`.
     fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {}
+
+    /// Handle an intercepted packet asynchronously. This is called for every
+    /// packet, after `on_packet`. If a future is returned, the packet will be
+    /// held until it completes, along with any packets sent from the same side
+    /// afterwards, so the order of packets is preserved. Packets sent from the
+    /// other side are unaffected. The context the future resolves to may
+    /// cancel the packet or send extra packets, but the packet itself can no
+    /// longer be modified. If the future fails, the error will be emitted as a
+    /// warning and the packet will be sent as normal.
+    fn on_packet_async(
+        &mut self,
+        packet: &mut AutoPacket,
+        context: &mut PacketContext,
+    ) -> Option<PacketFuture> {
+        None
+    }
+
+    /// Handle a timer scheduled by this plugin with `TimerAction::Callback`.
+    /// The context may be used to send packets or schedule more timers, but
+    /// there is no packet to cancel.
+    fn on_timer(&mut self, timer: TimerId, context: &mut PacketContext) {}
+
+    /// Handle an error which is about to end the session. This is always
+    /// followed by a call to `on_disconnect`.
+    fn on_error(&mut self, error: &PipeError) {}
+
+    /// Handle the end of the session. This is the last callback invoked for a
+    /// session, and should be used to release any resources.
+    fn on_disconnect(&mut self, reason: DisconnectReason) {}
+}
+
+/// Information about a new session, passed to a plugin while it's being
+/// initialized
+pub struct SessionSetup {
+    info: Arc<SessionInfo>,
+    pub(crate) handlers: PacketHandlers,
+    pub(crate) timers: Scheduler,
+}
+
+impl SessionSetup {
+    pub(crate) fn new(info: Arc<SessionInfo>) -> Self {
+        Self {
+            info,
+            handlers: PacketHandlers::default(),
+            timers: Scheduler::default(),
+        }
+    }
+
+    /// Get the ID of the session
+    pub fn session_id(&self) -> SessionId {
+        self.info.id()
+    }
+
+    /// Get the live information about the session
+    pub fn session_info(&self) -> &Arc<SessionInfo> {
+        &self.info
+    }
+
+    /// Get the address of the connected client
+    pub fn client_addr(&self) -> SocketAddr {
+        self.info.client_addr()
+    }
+
+    /// Get the address of the connected server
+    pub fn server_addr(&self) -> SocketAddr {
+        self.info.server_addr()
+    }
+
+    /// Get a handle to the session, which may be kept to send packets from
+    /// outside of plugin callbacks
+    pub fn handle(&self) -> SessionHandle {
+        self.info.handle().clone()
+    }
+
+    /// Get the table of typed packet handlers for this plugin
+    pub fn handlers(&mut self) -> &mut PacketHandlers {
+        &mut self.handlers
+    }
+
+    /// Get the scheduler for this session, used to send packets or invoke
+    /// `PluginState::on_timer` later on
+    pub fn timers(&mut self) -> &mut Scheduler {
+        &mut self.timers
+    }
 }
@@ -0,0 +1,91 @@
+//! Bookkeeping for `Reconnect` packets which have been redirected through the
+//! proxy, so that the following client connection can be sent to the server
+//! it was originally meant for.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long a pending reconnect is remembered before it's discarded
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Identifies a pending reconnect. The client echoes the game ID, key time and
+/// key from a `Reconnect` packet back in the `Hello` packet of its next
+/// connection. The key is often empty, so the account's GUID is included too,
+/// to tell apart different clients moving to the same game.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub(crate) struct ReconnectKey {
+    guid: String,
+    game_id: u32,
+    key_time: u32,
+    key: Vec<u8>,
+}
+
+impl ReconnectKey {
+    /// Create a new key from the GUID of the account reconnecting and the
+    /// game ID, key time and key of a `Reconnect` or `Hello` packet
+    pub fn new(guid: &str, game_id: u32, key_time: u32, key: &[u8]) -> Self {
+        Self {
+            guid: guid.to_owned(),
+            game_id,
+            key_time,
+            key: key.to_vec(),
+        }
+    }
+}
+
+/// The real targets of reconnects which have been redirected to the proxy,
+/// but which haven't yet been used by a new client connection
+#[derive(Debug, Default)]
+pub(crate) struct PendingReconnects {
+    pending: HashMap<ReconnectKey, (SocketAddr, Instant)>,
+}
+
+impl PendingReconnects {
+    /// Remember the real target of a redirected reconnect, replacing any
+    /// existing target with the same key
+    pub fn insert(&mut self, key: ReconnectKey, target: SocketAddr) {
+        self.expire();
+        self.pending.insert(key, (target, Instant::now()));
+    }
+
+    /// Get and forget the real target of a redirected reconnect
+    pub fn take(&mut self, key: &ReconnectKey) -> Option<SocketAddr> {
+        self.expire();
+        self.pending.remove(key).map(|(target, _)| target)
+    }
+
+    /// Discard any reconnects which are too old to be used
+    fn expire(&mut self) {
+        self.pending
+            .retain(|_, (_, created)| created.elapsed() < RECONNECT_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_take_reconnect() {
+        let target = SocketAddr::from_str("10.0.0.1:2050").unwrap();
+        let mut pending = PendingReconnects::default();
+
+        pending.insert(ReconnectKey::new("a", 5, 0, &[1, 2, 3]), target);
+
+        assert_eq!(pending.take(&ReconnectKey::new("a", 5, 0, &[1, 2])), None);
+        assert_eq!(
+            pending.take(&ReconnectKey::new("b", 5, 0, &[1, 2, 3])),
+            None
+        );
+        assert_eq!(
+            pending.take(&ReconnectKey::new("a", 5, 0, &[1, 2, 3])),
+            Some(target)
+        );
+        assert_eq!(
+            pending.take(&ReconnectKey::new("a", 5, 0, &[1, 2, 3])),
+            None
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use super::pipe::PacketSide;
+use super::{SessionClosed, SessionHandle};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Identifies a session within a pipe
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct SessionId(pub(crate) usize);
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Packet and byte counters for one side of a session
+#[derive(Debug, Default)]
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Live information about a session, shared between the session itself and
+/// the `Pipe` which is running it
+#[derive(Debug)]
+pub struct SessionInfo {
+    id: SessionId,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    started: SystemTime,
+    started_instant: Instant,
+    account: Mutex<Option<String>>,
+    character_name: Mutex<Option<String>>,
+    from_client: Counters,
+    from_server: Counters,
+    handle: SessionHandle,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(
+        id: SessionId,
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+        handle: SessionHandle,
+    ) -> Self {
+        Self {
+            id,
+            client_addr,
+            server_addr,
+            started: SystemTime::now(),
+            started_instant: Instant::now(),
+            account: Mutex::new(None),
+            character_name: Mutex::new(None),
+            from_client: Counters::default(),
+            from_server: Counters::default(),
+            handle,
+        }
+    }
+
+    /// Get the ID of this session
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Get the address of the connected client
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// Get the address of the connected server
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// Get the time at which this session started
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Get how long this session has been running
+    pub fn uptime(&self) -> Duration {
+        self.started_instant.elapsed()
+    }
+
+    /// Get the GUID of the account playing in this session, once the client has
+    /// sent its `Hello`
+    pub fn account(&self) -> Option<String> {
+        self.account
+            .lock()
+            .expect("error acquiring account lock")
+            .clone()
+    }
+
+    pub(crate) fn set_account(&self, guid: String) {
+        *self.account.lock().expect("error acquiring account lock") = Some(guid);
+    }
+
+    /// Get the name of the character playing in this session, once it's known
+    pub fn character_name(&self) -> Option<String> {
+        self.character_name
+            .lock()
+            .expect("error acquiring character name lock")
+            .clone()
+    }
+
+    pub(crate) fn set_character_name(&self, name: String) {
+        *self
+            .character_name
+            .lock()
+            .expect("error acquiring character name lock") = Some(name);
+    }
+
+    fn counters(&self, side: PacketSide) -> &Counters {
+        match side {
+            PacketSide::Client => &self.from_client,
+            PacketSide::Server => &self.from_server,
+        }
+    }
+
+    /// Get the number of packets received from the given side
+    pub fn packets(&self, from: PacketSide) -> u64 {
+        self.counters(from).packets.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes received from the given side
+    pub fn bytes(&self, from: PacketSide) -> u64 {
+        self.counters(from).bytes.load(Ordering::Relaxed)
+    }
+
+    /// Record a packet of the given size received from the given side
+    pub(crate) fn record_packet(&self, from: PacketSide, size: usize) {
+        let counters = self.counters(from);
+        counters.packets.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    /// Get a handle to this session
+    pub fn handle(&self) -> &SessionHandle {
+        &self.handle
+    }
+
+    /// Forcibly disconnect this session. See `SessionHandle::disconnect`.
+    pub fn disconnect(&self) -> Result<(), SessionClosed> {
+        self.handle.disconnect()
+    }
+}
@@ -0,0 +1,532 @@
+//! Offline replay of recorded sessions through plugins
+
+use super::handle::{SessionCommand, SessionCommands};
+use super::session::{is_subscribed, ScheduledTimer, SessionPlugin};
+use super::timer::{TimerCommand, MIN_INTERVAL};
+use super::{
+    AutoPacket, DisconnectReason, PacketContext, Plugin, SessionHandle, SessionId, SessionInfo,
+    SessionSetup, TimerAction, TimerId,
+};
+use crate::capture::{CaptureError, CaptureReader, CapturedPacket};
+use crate::mappings::Mappings;
+use crate::packets::Packet;
+use futures::executor::{spawn, Notify, NotifyHandle, Spawn};
+use futures::{Async, Future};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Something done by a plugin during a replay
+#[derive(Debug, Clone)]
+pub enum ReplayAction {
+    /// The plugin cancelled the packet it was handling
+    Cancelled,
+
+    /// The plugin sent a packet, either from a callback or a timer
+    Injected(Box<Packet>),
+}
+
+/// An action taken by a plugin, along with when it happened
+#[derive(Debug, Clone)]
+pub struct ReplayEvent {
+    /// The time on the virtual clock, relative to the first replayed packet
+    pub time: Duration,
+
+    /// The index of the replayed packet being handled, or `None` if the action
+    /// was taken by a timer or through a session handle
+    pub packet: Option<usize>,
+
+    /// What was done
+    pub action: ReplayAction,
+}
+
+/// Everything a single plugin did during a replay
+#[derive(Debug, Clone)]
+pub struct PluginReport {
+    /// The name of the plugin
+    pub name: String,
+
+    /// The actions taken by the plugin, in the order they happened
+    pub events: Vec<ReplayEvent>,
+}
+
+impl PluginReport {
+    /// Get the indices of the replayed packets cancelled by the plugin
+    pub fn cancelled(&self) -> impl Iterator<Item = usize> + '_ {
+        self.events.iter().filter_map(|e| match e.action {
+            ReplayAction::Cancelled => e.packet,
+            ReplayAction::Injected(_) => None,
+        })
+    }
+
+    /// Get the packets sent by the plugin
+    pub fn injected(&self) -> impl Iterator<Item = &Packet> {
+        self.events.iter().filter_map(|e| match &e.action {
+            ReplayAction::Injected(packet) => Some(&**packet),
+            ReplayAction::Cancelled => None,
+        })
+    }
+}
+
+/// The results of a replay
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    /// The number of packets replayed
+    pub packets: usize,
+
+    /// The time on the virtual clock when the replay finished
+    pub duration: Duration,
+
+    /// What each plugin did, in the order the plugins were invoked
+    pub plugins: Vec<PluginReport>,
+
+    /// Packets sent through session handles, directly or by their timers,
+    /// which can't be attributed to a single plugin
+    pub external: Vec<ReplayEvent>,
+}
+
+/// Used to poll for requests made through session handles outside of a task,
+/// since the replay checks for them itself rather than waiting to be notified
+struct NoopNotify;
+
+impl Notify for NoopNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+/// Runs recorded packets through a set of plugins without any network,
+/// recording what each plugin cancelled or sent.
+///
+/// Time is simulated by a virtual clock, which follows the times the packets
+/// were recorded at, so timers fire in the same order relative to the packets
+/// as they would have during the original session, but without waiting.
+///
+/// Asynchronous callbacks are waited for once every plugin has handled a
+/// packet, before the next packet is handled. They're waited for by blocking
+/// the calling thread rather than on a tokio runtime, so callbacks relying on
+/// tokio's timers or IO will fail, and waiting doesn't advance the virtual
+/// clock.
+///
+/// # Example
+///
+/// ```no_run
+/// use realmpipe_core::capture::CaptureReader;
+/// use realmpipe_core::pipe::Replay;
+/// use std::path::Path;
+///
+/// let capture = CaptureReader::open(Path::new("session.rpcap")).unwrap();
+/// let report = Replay::run(capture, vec![/* plugins */]).unwrap();
+///
+/// for plugin in &report.plugins {
+///     println!("{} cancelled {:?}", plugin.name, plugin.cancelled().collect::<Vec<_>>());
+/// }
+/// ```
+pub struct Replay {
+    mappings: Arc<Mappings>,
+    info: Arc<SessionInfo>,
+    plugins: Vec<SessionPlugin>,
+    reports: Vec<PluginReport>,
+    external: Vec<ReplayEvent>,
+    commands: Spawn<SessionCommands>,
+    timers: BTreeMap<(Duration, u64), ScheduledTimer>,
+    timer_keys: HashMap<TimerId, (Duration, u64)>,
+    next_timer: u64,
+    started: Option<SystemTime>,
+    now: Duration,
+    packets: usize,
+}
+
+impl Replay {
+    /// Initialize the plugins for a new replayed session, using the mappings
+    /// the session was recorded with
+    pub fn new(mappings: Arc<Mappings>, plugins: Vec<Box<dyn Plugin>>) -> Self {
+        // there's no real connection, so the addresses are unspecified
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let (handle, commands) = SessionHandle::new();
+        let info = Arc::new(SessionInfo::new(SessionId(0), addr, addr, handle));
+
+        let mut replay = Self {
+            mappings,
+            info,
+            plugins: Vec::with_capacity(plugins.len()),
+            reports: Vec::with_capacity(plugins.len()),
+            external: Vec::new(),
+            commands: spawn(commands),
+            timers: BTreeMap::new(),
+            timer_keys: HashMap::new(),
+            next_timer: 0,
+            started: None,
+            now: Duration::default(),
+            packets: 0,
+        };
+
+        for (i, mut plugin) in plugins.into_iter().enumerate() {
+            let mut setup = SessionSetup::new(Arc::clone(&replay.info));
+            let mut state = plugin.init_plugin(&mut setup);
+            state.on_connect(addr, addr);
+
+            replay.reports.push(PluginReport {
+                name: plugin.name().to_string(),
+                events: Vec::new(),
+            });
+            replay.plugins.push(SessionPlugin {
+                handlers: setup.handlers,
+                state,
+                enabled: true,
+            });
+            replay.schedule(Some(i), setup.timers.take_commands());
+        }
+
+        replay
+    }
+
+    /// Replay every packet from a capture file through the given plugins
+    pub fn run<R: Read>(
+        capture: CaptureReader<R>,
+        plugins: Vec<Box<dyn Plugin>>,
+    ) -> Result<ReplayReport, CaptureError> {
+        let mut replay = Self::new(Arc::new(capture.mappings().clone()), plugins);
+
+        for packet in capture {
+            replay.handle_packet(packet?);
+        }
+
+        Ok(replay.finish())
+    }
+
+    /// Get the current time on the virtual clock, relative to the first
+    /// replayed packet
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Advance the virtual clock, firing any timers which become due
+    pub fn advance(&mut self, by: Duration) {
+        self.advance_to(self.now + by);
+    }
+
+    /// Advance the virtual clock to the given time, firing timers in order
+    fn advance_to(&mut self, until: Duration) {
+        while let Some(&key) = self.timers.keys().next() {
+            if key.0 > until {
+                break;
+            }
+
+            let timer = self.timers.remove(&key).unwrap();
+            self.now = key.0;
+
+            // repeating timers are scheduled again straight away
+            if let Some(interval) = timer.interval {
+                let key = self.insert_timer(timer.clone(), interval.max(MIN_INTERVAL));
+                self.timer_keys.insert(timer.id, key);
+            } else {
+                self.timer_keys.remove(&timer.id);
+                if timer.plugin.is_none() {
+                    self.commands.get_ref().timer_done();
+                }
+            }
+
+            // timers of disabled plugins are kept, but don't do anything
+            if let Some(plugin) = timer.plugin {
+                if !self.plugins[plugin].enabled {
+                    continue;
+                }
+            }
+
+            match (timer.action, timer.plugin) {
+                (TimerAction::SendPacket(pkt), Some(plugin)) => {
+                    self.record(plugin, None, ReplayAction::Injected(pkt))
+                }
+                (TimerAction::SendPacket(pkt), None) => self.external.push(ReplayEvent {
+                    time: self.now,
+                    packet: None,
+                    action: ReplayAction::Injected(pkt),
+                }),
+                (TimerAction::Callback, Some(plugin)) => {
+                    let mut ctx = PacketContext::default();
+                    self.plugins[plugin].state.on_timer(timer.id, &mut ctx);
+                    self.apply(plugin, None, ctx);
+                }
+                // session handles can only schedule packets to be sent
+                (TimerAction::Callback, None) => {}
+            }
+
+            self.poll_commands();
+        }
+
+        self.now = self.now.max(until);
+    }
+
+    /// Handle a recorded packet, first advancing the virtual clock to the time
+    /// it was recorded at. Packets recorded earlier than the previous packet
+    /// are handled at the current time.
+    pub fn handle_packet(&mut self, packet: CapturedPacket) {
+        let started = *self.started.get_or_insert(packet.time);
+        self.advance_to(packet.time.duration_since(started).unwrap_or_default());
+
+        let index = self.packets;
+        self.packets += 1;
+        self.info.record_packet(packet.side, packet.raw.size());
+
+        let mappings = Arc::clone(&self.mappings);
+        let mut auto = AutoPacket::new(packet.raw, &mappings);
+        auto.set_decodable(is_subscribed(&self.plugins, &auto));
+
+        // the plugins share a context, as they would in a live session, so
+        // actions are attributed by how each plugin changed it
+        let mut ctx = PacketContext {
+            side: Some(packet.side),
+            ..PacketContext::default()
+        };
+        let mut futures = vec![];
+
+        for i in 0..self.plugins.len() {
+            if !self.plugins[i].enabled {
+                continue;
+            }
+
+            let (cancelled, extra) = (ctx.cancelled, ctx.extra.len());
+            futures.extend(
+                self.plugins[i]
+                    .handle_packet(&mut auto, &mut ctx)
+                    .map(|f| (i, f)),
+            );
+
+            if ctx.cancelled && !cancelled {
+                self.record(i, Some(index), ReplayAction::Cancelled);
+            }
+            for pkt in &ctx.extra[extra..] {
+                self.record(
+                    i,
+                    Some(index),
+                    ReplayAction::Injected(Box::new(pkt.clone())),
+                );
+            }
+            self.schedule(Some(i), ctx.timers.take_commands());
+        }
+
+        for (i, future) in futures {
+            match future.wait() {
+                Ok(ctx) => self.apply(i, Some(index), ctx),
+                Err(e) => warn!("Error in asynchronous plugin callback: {}", e),
+            }
+        }
+
+        self.poll_commands();
+    }
+
+    /// End the replayed session, returning what each plugin did. Timers which
+    /// haven't fired yet are discarded.
+    pub fn finish(mut self) -> ReplayReport {
+        self.plugins
+            .iter_mut()
+            .for_each(|p| p.state.on_disconnect(DisconnectReason::Disconnected));
+        self.poll_commands();
+
+        ReplayReport {
+            packets: self.packets,
+            duration: self.now,
+            plugins: self.reports,
+            external: self.external,
+        }
+    }
+
+    /// Record the actions requested through a plugin's context
+    fn apply(&mut self, plugin: usize, packet: Option<usize>, mut ctx: PacketContext) {
+        if ctx.cancelled && packet.is_some() {
+            self.record(plugin, packet, ReplayAction::Cancelled);
+        }
+
+        for pkt in ctx.extra.drain(..) {
+            self.record(plugin, packet, ReplayAction::Injected(Box::new(pkt)));
+        }
+
+        self.schedule(Some(plugin), ctx.timers.take_commands());
+    }
+
+    /// Add an event to a plugin's report
+    fn record(&mut self, plugin: usize, packet: Option<usize>, action: ReplayAction) {
+        self.reports[plugin].events.push(ReplayEvent {
+            time: self.now,
+            packet,
+            action,
+        });
+    }
+
+    /// Add a timer to the queue, to fire after the given delay
+    fn insert_timer(&mut self, timer: ScheduledTimer, delay: Duration) -> (Duration, u64) {
+        // timers due at the same time fire in the order they were scheduled
+        let key = (self.now + delay, self.next_timer);
+        self.next_timer += 1;
+        self.timers.insert(key, timer);
+        key
+    }
+
+    /// Apply timer requests made by the plugin with the given index, or through
+    /// a session handle
+    fn schedule(&mut self, plugin: Option<usize>, commands: Vec<TimerCommand>) {
+        for command in commands {
+            match command {
+                TimerCommand::Schedule {
+                    id,
+                    delay,
+                    interval,
+                    action,
+                } => {
+                    let timer = ScheduledTimer {
+                        plugin,
+                        id,
+                        interval,
+                        action,
+                    };
+                    let key = self.insert_timer(timer, delay);
+                    self.timer_keys.insert(id, key);
+                }
+                TimerCommand::Cancel(id) => {
+                    if let Some(key) = self.timer_keys.remove(&id) {
+                        let timer = self.timers.remove(&key);
+                        if let Some(ScheduledTimer { plugin: None, .. }) = timer {
+                            self.commands.get_ref().timer_done();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Carry out any requests made through session handles
+    fn poll_commands(&mut self) {
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+
+        // receiving from an unbounded channel can't fail
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll_stream_notify(&notify, 0) {
+            match command {
+                SessionCommand::SendPacket(pkt) => self.external.push(ReplayEvent {
+                    time: self.now,
+                    packet: None,
+                    action: ReplayAction::Injected(pkt),
+                }),
+                SessionCommand::Timer(command) => self.schedule(None, vec![command]),
+                // the replay always runs to the end of the recording
+                SessionCommand::Disconnect => {}
+                SessionCommand::SetPluginEnabled(index, enabled) => {
+                    if let Some(plugin) = self.plugins.get_mut(index) {
+                        plugin.enabled = enabled;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::mappings::RC4_LEN;
+    use crate::packets::{client, InternalPacketId};
+    use crate::pipe::{PacketSide, PluginState};
+    use crate::proxy::raw::RawPacket;
+    use bimap::BiHashMap;
+    use std::time::UNIX_EPOCH;
+
+    struct Censor;
+
+    struct CensorState;
+
+    impl PluginState for CensorState {
+        fn on_timer(&mut self, _timer: TimerId, context: &mut PacketContext) {
+            context.send_packet(text("ping"));
+        }
+    }
+
+    impl Plugin for Censor {
+        fn name(&self) -> &str {
+            "censor"
+        }
+
+        fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            setup.handlers().on(|pkt: &client::PlayerText, ctx| {
+                if pkt.text.starts_with('/') {
+                    ctx.cancel_packet();
+                    ctx.send_packet(text("censored"));
+                }
+            });
+            setup
+                .timers()
+                .every(Duration::from_secs(1), TimerAction::Callback)
+                .unwrap();
+            Box::new(CensorState)
+        }
+    }
+
+    /// Echoes every message, to check actions are attributed to the right
+    /// plugin
+    struct Echo;
+
+    impl PluginState for Echo {
+        fn on_packet(&mut self, _packet: &mut AutoPacket, context: &mut PacketContext) {
+            context.send_packet(text("echo"));
+        }
+    }
+
+    impl Plugin for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            setup.handlers().subscribe(InternalPacketId::PlayerText);
+            Box::new(Echo)
+        }
+    }
+
+    fn text(text: &str) -> Packet {
+        Packet::PlayerText(client::PlayerText {
+            text: RLE::new(text.to_owned()),
+        })
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        let mappings = Arc::new(Mappings::new("00".repeat(RC4_LEN), ids).unwrap());
+
+        let mut replay = Replay::new(
+            Arc::clone(&mappings),
+            vec![Box::new(Censor), Box::new(Echo)],
+        );
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        for (millis, msg) in &[(0, "hello"), (2500, "/tell someone"), (2600, "bye")] {
+            replay.handle_packet(CapturedPacket {
+                time: start + Duration::from_millis(*millis),
+                side: PacketSide::Client,
+                raw: RawPacket::from_packet(text(msg), &mappings).unwrap(),
+            });
+        }
+        let report = replay.finish();
+
+        assert_eq!(report.packets, 3);
+        assert_eq!(report.duration, Duration::from_millis(2600));
+
+        let censor = &report.plugins[0];
+        assert_eq!(censor.name, "censor");
+        assert_eq!(censor.cancelled().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(
+            censor.injected().cloned().collect::<Vec<_>>(),
+            vec![text("ping"), text("ping"), text("censored")]
+        );
+        assert_eq!(censor.events[1].time, Duration::from_secs(2));
+        assert_eq!(censor.events[1].packet, None);
+
+        let echo = &report.plugins[1];
+        assert_eq!(echo.cancelled().count(), 0);
+        assert_eq!(
+            echo.injected().cloned().collect::<Vec<_>>(),
+            vec![text("echo"); 3]
+        );
+    }
+}
@@ -0,0 +1,913 @@
+//! The state of a single proxied connection between a client and a server
+
+use super::handle::{SessionCommand, SessionCommands};
+use super::pipe::PacketSide;
+use super::timer::{check_timer, TimerCommand};
+use super::{
+    AutoPacket, PacketContext, PacketFuture, PacketHandlers, Pipe, PipeError, PluginState,
+    SessionHandle, SessionInfo, TimerAction, TimerId,
+};
+use crate::gamedata::{StatData, StatType};
+use crate::packets::{client, server, InternalPacketId, Packet};
+use crate::proxy::raw::RawPacket;
+use crate::proxy::Transport;
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::timer::delay_queue::Key;
+use tokio::timer::DelayQueue;
+
+/// The maximum number of packets queued for one side of the connection before
+/// the session stops reading from the other side, the maximum number of
+/// packets which may be waiting to be received from session handles, and the
+/// maximum number of unfinished timers scheduled through session handles
+pub(super) const MAX_QUEUED: usize = 64;
+
+/// The reason a session between a client and server ended
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisconnectReason {
+    /// The client closed its connection
+    ClientClosed,
+
+    /// The server closed its connection
+    ServerClosed,
+
+    /// The session was ended by an error, which will have been passed to
+    /// `PluginState::on_error` beforehand
+    Error,
+
+    /// The session was forcibly closed by the proxy, e.g. through
+    /// `SessionHandle::disconnect`
+    Disconnected,
+}
+
+/// A packet which has been handled by the plugins, but which may still be
+/// waiting on asynchronous callbacks before it can be sent
+struct PendingPacket {
+    /// The side the packet should be sent to, and the packet itself, unless it
+    /// was cancelled
+    packet: Option<(PacketSide, RawPacket)>,
+
+    /// Extra packets requested by plugins
+    extra: Vec<Packet>,
+
+    /// Timer requests from asynchronous callbacks, along with the index of the
+    /// plugin which made them
+    timers: Vec<(Option<usize>, TimerCommand)>,
+
+    /// Asynchronous callbacks which haven't completed yet, along with the
+    /// index of the plugin which returned them
+    futures: Vec<(usize, PacketFuture)>,
+}
+
+impl PendingPacket {
+    /// Poll the outstanding callbacks, becoming ready once all have completed
+    fn poll(&mut self) -> Async<()> {
+        let mut i = 0;
+
+        while i < self.futures.len() {
+            let (plugin, future) = &mut self.futures[i];
+
+            match future.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(mut ctx)) => {
+                    if ctx.cancelled {
+                        self.packet = None;
+                    }
+                    self.extra.extend(ctx.extra);
+
+                    let plugin = *plugin;
+                    self.timers.extend(
+                        ctx.timers
+                            .take_commands()
+                            .into_iter()
+                            .map(|c| (Some(plugin), c)),
+                    );
+                }
+                Err(e) => warn!("Error in asynchronous plugin callback: {}", e),
+            }
+
+            // the callback is done, so we won't poll it again
+            drop(self.futures.remove(i));
+        }
+
+        if self.futures.is_empty() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
+/// A timer which has been scheduled by a plugin
+#[derive(Clone)]
+pub(super) struct ScheduledTimer {
+    /// The index of the plugin which scheduled the timer, or `None` if it was
+    /// scheduled through a session handle
+    pub plugin: Option<usize>,
+    pub id: TimerId,
+    pub interval: Option<Duration>,
+    pub action: TimerAction,
+}
+
+/// The state of a plugin for a session
+pub(super) struct SessionPlugin {
+    // handlers are dropped before the state, which may be keeping the code for
+    // them loaded
+    pub handlers: PacketHandlers,
+    pub state: Box<dyn PluginState>,
+    pub enabled: bool,
+}
+
+impl SessionPlugin {
+    /// Invoke the plugin's callbacks for a packet, starting with its typed
+    /// handlers, and returning its asynchronous callback if it has one
+    pub fn handle_packet(
+        &mut self,
+        auto: &mut AutoPacket,
+        ctx: &mut PacketContext,
+    ) -> Option<PacketFuture> {
+        self.handlers.handle(auto, ctx);
+        self.state.on_packet(auto, ctx);
+        self.state.on_packet_async(auto, ctx)
+    }
+}
+
+/// Check whether any enabled plugin has subscribed to the type of a packet,
+/// meaning it may be decoded
+pub(super) fn is_subscribed(plugins: &[SessionPlugin], auto: &AutoPacket) -> bool {
+    let id = auto
+        .get_mappings()
+        .get_internal_id(auto.get_raw().game_id());
+    id.map(|id| {
+        plugins
+            .iter()
+            .any(|p| p.enabled && p.handlers.is_subscribed(id))
+    })
+    .unwrap_or(false)
+}
+
+/// A future driving a single connected client and server until either side
+/// disconnects, invoking plugin callbacks along the way
+pub(crate) struct Session<C, S> {
+    pipe: Arc<Pipe>,
+    info: Arc<SessionInfo>,
+    client: C,
+    server: S,
+    proxy_addr: SocketAddr,
+    server_addr: SocketAddr,
+    from_client: VecDeque<PendingPacket>,
+    from_server: VecDeque<PendingPacket>,
+    to_client: VecDeque<RawPacket>,
+    to_server: VecDeque<RawPacket>,
+    timers: DelayQueue<ScheduledTimer>,
+    timer_keys: HashMap<TimerId, Key>,
+    commands: SessionCommands,
+    closing: Option<DisconnectReason>,
+    player_id: Option<u32>,
+    player_named: bool,
+
+    // the plugins must be dropped after the pending packets, as the futures
+    // they returned may be using code from a plugin library
+    plugins: Vec<SessionPlugin>,
+}
+
+impl<C: Transport, S: Transport> Session<C, S> {
+    /// Start a new session between a connected client and server, initializing
+    /// the plugins. `first` is a packet already received from the client, which
+    /// will be handled before any others.
+    pub fn new(pipe: Arc<Pipe>, client: C, server: S, first: Option<RawPacket>) -> IoResult<Self> {
+        let client_addr = client.peer_addr()?;
+        let proxy_addr = client.local_addr()?;
+        let server_addr = server.peer_addr()?;
+
+        // add the session to the pipe's list of live sessions
+        let (handle, commands) = SessionHandle::new();
+        let info = pipe.register_session(client_addr, server_addr, handle);
+
+        debug!(
+            "Session {} started: {} <> {}",
+            info.id(),
+            client_addr,
+            server_addr
+        );
+
+        // initialize the plugins, keeping track of their initial timers
+        let mut plugins = Vec::new();
+        let mut timers = Vec::new();
+
+        for (i, (state, mut setup, enabled)) in pipe.init_plugins(&info).into_iter().enumerate() {
+            timers.extend(
+                setup
+                    .timers
+                    .take_commands()
+                    .into_iter()
+                    .map(|c| (Some(i), c)),
+            );
+            plugins.push(SessionPlugin {
+                state,
+                handlers: setup.handlers,
+                enabled,
+            });
+        }
+
+        // let the plugins know the session has started
+        plugins
+            .iter_mut()
+            .for_each(|p| p.state.on_connect(client_addr, server_addr));
+
+        let mut session = Self {
+            pipe,
+            info,
+            client,
+            server,
+            proxy_addr,
+            server_addr,
+            from_client: VecDeque::new(),
+            from_server: VecDeque::new(),
+            to_client: VecDeque::new(),
+            to_server: VecDeque::new(),
+            timers: DelayQueue::new(),
+            timer_keys: HashMap::new(),
+            commands,
+            closing: None,
+            player_id: None,
+            player_named: false,
+            plugins,
+        };
+
+        session.schedule(timers);
+
+        if let Some(first) = first {
+            session.handle_packet(PacketSide::Client, first);
+        }
+
+        Ok(session)
+    }
+
+    /// Get the queue of packets to be sent to the given side
+    fn queue(&mut self, to: PacketSide) -> &mut VecDeque<RawPacket> {
+        match to {
+            PacketSide::Client => &mut self.to_client,
+            PacketSide::Server => &mut self.to_server,
+        }
+    }
+
+    /// Get the queue of packets received from the given side which are still
+    /// waiting to be sent
+    fn pending(&mut self, from: PacketSide) -> &mut VecDeque<PendingPacket> {
+        match from {
+            PacketSide::Client => &mut self.from_client,
+            PacketSide::Server => &mut self.from_server,
+        }
+    }
+
+    /// Get the number of packets which are waiting to be sent to the given
+    /// side, whether or not they're ready
+    fn backlog(&self, to: PacketSide) -> usize {
+        match to {
+            PacketSide::Client => self.to_client.len() + self.from_server.len(),
+            PacketSide::Server => self.to_server.len() + self.from_client.len(),
+        }
+    }
+
+    /// Invoke plugin callbacks for a packet received from the given side, then
+    /// add it to the pending packets for that side
+    fn handle_packet(&mut self, side: PacketSide, raw: RawPacket) {
+        let pipe = Arc::clone(&self.pipe);
+        self.info.record_packet(side, raw.size());
+
+        // wrap the raw packet as an auto packet for easy downcasting, which
+        // plugins may only use if one of them subscribed to the packet type
+        let mut auto = AutoPacket::new(raw, pipe.get_mappings());
+        auto.set_decodable(is_subscribed(&self.plugins, &auto));
+
+        // create a packet context
+        let mut ctx = PacketContext {
+            side: Some(side),
+            ..PacketContext::default()
+        };
+
+        // invoke plugin callbacks
+        let mut futures = vec![];
+        let mut timers = vec![];
+        for (i, plugin) in self.plugins.iter_mut().enumerate() {
+            if !plugin.enabled {
+                continue;
+            }
+
+            futures.extend(plugin.handle_packet(&mut auto, &mut ctx).map(|f| (i, f)));
+
+            // keep track of which plugin requested each timer
+            timers.extend(ctx.timers.take_commands().into_iter().map(|c| (Some(i), c)));
+        }
+
+        self.schedule(timers);
+
+        // the session itself still needs to see a few packet types
+        auto.set_decodable(true);
+        self.track_character(side, &mut auto);
+
+        // if any plugin requested to cancel this packet, we don't send it
+        let packet = if !ctx.cancelled {
+            // keep the client behind the proxy when it changes servers
+            if let Some(reconnect) = auto.downcast_mut::<server::Reconnect>() {
+                let guid = self.info.account().unwrap_or_default();
+                pipe.redirect_reconnect(reconnect, &guid, self.server_addr, self.proxy_addr);
+            }
+
+            Some((side.other(), auto.into_raw()))
+        } else {
+            None
+        };
+
+        self.pending(side).push_back(PendingPacket {
+            packet,
+            extra: ctx.extra,
+            timers: vec![],
+            futures,
+        });
+    }
+
+    /// Keep track of who is playing in the session for the session info. The
+    /// account comes from the client's `Hello`, and the character's name from
+    /// the player's object in an `Update` after the server's `CreateSuccess`.
+    /// Only those packets are decoded here, and only when they're needed.
+    fn track_character(&mut self, side: PacketSide, auto: &mut AutoPacket) {
+        let id = match auto
+            .get_mappings()
+            .get_internal_id(auto.get_raw().game_id())
+        {
+            Some(id) => id,
+            None => return,
+        };
+
+        match (side, id) {
+            (PacketSide::Client, InternalPacketId::Hello) => {
+                if let Some(hello) = auto.downcast::<client::Hello>() {
+                    self.info.set_account(hello.guid.to_string());
+                }
+            }
+            (PacketSide::Server, InternalPacketId::MapInfo) => {
+                // the player will be created again for the new map
+                self.player_id = None;
+            }
+            (PacketSide::Server, InternalPacketId::CreateSuccess) => {
+                if let Some(success) = auto.downcast::<server::CreateSuccess>() {
+                    self.player_id = Some(success.object_id);
+                    self.player_named = false;
+                }
+            }
+            (PacketSide::Server, InternalPacketId::Update) => {
+                let player_id = match self.player_id {
+                    Some(id) if !self.player_named => id,
+                    _ => return,
+                };
+
+                let name = auto.downcast::<server::Update>().and_then(|update| {
+                    update
+                        .new_objs
+                        .iter()
+                        .filter(|obj| obj.status.object_id == player_id)
+                        .flat_map(|obj| obj.status.stats.iter())
+                        .filter_map(|stat| match stat {
+                            StatData::String(StatType::NAME_STAT, name) => Some(name.clone()),
+                            _ => None,
+                        })
+                        .next()
+                });
+
+                if let Some(name) = name {
+                    self.info.set_character_name(name);
+                    self.player_named = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply timer requests made by the plugins with the given indices, or
+    /// through session handles
+    fn schedule(&mut self, commands: Vec<(Option<usize>, TimerCommand)>) {
+        for (plugin, command) in commands {
+            match command {
+                TimerCommand::Schedule {
+                    id,
+                    delay,
+                    interval,
+                    action,
+                } => {
+                    // the timer wheel can't hold every duration, and repeating
+                    // timers with no interval would fire forever
+                    let (delay, interval) = match check_timer(delay, interval) {
+                        Ok(checked) => checked,
+                        Err(e) => {
+                            warn!("Error scheduling timer: {}", e);
+                            if plugin.is_none() {
+                                self.commands.timer_done();
+                            }
+                            continue;
+                        }
+                    };
+
+                    let timer = ScheduledTimer {
+                        plugin,
+                        id,
+                        interval,
+                        action,
+                    };
+                    let key = self.timers.insert(timer, delay);
+                    self.timer_keys.insert(id, key);
+                }
+                TimerCommand::Cancel(id) => {
+                    if let Some(key) = self.timer_keys.remove(&id) {
+                        if self.timers.remove(&key).into_inner().plugin.is_none() {
+                            self.commands.timer_done();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Perform the actions of any timers which have fired, returning whether
+    /// any did
+    fn poll_timers(&mut self) -> Result<bool, PipeError> {
+        let mut fired = false;
+
+        while let Async::Ready(Some(expired)) = self.timers.poll()? {
+            let timer = expired.into_inner();
+            fired = true;
+
+            // repeating timers are scheduled again straight away
+            if let Some(interval) = timer.interval {
+                let key = self.timers.insert(timer.clone(), interval);
+                self.timer_keys.insert(timer.id, key);
+            } else {
+                self.timer_keys.remove(&timer.id);
+                if timer.plugin.is_none() {
+                    self.commands.timer_done();
+                }
+            }
+
+            // timers of disabled plugins are kept, but don't do anything
+            if let Some(plugin) = timer.plugin {
+                if !self.plugins[plugin].enabled {
+                    continue;
+                }
+            }
+
+            match (timer.action, timer.plugin) {
+                (TimerAction::SendPacket(pkt), _) => self.send_packet(*pkt),
+                (TimerAction::Callback, Some(plugin)) => {
+                    let mut ctx = PacketContext::default();
+                    self.plugins[plugin].state.on_timer(timer.id, &mut ctx);
+
+                    let commands = ctx.timers.take_commands();
+                    self.schedule(commands.into_iter().map(|c| (Some(plugin), c)).collect());
+                    ctx.extra.into_iter().for_each(|pkt| self.send_packet(pkt));
+                }
+                // session handles can only schedule packets to be sent
+                (TimerAction::Callback, None) => {}
+            }
+        }
+
+        Ok(fired)
+    }
+
+    /// Carry out any requests made through session handles, returning whether
+    /// there were any
+    fn poll_commands(&mut self) -> bool {
+        let mut received = false;
+
+        // receiving from an unbounded channel can't fail
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll() {
+            received = true;
+
+            match command {
+                SessionCommand::SendPacket(pkt) => self.send_packet(*pkt),
+                SessionCommand::Timer(command) => self.schedule(vec![(None, command)]),
+                SessionCommand::Disconnect => {
+                    if self.closing.is_none() {
+                        self.closing = Some(DisconnectReason::Disconnected);
+                    }
+
+                    // don't wait on callbacks which may never complete
+                    self.release();
+                    self.abandon(PacketSide::Client);
+                    self.abandon(PacketSide::Server);
+                }
+                SessionCommand::SetPluginEnabled(index, enabled) => {
+                    if let Some(plugin) = self.plugins.get_mut(index) {
+                        plugin.enabled = enabled;
+                    }
+                }
+            }
+        }
+
+        received
+    }
+
+    /// Move any pending packets which are no longer waiting on asynchronous
+    /// callbacks to the queues to be sent, preserving the order packets were
+    /// received from each side. Returns whether any packets were released.
+    fn release(&mut self) -> bool {
+        let mut released = false;
+
+        for &from in &[PacketSide::Client, PacketSide::Server] {
+            while let Some(pending) = self.pending(from).front_mut() {
+                if pending.poll().is_not_ready() {
+                    break;
+                }
+
+                let pending = self.pending(from).pop_front().unwrap();
+                released = true;
+
+                if let Some((to, raw)) = pending.packet {
+                    self.send(to, raw);
+                }
+
+                // next, we add any packets that plugins requested to be sent
+                pending
+                    .extra
+                    .into_iter()
+                    .for_each(|pkt| self.send_packet(pkt));
+                self.schedule(pending.timers);
+            }
+        }
+
+        released
+    }
+
+    /// Encode a packet requested by a plugin and queue it to be sent to the
+    /// appropriate side. If an error occurs encoding the packet, the error will
+    /// be emitted as a warning, and the packet will be skipped.
+    fn send_packet(&mut self, pkt: Packet) {
+        let to = if pkt.get_internal_id().is_server() {
+            PacketSide::Client
+        } else {
+            PacketSide::Server
+        };
+
+        match RawPacket::from_packet(pkt, self.pipe.get_mappings()) {
+            Ok(raw) => self.send(to, raw),
+            Err(e) => warn!("Error encoding packet: {:?}", e),
+        }
+    }
+
+    /// Queue a packet to be sent to the given side, unless that side has
+    /// already disconnected
+    fn send(&mut self, to: PacketSide, raw: RawPacket) {
+        let closed = match to {
+            PacketSide::Client => DisconnectReason::ClientClosed,
+            PacketSide::Server => DisconnectReason::ServerClosed,
+        };
+
+        if self.closing != Some(closed) {
+            self.queue(to).push_back(raw);
+        }
+    }
+
+    /// Give up on the packets received from the given side which are still
+    /// waiting on asynchronous callbacks. The packets themselves are dropped,
+    /// but packets and timers requested by callbacks which already completed
+    /// are kept.
+    fn abandon(&mut self, from: PacketSide) {
+        let pending = std::mem::take(self.pending(from));
+        for mut pending in pending {
+            // collect anything from callbacks which have completed since the
+            // last poll, dropping the rest
+            let _ = pending.poll();
+
+            pending
+                .extra
+                .into_iter()
+                .for_each(|pkt| self.send_packet(pkt));
+            self.schedule(pending.timers);
+        }
+    }
+
+    /// Handle one side of the connection closing. Packets for that side can no
+    /// longer be delivered, but packets which plugins requested to be sent to
+    /// the other side alongside them are still sent. Packets still waiting on
+    /// asynchronous callbacks are abandoned, so the session can end.
+    fn close(&mut self, side: PacketSide) {
+        self.closing = Some(match side {
+            PacketSide::Client => DisconnectReason::ClientClosed,
+            PacketSide::Server => DisconnectReason::ServerClosed,
+        });
+        self.queue(side).clear();
+
+        self.release();
+        self.abandon(PacketSide::Client);
+        self.abandon(PacketSide::Server);
+    }
+
+    /// Poll the session, returning the reason it ended once it's finished
+    fn poll_session(&mut self) -> Poll<DisconnectReason, PipeError> {
+        loop {
+            // start by sending as many packets as we can
+            let fired = self.poll_timers()?;
+            let received = self.poll_commands();
+            let released = self.release();
+            let client_flushed = flush(&mut self.client, &mut self.to_client)?;
+            let server_flushed = flush(&mut self.server, &mut self.to_server)?;
+
+            // once one side has disconnected, we stop reading packets and just
+            // finish sending whatever was pending for the other side
+            if let Some(reason) = self.closing {
+                if self.from_client.is_empty()
+                    && self.from_server.is_empty()
+                    && client_flushed.is_ready()
+                    && server_flushed.is_ready()
+                {
+                    return Ok(Async::Ready(reason));
+                } else if released || fired || received {
+                    continue;
+                } else {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            let mut progress = released || fired || received;
+
+            // only read more packets if there's room to queue them
+            if self.backlog(PacketSide::Server) < MAX_QUEUED {
+                match self.client.poll()? {
+                    Async::Ready(Some(raw)) => {
+                        self.handle_packet(PacketSide::Client, raw);
+                        progress = true;
+                    }
+                    Async::Ready(None) => {
+                        self.close(PacketSide::Client);
+                        continue;
+                    }
+                    Async::NotReady => {}
+                }
+            }
+
+            if self.backlog(PacketSide::Client) < MAX_QUEUED {
+                match self.server.poll()? {
+                    Async::Ready(Some(raw)) => {
+                        self.handle_packet(PacketSide::Server, raw);
+                        progress = true;
+                    }
+                    Async::Ready(None) => {
+                        self.close(PacketSide::Server);
+                        continue;
+                    }
+                    Async::NotReady => {}
+                }
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// Send as many queued packets to the given connection as possible, becoming
+/// ready once the queue is empty and everything has been flushed
+fn flush<T: Transport>(conn: &mut T, queue: &mut VecDeque<RawPacket>) -> Poll<(), PipeError> {
+    while let Some(pkt) = queue.pop_front() {
+        if let AsyncSink::NotReady(pkt) = conn.start_send(pkt)? {
+            queue.push_front(pkt);
+            break;
+        }
+    }
+
+    match conn.poll_complete()? {
+        Async::Ready(()) if queue.is_empty() => Ok(Async::Ready(())),
+        _ => Ok(Async::NotReady),
+    }
+}
+
+impl<C: Transport, S: Transport> Future for Session<C, S> {
+    type Item = ();
+    type Error = PipeError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_session();
+
+        // let the plugins know if the session has ended
+        match &result {
+            Ok(Async::Ready(reason)) => {
+                debug!("Session {} ended: {:?}", self.info.id(), reason);
+                self.plugins
+                    .iter_mut()
+                    .for_each(|p| p.state.on_disconnect(*reason));
+            }
+            Err(e) => {
+                debug!("Session {} ended with error: {}", self.info.id(), e);
+                self.plugins.iter_mut().for_each(|p| {
+                    p.state.on_error(e);
+                    p.state.on_disconnect(DisconnectReason::Error);
+                });
+            }
+            Ok(Async::NotReady) => {}
+        }
+
+        result.map(|r| r.map(|_| ()))
+    }
+}
+
+impl<C, S> Drop for Session<C, S> {
+    fn drop(&mut self) {
+        self.pipe.unregister_session(self.info.id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::mappings::{Mappings, RC4_LEN};
+    use crate::pipe::{Plugin, SessionSetup};
+    use crate::proxy::codec::CodecError;
+    use crate::proxy::memory::MemoryConnection;
+    use crate::serverlist::ServerList;
+    use bimap::BiHashMap;
+    use futures::future::empty;
+    use std::net::IpAddr;
+    use std::thread::sleep;
+    use std::time::Instant;
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    /// Start a session through a pipe with the given plugin, returning the
+    /// fake client and server ends
+    fn start(plugin: Box<dyn Plugin>) -> (Runtime, Arc<Pipe>, MemoryConnection, MemoryConnection) {
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        ids.insert(2, InternalPacketId::Ping);
+        let mappings = Mappings::new("00".repeat(RC4_LEN), ids).unwrap();
+        let mut servers = HashMap::new();
+        servers.insert("USWest", IpAddr::from([127, 0, 0, 1]));
+
+        let pipe = Pipe::builder()
+            .mappings(Arc::new(mappings))
+            .servers(ServerList::new(&servers), "usw")
+            .plugin(plugin)
+            .build()
+            .unwrap();
+        let pipe = Arc::new(pipe);
+
+        let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let proxy_addr: SocketAddr = "127.0.0.1:2050".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:2051".parse().unwrap();
+        let (client, proxy_client) = MemoryConnection::pair(client_addr, proxy_addr);
+        let (proxy_server, server) = MemoryConnection::pair(proxy_addr, server_addr);
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(
+            Arc::clone(&pipe)
+                .accept_client_with(proxy_client, move |_| Ok(proxy_server))
+                .map_err(|e| panic!("session error: {}", e)),
+        );
+
+        (runtime, pipe, client, server)
+    }
+
+    fn send(conn: MemoryConnection, pipe: &Pipe, packets: Vec<Packet>) -> MemoryConnection {
+        let raw = packets
+            .into_iter()
+            .map(|p| RawPacket::from_packet(p, pipe.get_mappings()).unwrap());
+        let (conn, _) = conn
+            .send_all(stream::iter_ok::<_, CodecError>(raw))
+            .wait()
+            .unwrap();
+        conn
+    }
+
+    /// Collect the packets which arrive at a connection until the session ends
+    fn collect(conn: MemoryConnection, pipe: &Pipe) -> Vec<Packet> {
+        conn.collect()
+            .wait()
+            .unwrap()
+            .iter()
+            .map(|r| r.to_packet(pipe.get_mappings()).unwrap())
+            .collect()
+    }
+
+    /// Wait until the session has received the given number of packets from
+    /// the client and server
+    fn wait_for(pipe: &Pipe, from_client: u64, from_server: u64) {
+        let started = Instant::now();
+        while !pipe.sessions().iter().any(|s| {
+            s.packets(PacketSide::Client) == from_client
+                && s.packets(PacketSide::Server) == from_server
+        }) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn text(text: &str) -> Packet {
+        Packet::PlayerText(client::PlayerText {
+            text: RLE::new(text.to_owned()),
+        })
+    }
+
+    /// Holds "slow" messages for a while, then sends "done" after them
+    struct Slow;
+
+    impl Plugin for Slow {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            setup.handlers().subscribe(InternalPacketId::PlayerText);
+            Box::new(Slow)
+        }
+    }
+
+    impl PluginState for Slow {
+        fn on_packet_async(
+            &mut self,
+            packet: &mut AutoPacket,
+            _ctx: &mut PacketContext,
+        ) -> Option<PacketFuture> {
+            if &*packet.downcast::<client::PlayerText>()?.text != "slow" {
+                return None;
+            }
+
+            let delay = Delay::new(Instant::now() + Duration::from_millis(50));
+            Some(Box::new(delay.from_err().map(|_| {
+                let mut ctx = PacketContext::default();
+                ctx.send_packet(text("done"));
+                ctx
+            })))
+        }
+    }
+
+    /// Replies to server pings, but never lets them through
+    struct Stuck;
+
+    impl Plugin for Stuck {
+        fn name(&self) -> &str {
+            "stuck"
+        }
+
+        fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+            setup.handlers().subscribe(InternalPacketId::Ping);
+            Box::new(Stuck)
+        }
+    }
+
+    impl PluginState for Stuck {
+        fn on_packet_async(
+            &mut self,
+            packet: &mut AutoPacket,
+            ctx: &mut PacketContext,
+        ) -> Option<PacketFuture> {
+            packet.downcast::<server::Ping>()?;
+            ctx.send_packet(text("ack"));
+            Some(Box::new(empty()))
+        }
+    }
+
+    #[test]
+    fn test_async_delay() {
+        let (runtime, pipe, client, server) = start(Box::new(Slow));
+        let client = send(client, &pipe, vec![text("slow"), text("fast")]);
+        wait_for(&pipe, 2, 0);
+
+        // later packets wait for the delayed one, which is followed by the
+        // packet sent once its callback completed
+        let mut server = server.map(|r| r.to_packet(pipe.get_mappings()).unwrap());
+        let arrived = server.by_ref().take(3).collect().wait().unwrap();
+        assert_eq!(arrived, vec![text("slow"), text("done"), text("fast")]);
+
+        pipe.sessions()[0].disconnect().unwrap();
+        assert!(server.collect().wait().unwrap().is_empty());
+        drop(client);
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    #[test]
+    fn test_close_keeps_extras() {
+        let (runtime, pipe, client, server) = start(Box::new(Stuck));
+        let client = send(client, &pipe, vec![text("hello")]);
+        let server = send(
+            server,
+            &pipe,
+            vec![Packet::Ping(server::Ping { serial: 1 })],
+        );
+        wait_for(&pipe, 1, 1);
+
+        // the ping can't reach the client any more, but the reply to it should
+        // still reach the server
+        drop(client);
+        assert_eq!(collect(server, &pipe), vec![text("hello"), text("ack")]);
+        runtime.shutdown_on_idle().wait().unwrap();
+    }
+}
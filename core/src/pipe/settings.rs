@@ -0,0 +1,68 @@
+use failure_derive::Fail;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The value of a plugin setting
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SettingValue {
+    /// An on/off switch
+    Bool(bool),
+
+    /// A whole number
+    Integer(i64),
+
+    /// Arbitrary text
+    Text(String),
+}
+
+impl Display for SettingValue {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SettingValue::Bool(b) => write!(f, "{}", b),
+            SettingValue::Integer(i) => write!(f, "{}", i),
+            SettingValue::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// A setting exposed by a plugin, which may be changed while the proxy is
+/// running
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    /// The name used to identify this setting
+    pub name: String,
+
+    /// A short description of what the setting does
+    pub description: String,
+
+    /// The current value of the setting
+    pub value: SettingValue,
+}
+
+impl Setting {
+    /// Create a new setting
+    pub fn new(name: &str, description: &str, value: SettingValue) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            value,
+        }
+    }
+}
+
+/// An error changing a plugin setting
+#[derive(Debug, Clone, Fail)]
+pub enum SettingError {
+    /// There is no plugin with the given name
+    #[fail(display = "unknown plugin: {}", _0)]
+    UnknownPlugin(String),
+
+    /// The plugin has no setting with the given name
+    #[fail(display = "unknown setting: {}", _0)]
+    UnknownSetting(String),
+
+    /// The value isn't valid for the setting
+    #[fail(display = "invalid value for {}: {}", _0, _1)]
+    InvalidValue(String, String),
+}
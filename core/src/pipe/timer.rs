@@ -0,0 +1,149 @@
+use crate::packets::Packet;
+use failure_derive::Fail;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The next timer ID to hand out. These are unique across all sessions, so
+/// IDs can't be confused between plugins or sessions.
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The shortest interval a repeating timer may fire at. Shorter intervals are
+/// rounded up, so a timer can't fire forever without time passing.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The longest delay or interval a timer may be scheduled with. The timer
+/// wheel used by sessions can't hold deadlines much more than two years away.
+pub const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// An error returned when a timer can't be scheduled
+#[derive(Debug, Fail)]
+#[fail(display = "timer delay too long: {:?}", _0)]
+pub struct DelayTooLong(pub Duration);
+
+/// Check the delay and interval of a new timer, rounding up intervals which
+/// are too short
+pub(crate) fn check_timer(
+    delay: Duration,
+    interval: Option<Duration>,
+) -> Result<(Duration, Option<Duration>), DelayTooLong> {
+    let interval = interval.map(|i| i.max(MIN_INTERVAL));
+
+    for &duration in [Some(delay), interval].iter().flatten() {
+        if duration > MAX_DELAY {
+            return Err(DelayTooLong(duration));
+        }
+    }
+
+    Ok((delay, interval))
+}
+
+/// Identifies a timer scheduled by a plugin or through a session handle
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct TimerId(usize);
+
+impl TimerId {
+    /// Allocate a new, unique timer ID
+    pub(crate) fn next() -> Self {
+        TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// What happens when a timer fires
+#[derive(Debug, Clone)]
+pub enum TimerAction {
+    /// Send the given packet to the appropriate side of the connection. The
+    /// packet will not trigger plugin callbacks.
+    SendPacket(Box<Packet>),
+
+    /// Invoke `PluginState::on_timer` for the plugin which scheduled the timer
+    Callback,
+}
+
+/// A request to schedule or cancel a timer
+#[derive(Debug)]
+pub(crate) enum TimerCommand {
+    Schedule {
+        id: TimerId,
+        delay: Duration,
+        interval: Option<Duration>,
+        action: TimerAction,
+    },
+    Cancel(TimerId),
+}
+
+/// A handle used by plugins to schedule actions for their session at a later
+/// time. Requests are applied once the current callback returns, and any
+/// timers still scheduled when the session ends are discarded.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    commands: Vec<TimerCommand>,
+}
+
+impl Scheduler {
+    fn schedule(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+        action: TimerAction,
+    ) -> Result<TimerId, DelayTooLong> {
+        let (delay, interval) = check_timer(delay, interval)?;
+        let id = TimerId::next();
+        self.commands.push(TimerCommand::Schedule {
+            id,
+            delay,
+            interval,
+            action,
+        });
+        Ok(id)
+    }
+
+    /// Perform the given action once, after `delay` has elapsed. Fails if the
+    /// delay is longer than `MAX_DELAY`.
+    pub fn after(&mut self, delay: Duration, action: TimerAction) -> Result<TimerId, DelayTooLong> {
+        self.schedule(delay, None, action)
+    }
+
+    /// Perform the given action repeatedly, each time `interval` elapses,
+    /// until the timer is cancelled. Intervals shorter than a millisecond are
+    /// rounded up, and intervals longer than `MAX_DELAY` are rejected.
+    pub fn every(
+        &mut self,
+        interval: Duration,
+        action: TimerAction,
+    ) -> Result<TimerId, DelayTooLong> {
+        self.schedule(interval, Some(interval), action)
+    }
+
+    /// Cancel a timer, if it hasn't fired yet
+    pub fn cancel(&mut self, id: TimerId) {
+        self.commands.push(TimerCommand::Cancel(id));
+    }
+
+    /// Take the requests made since this was last called
+    pub(crate) fn take_commands(&mut self) -> Vec<TimerCommand> {
+        std::mem::take(&mut self.commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_timer() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            check_timer(ms(0), Some(ms(0))).unwrap(),
+            (ms(0), Some(ms(1)))
+        );
+        assert_eq!(check_timer(ms(5), None).unwrap(), (ms(5), None));
+        assert!(check_timer(MAX_DELAY + ms(1), None).is_err());
+        assert!(check_timer(ms(0), Some(Duration::from_secs(u64::MAX))).is_err());
+
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler
+            .after(Duration::from_secs(u64::MAX), TimerAction::Callback)
+            .is_err());
+        assert!(scheduler.take_commands().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+//! Connections within the same process, which can stand in for TCP
+//! connections to run a pipe without any sockets, e.g. in tests.
+
+use super::codec::CodecError;
+use super::raw::RawPacket;
+use super::Transport;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StartSend;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use tokio::prelude::*;
+
+/// One end of an in-memory duplex connection. Packets are passed to the other
+/// end as they are, without any framing or encryption. Once either end is
+/// dropped, the other end will receive any packets which were already sent,
+/// then its stream will finish.
+#[derive(Debug)]
+pub struct MemoryConnection {
+    local: SocketAddr,
+    peer: SocketAddr,
+    sender: UnboundedSender<RawPacket>,
+    receiver: UnboundedReceiver<RawPacket>,
+}
+
+impl MemoryConnection {
+    /// Create a pair of connected ends, with the given addresses. Packets sent
+    /// by one end are received by the other.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+        let (to_b, from_a) = unbounded();
+        let (to_a, from_b) = unbounded();
+
+        let a_end = Self {
+            local: a,
+            peer: b,
+            sender: to_b,
+            receiver: from_b,
+        };
+        let b_end = Self {
+            local: b,
+            peer: a,
+            sender: to_a,
+            receiver: from_a,
+        };
+
+        (a_end, b_end)
+    }
+}
+
+impl Stream for MemoryConnection {
+    type Item = RawPacket;
+    type Error = CodecError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // receiving from an unbounded channel can't fail
+        Ok(self.receiver.poll().unwrap())
+    }
+}
+
+impl Sink for MemoryConnection {
+    type SinkItem = RawPacket;
+    type SinkError = CodecError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.sender
+            .start_send(item)
+            .map_err(|_| IoError::new(ErrorKind::BrokenPipe, "connection closed").into())
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // sending to an unbounded channel completes immediately
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Transport for MemoryConnection {
+    fn local_addr(&self) -> IoResult<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        Ok(self.peer)
+    }
+}
@@ -1,11 +1,13 @@
 //! The actual implementation of the proxy server.
 
 pub mod codec;
+pub mod memory;
 mod policy;
 pub mod raw;
 
-use self::codec::Codec;
+use self::codec::{Codec, CodecError};
 use self::policy::handle_policy_request;
+use self::raw::RawPacket;
 use crate::mappings::Mappings;
 use std::convert::identity;
 use std::io::{Error as IoError, Result as IoResult};
@@ -18,6 +20,32 @@ use tokio::prelude::*;
 /// A framed TCP connection that operates on `RawPacket` instances
 pub type Connection = Framed<TcpStream, Codec>;
 
+/// A duplex connection carrying `RawPacket` instances, which a pipe may use
+/// for either side of a session. This is implemented for `Connection`, and by
+/// `memory::MemoryConnection` for connections within the same process.
+pub trait Transport:
+    Stream<Item = RawPacket, Error = CodecError>
+    + Sink<SinkItem = RawPacket, SinkError = CodecError>
+    + Send
+    + 'static
+{
+    /// Get the local address of this connection
+    fn local_addr(&self) -> IoResult<SocketAddr>;
+
+    /// Get the address of the remote end of this connection
+    fn peer_addr(&self) -> IoResult<SocketAddr>;
+}
+
+impl Transport for Connection {
+    fn local_addr(&self) -> IoResult<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+}
+
 fn configure_stream(s: TcpStream) -> IoResult<TcpStream> {
     s.set_nodelay(true)?;
 
@@ -56,6 +56,11 @@ impl RawPacket {
         self.bytes
     }
 
+    /// Get the total size of this packet in bytes, including the header
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
     /// Get the game ID representing this packet type
     pub fn game_id(&self) -> u8 {
         self.bytes[4]
@@ -0,0 +1,497 @@
+//! A plugin running user scripts written in [Rhai](https://rhai.rs), for
+//! quick experiments which don't justify writing and building a Rust plugin.
+//!
+//! Scripts subscribe to a packet type by defining a function named `on_`
+//! followed by the name of the packet type. When a packet of that type is
+//! received, the function is called with `this` bound to a map of the packet's
+//! fields, which may be read and modified. The following functions are also
+//! available to scripts:
+//!
+//! * `cancel()` cancels the packet being handled
+//! * `send(type, fields)` sends a new packet of the given type, built from a
+//!   map of its fields
+//! * `print(message)` writes a message to the log
+//!
+//! Scripts are reloaded automatically when the file changes, which affects
+//! existing sessions as well as new ones. However, handlers for packet types
+//! the script didn't handle when a session started only take effect in new
+//! sessions, since the pipe only decodes the packet types plugins subscribed
+//! to.
+//!
+//! # Examples
+//!
+//! ```rhai
+//! // hide chat messages containing a word, and reply to them instead
+//! fn on_Text() {
+//!     if this.text.contains("spam") {
+//!         cancel();
+//!         send("PlayerText", #{ text: "no thanks" });
+//!     }
+//! }
+//!
+//! // change what the player says
+//! fn on_PlayerText() {
+//!     this.text.replace("hello", "hi");
+//! }
+//! ```
+
+use crate::config::PluginRegistry;
+use crate::packets::{InternalPacketId, Packet};
+use crate::pipe::{
+    AutoPacket, PacketContext, Plugin, PluginState, SessionSetup, Setting, SettingError,
+    SettingValue,
+};
+use failure_derive::Fail;
+use log::{info, warn};
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, NativeCallContext, Scope, AST};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{metadata, read_dir};
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The file extension used for scripts
+pub const SCRIPT_EXTENSION: &str = "rhai";
+
+/// How often to check whether a script has changed
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum number of operations a single handler may perform, so a
+/// runaway script can't stall the session
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// An error loading or running a script
+#[derive(Debug, Fail)]
+pub enum ScriptError {
+    /// An error reading the script or scripts directory
+    #[fail(display = "error reading {}: {}", _0, _1)]
+    IoError(String, IoError),
+
+    /// The script couldn't be compiled
+    #[fail(display = "error compiling {}: {}", _0, _1)]
+    CompileError(String, String),
+
+    /// The script defines a handler for a packet type which doesn't exist
+    #[fail(display = "{} handles unknown packet type: {}", _0, _1)]
+    UnknownPacket(String, String),
+
+    /// An error occurred while running the script
+    #[fail(display = "error running {}: {}", _0, _1)]
+    RuntimeError(String, String),
+}
+
+/// A compiled version of a script
+struct Program {
+    ast: AST,
+    handlers: HashMap<InternalPacketId, String>,
+    modified: SystemTime,
+}
+
+/// The actions requested by a handler, collected while it runs
+#[derive(Default)]
+struct Actions {
+    cancel: bool,
+    send: Vec<Packet>,
+}
+
+type SharedActions = Arc<Mutex<Actions>>;
+
+/// Get the actions for the handler being called
+fn actions(context: &NativeCallContext) -> SharedActions {
+    context
+        .tag()
+        .and_then(|tag| tag.clone().try_cast())
+        .expect("script called outside of a handler")
+}
+
+/// Convert a packet to a map of its fields
+fn to_fields(packet: &Packet) -> Result<Dynamic, Box<EvalAltResult>> {
+    // packets are serialized as a map from the packet type to the fields
+    let mut map: Map = to_dynamic(packet)?.cast();
+    Ok(map.remove(packet.get_name()).unwrap_or_default())
+}
+
+/// Build a packet of the given type from a map of its fields
+fn from_fields(name: &str, fields: Dynamic) -> Result<Packet, Box<EvalAltResult>> {
+    let mut map = Map::new();
+    map.insert(name.into(), fields);
+    from_dynamic(&map.into())
+}
+
+/// A script and the engine used to run it
+struct Script {
+    path: PathBuf,
+    engine: Engine,
+    program: RwLock<Arc<Program>>,
+    auto_reload: AtomicBool,
+    last_check: Mutex<Instant>,
+}
+
+impl Script {
+    fn load(path: PathBuf) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let print_path = path.display().to_string();
+        engine.on_print(move |message| info!("[{}] {}", print_path, message));
+
+        engine.register_fn("cancel", |context: NativeCallContext| {
+            actions(&context).lock().unwrap().cancel = true;
+        });
+
+        engine.register_fn(
+            "send",
+            |context: NativeCallContext,
+             name: &str,
+             fields: Map|
+             -> Result<(), Box<EvalAltResult>> {
+                let packet = from_fields(name, fields.into())?;
+                actions(&context).lock().unwrap().send.push(packet);
+                Ok(())
+            },
+        );
+
+        let program = Self::compile(&engine, &path)?;
+
+        Ok(Self {
+            path,
+            engine,
+            program: RwLock::new(Arc::new(program)),
+            auto_reload: AtomicBool::new(true),
+            last_check: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn display(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn compile(engine: &Engine, path: &Path) -> Result<Program, ScriptError> {
+        let display = path.display().to_string();
+        let modified = metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(|e| ScriptError::IoError(display.clone(), e))?;
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| ScriptError::CompileError(display.clone(), e.to_string()))?;
+
+        let mut handlers = HashMap::new();
+        for function in ast.iter_functions() {
+            if let Some(name) = function.name.strip_prefix("on_") {
+                let id = InternalPacketId::get_name_mappings()
+                    .iter()
+                    .find(|(_, n)| **n == name)
+                    .map(|(id, _)| *id)
+                    .ok_or_else(|| ScriptError::UnknownPacket(display.clone(), name.to_string()))?;
+                handlers.insert(id, function.name.to_string());
+            }
+        }
+
+        Ok(Program {
+            ast,
+            handlers,
+            modified,
+        })
+    }
+
+    /// Compile the script again, replacing the current version if successful
+    fn reload(&self) -> Result<(), ScriptError> {
+        let program = Self::compile(&self.engine, &self.path)?;
+        *self.program.write().unwrap() = Arc::new(program);
+        info!("Reloaded script {}", self.display());
+        Ok(())
+    }
+
+    /// Get the current version of the script, reloading it first if it's
+    /// changed
+    fn program(&self) -> Arc<Program> {
+        let program = Arc::clone(&self.program.read().unwrap());
+
+        if self.auto_reload.load(Ordering::Relaxed) {
+            let mut last_check = self.last_check.lock().unwrap();
+            if last_check.elapsed() >= RELOAD_INTERVAL {
+                *last_check = Instant::now();
+
+                // if the file can't be read, keep using the current version
+                let changed = match metadata(&self.path).and_then(|m| m.modified()) {
+                    Ok(modified) => modified != program.modified,
+                    Err(_) => false,
+                };
+
+                if changed {
+                    match self.reload() {
+                        Ok(()) => return Arc::clone(&self.program.read().unwrap()),
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+            }
+        }
+
+        program
+    }
+
+    /// Check whether the script has a handler for the given type of packet
+    fn handles(&self, id: InternalPacketId) -> bool {
+        self.program().handlers.contains_key(&id)
+    }
+
+    /// Call the handler for a packet, returning the packet as modified by the
+    /// script, or `None` if the script doesn't handle this type of packet
+    fn handle(
+        &self,
+        packet: &Packet,
+        context: &mut PacketContext,
+    ) -> Result<Option<Packet>, ScriptError> {
+        let program = self.program();
+        let handler = match program.handlers.get(&packet.get_internal_id()) {
+            Some(handler) => handler,
+            None => return Ok(None),
+        };
+
+        let error =
+            |e: Box<EvalAltResult>| ScriptError::RuntimeError(self.display(), e.to_string());
+        let actions = SharedActions::default();
+        let mut fields = to_fields(packet).map_err(error)?;
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut fields)
+            .with_tag(Arc::clone(&actions));
+        // the handler's return value is ignored
+        let _: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), &program.ast, handler, ())
+            .map_err(error)?;
+
+        let actions = std::mem::take(&mut *actions.lock().unwrap());
+        if actions.cancel {
+            context.cancel_packet();
+        }
+        actions
+            .send
+            .into_iter()
+            .for_each(|p| context.send_packet(p));
+
+        from_fields(packet.get_name(), fields)
+            .map(Some)
+            .map_err(error)
+    }
+}
+
+/// A plugin running a script
+pub struct ScriptPlugin {
+    name: String,
+    description: String,
+    script: Arc<Script>,
+}
+
+impl ScriptPlugin {
+    /// Load and compile the script at the given path, creating a plugin with
+    /// the given name
+    pub fn load(name: &str, path: &Path) -> Result<Self, ScriptError> {
+        Ok(Self {
+            name: name.to_string(),
+            description: format!("Runs the script {}", path.display()),
+            script: Arc::new(Script::load(path.to_path_buf())?),
+        })
+    }
+
+    /// Reload the script from disk. If there's an error, the previous version
+    /// of the script will continue to be used.
+    pub fn reload(&self) -> Result<(), ScriptError> {
+        self.script.reload()
+    }
+}
+
+impl Clone for ScriptPlugin {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            script: Arc::clone(&self.script),
+        }
+    }
+}
+
+impl Plugin for ScriptPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        vec![Setting::new(
+            "auto_reload",
+            "Reload the script when the file changes",
+            SettingValue::Bool(self.script.auto_reload.load(Ordering::Relaxed)),
+        )]
+    }
+
+    fn update_setting(&mut self, name: &str, value: SettingValue) -> Result<(), SettingError> {
+        match (name, value) {
+            ("auto_reload", SettingValue::Bool(b)) => {
+                self.script.auto_reload.store(b, Ordering::Relaxed);
+                Ok(())
+            }
+            ("auto_reload", value) => Err(SettingError::InvalidValue(
+                name.to_string(),
+                value.to_string(),
+            )),
+            _ => Err(SettingError::UnknownSetting(name.to_string())),
+        }
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        for id in self.script.program().handlers.keys() {
+            setup.handlers().subscribe(*id);
+        }
+
+        Box::new(ScriptState {
+            script: Arc::clone(&self.script),
+        })
+    }
+}
+
+struct ScriptState {
+    script: Arc<Script>,
+}
+
+impl PluginState for ScriptState {
+    fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        // avoid decoding and copying packets the script doesn't handle
+        let id = packet
+            .get_mappings()
+            .get_internal_id(packet.get_raw().game_id());
+        match id {
+            Some(id) if self.script.handles(id) => {}
+            _ => return,
+        }
+
+        let original = match packet.get_any() {
+            Some(original) => original.clone(),
+            None => return,
+        };
+
+        match self.script.handle(&original, context) {
+            Ok(Some(modified)) => {
+                // only mark the packet as modified if it was actually changed,
+                // to avoid re-encoding it
+                if modified != original {
+                    if let Some(packet) = packet.get_any_mut() {
+                        *packet = modified;
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Load every script in the given directory, registering a plugin for each
+/// one named after the file. Returns the names of the plugins which were
+/// registered.
+pub fn load_directory(
+    dir: &Path,
+    registry: &mut PluginRegistry,
+) -> Result<Vec<String>, ScriptError> {
+    let io_error = |e| ScriptError::IoError(dir.display().to_string(), e);
+
+    let mut paths = Vec::new();
+    for entry in read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension() == Some(OsStr::new(SCRIPT_EXTENSION)) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut names = Vec::new();
+    for path in paths {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let plugin = ScriptPlugin::load(&name, &path)?;
+        registry.register(&name, move || Box::new(plugin.clone()));
+        names.push(name);
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::packets::{client, server};
+    use assert_matches::assert_matches;
+    use std::fs::write;
+
+    #[test]
+    fn test_handle_packet() {
+        // tests in other processes may be running at the same time
+        let path = std::env::temp_dir().join(format!(
+            "realmpipe_test_handle_packet_{}.rhai",
+            std::process::id()
+        ));
+        write(
+            &path,
+            r#"
+            fn on_PlayerText() {
+                if this.text == "cancel" {
+                    cancel();
+                    send("Text", #{
+                        name: "", object_id: 0, num_stars: 0, bubble_time: 0,
+                        recipient: "", text: "cancelled", clean_text: "",
+                        is_supporter: false
+                    });
+                } else {
+                    this.text += "!";
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let script = Script::load(path.clone()).unwrap();
+
+        let packet = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("hello".to_owned()),
+        });
+        let mut context = PacketContext::default();
+        let modified = script.handle(&packet, &mut context).unwrap();
+        assert_eq!(
+            modified,
+            Some(Packet::PlayerText(client::PlayerText {
+                text: RLE::new("hello!".to_owned())
+            }))
+        );
+        assert!(!context.cancelled);
+
+        let packet = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("cancel".to_owned()),
+        });
+        let mut context = PacketContext::default();
+        script.handle(&packet, &mut context).unwrap();
+        assert!(context.cancelled);
+        assert_matches!(
+            &context.extra[..],
+            [Packet::Text(server::Text { text, .. })] if &**text == "cancelled"
+        );
+
+        // packets without a handler aren't touched
+        let packet = Packet::CancelTrade(client::CancelTrade {});
+        assert_eq!(script.handle(&packet, &mut context).unwrap(), None);
+
+        write(&path, "fn on_NotAPacket() {}").unwrap();
+        assert!(script.reload().is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
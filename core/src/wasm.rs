@@ -0,0 +1,515 @@
+//! A plugin host running WebAssembly modules, allowing sandboxed plugins to be
+//! written in any language which compiles to WASM.
+//!
+//! Each session gets its own instance of the module, which sees packets in
+//! their encoded form. A module must export:
+//!
+//! * `memory`, its linear memory
+//! * `alloc(len: i32) -> i32`, returning a pointer to `len` bytes of memory
+//!   which the host may write a packet into
+//! * `on_packet(id: i32, side: i32, ptr: i32, len: i32) -> i32`, called for
+//!   every packet with its `InternalPacketId` (as `id as u8`), the side it was
+//!   sent from (0 for the client, 1 for the server) and a pointer to its
+//!   encoded contents. Returning 1 cancels the packet, while returning 0 lets
+//!   it through.
+//!
+//! Modules may import the following functions from the `realmpipe` module:
+//!
+//! * `send_packet(id: i32, ptr: i32, len: i32)` sends a new packet, given its
+//!   `InternalPacketId` and encoded contents
+//! * `log(ptr: i32, len: i32)` writes a UTF-8 message to the log
+//!
+//! Each packet handled by a module is limited to a fixed amount of fuel, which
+//! is consumed as instructions are executed, and the memory a module may use
+//! is limited. If a module runs out of fuel or otherwise traps, it's disabled
+//! for the rest of the session, so a buggy plugin can't stall the session.
+
+use crate::config::PluginRegistry;
+use crate::packets::{InternalPacketId, Packet};
+use crate::pipe::{
+    AutoPacket, PacketContext, PacketSide, Plugin, PluginState, SessionSetup, Setting,
+    SettingError, SettingValue,
+};
+use bytes::IntoBuf;
+use failure_derive::Fail;
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::{read, read_dir};
+use std::io::Error as IoError;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use wasmi::core::Trap;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+/// The file extension used for WASM modules
+pub const WASM_EXTENSION: &str = "wasm";
+
+/// The default amount of fuel available to a module for each packet
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// The maximum size of a module's memory, in bytes
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// The name of the module host functions are imported from
+const IMPORT_MODULE: &str = "realmpipe";
+
+/// An error loading or running a WASM module
+#[derive(Debug, Fail)]
+pub enum WasmError {
+    /// An error reading the module or modules directory
+    #[fail(display = "error reading {}: {}", _0, _1)]
+    IoError(String, IoError),
+
+    /// The module is invalid or doesn't export the required items
+    #[fail(display = "invalid module {}: {}", _0, _1)]
+    InvalidModule(String, String),
+
+    /// The module trapped or ran out of fuel
+    #[fail(display = "error running {}: {}", _0, _1)]
+    RuntimeError(String, String),
+}
+
+/// A packet sent by a module, as its packet type and encoded contents
+type SentPacket = (i32, Vec<u8>);
+
+/// The state available to host functions
+struct HostState {
+    name: String,
+    limits: StoreLimits,
+    sent: Vec<SentPacket>,
+}
+
+/// Read `len` bytes from the calling module's memory, starting at `ptr`
+fn read_memory(caller: &Caller<HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("module has no memory"))?;
+
+    // the range must be checked before allocating, since the module may pass
+    // any values at all
+    let start = usize::try_from(ptr).map_err(|_| Trap::new("negative pointer"))?;
+    let len = usize::try_from(len).map_err(|_| Trap::new("negative length"))?;
+    start
+        .checked_add(len)
+        .and_then(|end| memory.data(caller).get(start..end))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| Trap::new("out of bounds memory access"))
+}
+
+/// A module which has been compiled and checked, ready to be instantiated
+struct CompiledModule {
+    name: String,
+    engine: Engine,
+    module: Module,
+    fuel: AtomicU64,
+}
+
+impl CompiledModule {
+    fn new(name: &str, wasm: &[u8]) -> Result<Self, WasmError> {
+        let invalid = |message: String| WasmError::InvalidModule(name.to_string(), message);
+
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| invalid(e.to_string()))?;
+
+        for export in &["memory", "alloc", "on_packet"] {
+            if module.get_export(export).is_none() {
+                return Err(invalid(format!("missing export {}", export)));
+            }
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            engine,
+            module,
+            fuel: AtomicU64::new(DEFAULT_FUEL),
+        })
+    }
+}
+
+/// A single session's instance of a module
+struct ModuleInstance {
+    store: Store<HostState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_packet: TypedFunc<(i32, i32, i32, i32), i32>,
+    fuel_added: u64,
+}
+
+impl ModuleInstance {
+    fn new(module: &CompiledModule) -> Result<Self, wasmi::Error> {
+        let state = HostState {
+            name: module.name.clone(),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            sent: Vec::new(),
+        };
+        let mut store = Store::new(&module.engine, state);
+        store.limiter(|state| &mut state.limits);
+
+        let mut linker = Linker::new(&module.engine);
+        linker.func_wrap(
+            IMPORT_MODULE,
+            "send_packet",
+            |mut caller: Caller<HostState>, id: i32, ptr: i32, len: i32| -> Result<(), Trap> {
+                let contents = read_memory(&caller, ptr, len)?;
+                caller.data_mut().sent.push((id, contents));
+                Ok(())
+            },
+        )?;
+        linker.func_wrap(
+            IMPORT_MODULE,
+            "log",
+            |caller: Caller<HostState>, ptr: i32, len: i32| -> Result<(), Trap> {
+                let message = read_memory(&caller, ptr, len)?;
+                info!(
+                    "[{}] {}",
+                    caller.data().name,
+                    String::from_utf8_lossy(&message)
+                );
+                Ok(())
+            },
+        )?;
+
+        // the start function is limited by the same amount of fuel
+        let fuel_added = module.fuel.load(Ordering::Relaxed);
+        store.add_fuel(fuel_added)?;
+        let instance = linker
+            .instantiate(&mut store, &module.module)?
+            .start(&mut store)?;
+
+        Ok(Self {
+            memory: instance
+                .get_memory(&store, "memory")
+                .ok_or_else(|| Trap::new("module has no memory"))?,
+            alloc: instance.get_typed_func(&store, "alloc")?,
+            on_packet: instance.get_typed_func(&store, "on_packet")?,
+            store,
+            fuel_added,
+        })
+    }
+
+    /// Top up the remaining fuel to `fuel`
+    fn refuel(&mut self, fuel: u64) -> Result<(), wasmi::Error> {
+        let consumed = self.store.fuel_consumed().unwrap_or_default();
+        let remaining = self.fuel_added.saturating_sub(consumed);
+
+        if remaining < fuel {
+            self.store.add_fuel(fuel - remaining)?;
+            self.fuel_added += fuel - remaining;
+        }
+
+        Ok(())
+    }
+
+    /// Pass a packet to the module, returning whether it should be cancelled
+    /// and the packets the module sent
+    fn handle(
+        &mut self,
+        id: InternalPacketId,
+        side: PacketSide,
+        contents: &[u8],
+        fuel: u64,
+    ) -> Result<(bool, Vec<SentPacket>), wasmi::Error> {
+        self.refuel(fuel)?;
+
+        let len = contents.len() as i32;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as usize, contents)
+            .map_err(|e| Trap::new(e.to_string()))?;
+
+        let side = match side {
+            PacketSide::Client => 0,
+            PacketSide::Server => 1,
+        };
+        let result = self
+            .on_packet
+            .call(&mut self.store, (i32::from(id as u8), side, ptr, len));
+        let sent = std::mem::take(&mut self.store.data_mut().sent);
+
+        Ok((result? == 1, sent))
+    }
+}
+
+/// A plugin running a WASM module
+#[derive(Clone)]
+pub struct WasmPlugin {
+    description: String,
+    module: Arc<CompiledModule>,
+}
+
+impl WasmPlugin {
+    /// Compile a WASM module, creating a plugin with the given name
+    pub fn new(name: &str, wasm: &[u8]) -> Result<Self, WasmError> {
+        Ok(Self {
+            description: format!("Runs the WASM module {}", name),
+            module: Arc::new(CompiledModule::new(name, wasm)?),
+        })
+    }
+
+    /// Load and compile the WASM module at the given path, creating a plugin
+    /// with the given name
+    pub fn load(name: &str, path: &Path) -> Result<Self, WasmError> {
+        let wasm = read(path).map_err(|e| WasmError::IoError(path.display().to_string(), e))?;
+        let mut plugin = Self::new(name, &wasm)?;
+        plugin.description = format!("Runs the WASM module {}", path.display());
+        Ok(plugin)
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.module.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        vec![Setting::new(
+            "fuel",
+            "The amount of fuel the module may use for each packet",
+            SettingValue::Integer(self.module.fuel.load(Ordering::Relaxed) as i64),
+        )]
+    }
+
+    fn update_setting(&mut self, name: &str, value: SettingValue) -> Result<(), SettingError> {
+        match (name, value) {
+            ("fuel", SettingValue::Integer(fuel)) if fuel > 0 => {
+                self.module.fuel.store(fuel as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            ("fuel", value) => Err(SettingError::InvalidValue(
+                name.to_string(),
+                value.to_string(),
+            )),
+            _ => Err(SettingError::UnknownSetting(name.to_string())),
+        }
+    }
+
+    fn init_plugin(&mut self, _setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        let instance = match ModuleInstance::new(&self.module) {
+            Ok(instance) => Some(instance),
+            Err(e) => {
+                warn!(
+                    "{}",
+                    WasmError::RuntimeError(self.module.name.clone(), e.to_string())
+                );
+                None
+            }
+        };
+
+        Box::new(WasmState {
+            module: Arc::clone(&self.module),
+            instance,
+        })
+    }
+}
+
+struct WasmState {
+    module: Arc<CompiledModule>,
+
+    /// The instance of the module, or `None` if it's been disabled
+    instance: Option<ModuleInstance>,
+}
+
+impl PluginState for WasmState {
+    fn on_packet(&mut self, packet: &mut AutoPacket, context: &mut PacketContext) {
+        let (instance, side) = match (&mut self.instance, context.side()) {
+            (Some(instance), Some(side)) => (instance, side),
+            _ => return,
+        };
+        let id = match packet
+            .get_mappings()
+            .get_internal_id(packet.get_raw().game_id())
+        {
+            Some(id) => id,
+            None => return,
+        };
+
+        let fuel = self.module.fuel.load(Ordering::Relaxed);
+        // the module sees the packet as modified by earlier plugins
+        let contents = packet.to_raw().contents();
+        let (cancel, sent) = match instance.handle(id, side, &contents, fuel) {
+            Ok(result) => result,
+            Err(e) => {
+                let error = WasmError::RuntimeError(self.module.name.clone(), e.to_string());
+                warn!("{}, disabling it for this session", error);
+                self.instance = None;
+                return;
+            }
+        };
+
+        if cancel {
+            context.cancel_packet();
+        }
+
+        for (id, contents) in sent {
+            match decode_sent(id, contents) {
+                Ok(packet) => context.send_packet(packet),
+                Err(e) => warn!("Invalid packet sent by {}: {}", self.module.name, e),
+            }
+        }
+    }
+}
+
+/// Decode a packet sent by a module. The packet type is supplied by the module,
+/// so it may be out of range or unknown.
+fn decode_sent(id: i32, contents: Vec<u8>) -> Result<Packet, String> {
+    u8::try_from(id)
+        .ok()
+        .and_then(InternalPacketId::from_byte)
+        .ok_or_else(|| format!("unknown packet type {}", id))
+        .and_then(|id| Packet::from_bytes(id, &mut contents.into_buf()).map_err(|e| e.to_string()))
+}
+
+/// Load every WASM module in the given directory, registering a plugin for
+/// each one named after the file. Returns the names of the plugins which were
+/// registered.
+pub fn load_directory(dir: &Path, registry: &mut PluginRegistry) -> Result<Vec<String>, WasmError> {
+    let io_error = |e| WasmError::IoError(dir.display().to_string(), e);
+
+    let mut paths = Vec::new();
+    for entry in read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension() == Some(OsStr::new(WASM_EXTENSION)) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut names = Vec::new();
+    for path in paths {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let plugin = WasmPlugin::load(&name, &path)?;
+        registry.register(&name, move || Box::new(plugin.clone()));
+        names.push(name);
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::mappings::{Mappings, RC4_LEN};
+    use crate::packets::client;
+    use crate::proxy::raw::RawPacket;
+    use bimap::BiHashMap;
+
+    fn instance(wat: &str) -> ModuleInstance {
+        let wasm = wat::parse_str(wat).unwrap();
+        ModuleInstance::new(&CompiledModule::new("test", &wasm).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_handle_packet() {
+        // echoes every packet back and cancels it
+        let mut instance = instance(
+            r#"(module
+                (import "realmpipe" "send_packet" (func $send (param i32 i32 i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32)
+                    i32.const 1024)
+                (func (export "on_packet") (param i32 i32 i32 i32) (result i32)
+                    (call $send (local.get 0) (local.get 2) (local.get 3))
+                    i32.const 1))"#,
+        );
+
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        let mappings = Mappings::new("00".repeat(RC4_LEN), ids).unwrap();
+        let packet = Packet::PlayerText(client::PlayerText {
+            text: RLE::new("hello".to_owned()),
+        });
+        let contents = RawPacket::from_packet(packet.clone(), &mappings)
+            .unwrap()
+            .contents();
+
+        let (cancel, sent) = instance
+            .handle(
+                InternalPacketId::PlayerText,
+                PacketSide::Client,
+                &contents,
+                DEFAULT_FUEL,
+            )
+            .unwrap();
+        assert!(cancel);
+        assert_eq!(
+            sent,
+            vec![(InternalPacketId::PlayerText as i32, contents.to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_decode_sent() {
+        let id = InternalPacketId::CancelTrade as i32;
+        assert_eq!(
+            decode_sent(id, vec![]),
+            Ok(Packet::CancelTrade(client::CancelTrade {}))
+        );
+
+        // ids which don't fit in a byte aren't truncated
+        assert!(decode_sent(id + 256, vec![]).is_err());
+        assert!(decode_sent(-1, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_bad_memory_access() {
+        // one page of memory is 64 KiB
+        for (ptr, len) in &[(0, -1), (-1, 1), (65530, 100), (1, i32::MAX)] {
+            let mut instance = instance(&format!(
+                r#"(module
+                    (import "realmpipe" "send_packet" (func $send (param i32 i32 i32)))
+                    (memory (export "memory") 1)
+                    (func (export "alloc") (param i32) (result i32)
+                        i32.const 0)
+                    (func (export "on_packet") (param i32 i32 i32 i32) (result i32)
+                        (call $send (i32.const 1) (i32.const {}) (i32.const {}))
+                        i32.const 0))"#,
+                ptr, len
+            ));
+
+            let result = instance.handle(
+                InternalPacketId::CancelTrade,
+                PacketSide::Client,
+                &[],
+                DEFAULT_FUEL,
+            );
+            assert!(result.is_err(), "reading {} bytes at {}", len, ptr);
+        }
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        let mut instance = instance(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32)
+                    i32.const 0)
+                (func (export "on_packet") (param i32 i32 i32 i32) (result i32)
+                    (loop $forever (br $forever))
+                    i32.const 0))"#,
+        );
+
+        let result = instance.handle(
+            InternalPacketId::CancelTrade,
+            PacketSide::Client,
+            &[],
+            DEFAULT_FUEL,
+        );
+        assert!(result.is_err());
+    }
+}
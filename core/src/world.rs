@@ -0,0 +1,459 @@
+//! Tracking the state of the world in each session, so plugins can query the
+//! current map, the objects in it and the player without decoding the packets
+//! describing them.
+//!
+//! The `WorldTracker` plugin keeps a `WorldState` for each session, which
+//! other plugins may read through the `Worlds` it was created with. Along with
+//! the raw stats of each object, typed stats are kept for every object and the
+//! player, recording which fields changed on each update.
+//!
+//! The state of a session is updated when the tracker sees each packet, so the
+//! tracker should be added to a pipe before any plugins which use it, or they
+//! will see the state from before the packet they're handling.
+//!
+//! # Example
+//!
+//! ```
+//! use realmpipe_core::packets::client;
+//! use realmpipe_core::pipe::{Pipe, Plugin, PluginState, SessionSetup};
+//! use realmpipe_core::world::{WorldTracker, Worlds};
+//!
+//! struct Example {
+//!     worlds: Worlds,
+//! }
+//!
+//! struct ExampleState;
+//!
+//! impl PluginState for ExampleState {}
+//!
+//! impl Plugin for Example {
+//!     fn name(&self) -> &str {
+//!         "example"
+//!     }
+//!
+//!     fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+//!         // the tracker must be added to the pipe first for the world to exist
+//!         if let Some(world) = self.worlds.get(setup.session_id()) {
+//!             setup.handlers().on(move |_: &client::PlayerText, _| {
+//!                 let world = world.read();
+//!                 if let (Some(player), Some(stats)) = (world.player(), world.player_stats()) {
+//!                     println!("Player is at {:?} with {} HP", player.pos, stats.object.hp);
+//!                 }
+//!             });
+//!         }
+//!         Box::new(ExampleState)
+//!     }
+//! }
+//!
+//! let tracker = WorldTracker::new();
+//! let example = Example {
+//!     worlds: tracker.worlds(),
+//! };
+//! let builder = Pipe::builder().plugin(Box::new(tracker)).plugin(Box::new(example));
+//! ```
+
+use crate::gamedata::{
+    ObjectData, ObjectStats, ObjectStatusData, PlayerStats, StatData, StatField, StatType,
+    TypedStats, WorldPosData,
+};
+use crate::packets::{server, Packet};
+use crate::pipe::{DisconnectReason, Plugin, PluginState, SessionId, SessionSetup};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The details of the current map, from `MapInfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapDetails {
+    /// The internal name of the map
+    pub name: String,
+
+    /// The width of the map, in tiles
+    pub width: u32,
+
+    /// The height of the map, in tiles
+    pub height: u32,
+}
+
+/// An object which is currently in the world
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedObject {
+    /// The type of the object
+    pub object_type: u16,
+
+    /// The last known position of the object
+    pub pos: WorldPosData,
+
+    /// The latest value of every stat which has been received for the object
+    pub stats: BTreeMap<StatType, StatData>,
+
+    /// The typed stats of the object
+    pub object_stats: ObjectStats,
+
+    /// The fields of `object_stats` changed by the latest update to the
+    /// object. When the object is added, these are the fields which differ
+    /// from their defaults.
+    pub changed: Vec<StatField>,
+}
+
+impl TrackedObject {
+    fn new(data: &ObjectData) -> Self {
+        let mut object = Self {
+            object_type: data.object_type,
+            pos: data.status.pos.clone(),
+            stats: BTreeMap::new(),
+            object_stats: ObjectStats::default(),
+            changed: Vec::new(),
+        };
+        object.update(&data.status);
+        object
+    }
+
+    /// Merge an update to the object's status, replacing any stats which have
+    /// changed and keeping the rest
+    fn update(&mut self, status: &ObjectStatusData) {
+        self.pos = status.pos.clone();
+        for stat in status.stats.iter() {
+            let stat_type = match stat {
+                StatData::Integer(t, _) | StatData::String(t, _) => *t,
+            };
+            self.stats.insert(stat_type, stat.clone());
+        }
+        self.changed = self.object_stats.update(status.stats.iter());
+    }
+
+    /// Get the latest value of an integer stat, if it's been received
+    pub fn int_stat(&self, stat_type: StatType) -> Option<u32> {
+        match self.stats.get(&stat_type)? {
+            StatData::Integer(_, value) => Some(*value),
+            StatData::String(..) => None,
+        }
+    }
+
+    /// Get the latest value of a string stat, if it's been received
+    pub fn string_stat(&self, stat_type: StatType) -> Option<&str> {
+        match self.stats.get(&stat_type)? {
+            StatData::String(_, value) => Some(value),
+            StatData::Integer(..) => None,
+        }
+    }
+}
+
+/// The state of the world in a single session, built from the packets sent by
+/// the server
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    map: Option<MapDetails>,
+    tiles: HashMap<(u16, u16), u16>,
+    objects: HashMap<u32, TrackedObject>,
+    player_id: Option<u32>,
+    player_stats: Option<PlayerStats>,
+    player_changes: Vec<StatField>,
+}
+
+impl WorldState {
+    /// Update the state using a packet sent by the server. Packets which don't
+    /// describe the world are ignored.
+    pub fn update(&mut self, packet: &Packet) {
+        match packet {
+            Packet::MapInfo(info) => self.map_info(info),
+            Packet::CreateSuccess(success) => self.create_success(success),
+            Packet::Update(update) => self.update_objects(update),
+            Packet::NewTick(tick) => self.new_tick(tick),
+            _ => {}
+        }
+    }
+
+    fn map_info(&mut self, info: &server::MapInfo) {
+        // each connection is to a new map, so nothing carries over
+        *self = Self {
+            map: Some(MapDetails {
+                name: info.name.to_string(),
+                width: info.width,
+                height: info.height,
+            }),
+            ..Self::default()
+        };
+    }
+
+    fn create_success(&mut self, success: &server::CreateSuccess) {
+        self.player_id = Some(success.object_id);
+
+        // in case the player was added to the world first
+        if let Some(player) = self.objects.get(&success.object_id) {
+            let mut stats = PlayerStats::default();
+            self.player_changes = stats.update(player.stats.values());
+            self.player_stats = Some(stats);
+        }
+    }
+
+    fn update_objects(&mut self, update: &server::Update) {
+        for tile in update.tiles.iter() {
+            self.tiles.insert((tile.x, tile.y), tile.tile);
+        }
+        for data in update.new_objs.iter() {
+            self.objects
+                .insert(data.status.object_id, TrackedObject::new(data));
+
+            if Some(data.status.object_id) == self.player_id {
+                let mut stats = PlayerStats::default();
+                self.player_changes = stats.update(data.status.stats.iter());
+                self.player_stats = Some(stats);
+            }
+        }
+        for object_id in update.drops.iter() {
+            self.objects.remove(object_id);
+        }
+    }
+
+    fn new_tick(&mut self, tick: &server::NewTick) {
+        for status in tick.statuses.iter() {
+            if let Some(object) = self.objects.get_mut(&status.object_id) {
+                object.update(status);
+            }
+
+            if Some(status.object_id) == self.player_id {
+                if let Some(stats) = &mut self.player_stats {
+                    self.player_changes = stats.update(status.stats.iter());
+                }
+            }
+        }
+    }
+
+    /// Get the details of the current map, once `MapInfo` has been received
+    pub fn map(&self) -> Option<&MapDetails> {
+        self.map.as_ref()
+    }
+
+    /// Get the type of the tile at the given position, if it's been revealed
+    pub fn tile(&self, x: u16, y: u16) -> Option<u16> {
+        self.tiles.get(&(x, y)).cloned()
+    }
+
+    /// Get every tile which has been revealed, keyed by position
+    pub fn tiles(&self) -> &HashMap<(u16, u16), u16> {
+        &self.tiles
+    }
+
+    /// Get the object with the given ID, if it's in the world
+    pub fn object(&self, object_id: u32) -> Option<&TrackedObject> {
+        self.objects.get(&object_id)
+    }
+
+    /// Get every object in the world, keyed by object ID
+    pub fn objects(&self) -> &HashMap<u32, TrackedObject> {
+        &self.objects
+    }
+
+    /// Get the object ID of the player, once `CreateSuccess` has been received
+    pub fn player_id(&self) -> Option<u32> {
+        self.player_id
+    }
+
+    /// Get the player's object, once it's been added to the world
+    pub fn player(&self) -> Option<&TrackedObject> {
+        self.object(self.player_id?)
+    }
+
+    /// Get the typed stats of the player, once it's been added to the world
+    pub fn player_stats(&self) -> Option<&PlayerStats> {
+        self.player_stats.as_ref()
+    }
+
+    /// Get the fields of the player's stats changed by the latest update to
+    /// the player, e.g. on the last tick which included it
+    pub fn player_changes(&self) -> &[StatField] {
+        &self.player_changes
+    }
+}
+
+/// A read-only handle to the world state of a single session
+#[derive(Debug, Clone, Default)]
+pub struct SessionWorld(Arc<RwLock<WorldState>>);
+
+impl SessionWorld {
+    /// Lock the world state for reading. The state can't be updated while
+    /// it's locked, so the lock should be released before the next packet is
+    /// handled.
+    pub fn read(&self) -> RwLockReadGuard<'_, WorldState> {
+        self.0.read().expect("error acquiring world state lock")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, WorldState> {
+        self.0.write().expect("error acquiring world state lock")
+    }
+}
+
+/// The world states of every active session tracked by a `WorldTracker`,
+/// which may be shared with other plugins
+#[derive(Debug, Clone, Default)]
+pub struct Worlds {
+    sessions: Arc<Mutex<HashMap<SessionId, SessionWorld>>>,
+}
+
+impl Worlds {
+    fn lock(&self) -> MutexGuard<'_, HashMap<SessionId, SessionWorld>> {
+        self.sessions
+            .lock()
+            .expect("error acquiring world sessions lock")
+    }
+
+    /// Get the world state of the session with the given ID, if it's active
+    /// and the tracker has been initialized for it
+    pub fn get(&self, session: SessionId) -> Option<SessionWorld> {
+        self.lock().get(&session).cloned()
+    }
+
+    /// Get the IDs of every session being tracked
+    pub fn sessions(&self) -> Vec<SessionId> {
+        self.lock().keys().cloned().collect()
+    }
+}
+
+/// A plugin tracking the state of the world in each session
+#[derive(Debug, Clone, Default)]
+pub struct WorldTracker {
+    worlds: Worlds,
+}
+
+impl WorldTracker {
+    /// Create a new tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the world states tracked by this plugin
+    pub fn worlds(&self) -> Worlds {
+        self.worlds.clone()
+    }
+}
+
+impl Plugin for WorldTracker {
+    fn name(&self) -> &str {
+        "world"
+    }
+
+    fn description(&self) -> &str {
+        "Tracks the map, objects and player in each session for other plugins"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        let world = SessionWorld::default();
+        self.worlds.lock().insert(setup.session_id(), world.clone());
+
+        let (w1, w2, w3, w4) = (world.clone(), world.clone(), world.clone(), world);
+        setup
+            .handlers()
+            .on(move |info: &server::MapInfo, _| w1.write().map_info(info))
+            .on(move |success: &server::CreateSuccess, _| w2.write().create_success(success))
+            .on(move |update: &server::Update, _| w3.write().update_objects(update))
+            .on(move |tick: &server::NewTick, _| w4.write().new_tick(tick));
+
+        Box::new(WorldTrackerState {
+            id: setup.session_id(),
+            worlds: self.worlds.clone(),
+        })
+    }
+}
+
+struct WorldTrackerState {
+    id: SessionId,
+    worlds: Worlds,
+}
+
+impl PluginState for WorldTrackerState {
+    fn on_disconnect(&mut self, _reason: DisconnectReason) {
+        self.worlds.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::RLE;
+    use crate::gamedata::GroundTileData;
+
+    fn object(object_id: u32, x: f32, stats: Vec<StatData>) -> ObjectStatusData {
+        ObjectStatusData {
+            object_id,
+            pos: WorldPosData { x, y: 0.0 },
+            stats: RLE::new(stats),
+        }
+    }
+
+    #[test]
+    fn test_world_state() {
+        let mut world = WorldState::default();
+        world.update(&Packet::MapInfo(server::MapInfo {
+            width: 64,
+            height: 32,
+            name: RLE::new("Nexus".to_owned()),
+            display_name: RLE::new("Nexus".to_owned()),
+            fp: 0,
+            background: 0,
+            difficulty: 0,
+            allow_player_teleport: true,
+            show_displays: true,
+            client_xml: RLE::new(Vec::new()),
+            extra_xml: RLE::new(Vec::new()),
+        }));
+        world.update(&Packet::CreateSuccess(server::CreateSuccess {
+            object_id: 1,
+            char_id: 2,
+        }));
+        world.update(&Packet::Update(server::Update {
+            tiles: RLE::new(vec![GroundTileData {
+                x: 3,
+                y: 4,
+                tile: 5,
+            }]),
+            new_objs: RLE::new(vec![
+                ObjectData {
+                    object_type: 0x030e,
+                    status: object(
+                        1,
+                        1.0,
+                        vec![
+                            StatData::Integer(StatType::HP_STAT, 100),
+                            StatData::String(StatType::NAME_STAT, "Tester".to_owned()),
+                        ],
+                    ),
+                },
+                ObjectData {
+                    object_type: 0x0700,
+                    status: object(2, 2.0, vec![]),
+                },
+            ]),
+            drops: RLE::new(vec![]),
+        }));
+        world.update(&Packet::NewTick(server::NewTick {
+            tick_id: 1,
+            tick_time: 200,
+            statuses: RLE::new(vec![
+                object(1, 1.5, vec![StatData::Integer(StatType::HP_STAT, 80)]),
+                object(9, 0.0, vec![]),
+            ]),
+        }));
+        world.update(&Packet::Update(server::Update {
+            tiles: RLE::new(vec![]),
+            new_objs: RLE::new(vec![]),
+            drops: RLE::new(vec![2]),
+        }));
+
+        assert_eq!(world.map().unwrap().name, "Nexus");
+        assert_eq!(world.tile(3, 4), Some(5));
+        assert_eq!(world.tile(4, 3), None);
+        assert_eq!(world.objects().len(), 1);
+
+        let player = world.player().unwrap();
+        assert_eq!(player.object_type, 0x030e);
+        assert_eq!(player.pos, WorldPosData { x: 1.5, y: 0.0 });
+        assert_eq!(player.int_stat(StatType::HP_STAT), Some(80));
+        assert_eq!(player.string_stat(StatType::NAME_STAT), Some("Tester"));
+        assert_eq!(player.changed, vec![StatField::Hp]);
+
+        let stats = world.player_stats().unwrap();
+        assert_eq!(stats.object.hp, 80);
+        assert_eq!(stats.object.name, "Tester");
+        assert_eq!(world.player_changes(), &[StatField::Hp]);
+    }
+}
@@ -0,0 +1,31 @@
+//! Tests of loading a real plugin library, using the `plugin_library` example,
+//! which is built alongside the tests
+
+use realmpipe_core::config::PluginRegistry;
+use realmpipe_core::dynamic::load_library;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::env::current_exe;
+
+#[test]
+fn test_load_library() {
+    // tests are built in target/*/deps, and examples in target/*/examples
+    let path = current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .with_file_name("examples")
+        .join(format!("{}plugin_library{}", DLL_PREFIX, DLL_SUFFIX));
+    assert!(
+        path.exists(),
+        "{} is missing, build it with `cargo build --examples`",
+        path.display()
+    );
+
+    let mut registry = PluginRegistry::default();
+    let names = load_library(&path, &mut registry).unwrap();
+    assert_eq!(names, vec!["example"]);
+
+    let plugin = registry.create("example").unwrap();
+    assert_eq!(plugin.name(), "example");
+    assert_eq!(plugin.description(), "An example plugin from a dynamic library");
+}
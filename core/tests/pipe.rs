@@ -0,0 +1,506 @@
+//! End-to-end tests of packets passing through a pipe, using a scripted fake
+//! client and server connected with in-memory connections
+
+use bimap::BiHashMap;
+use futures::future::{empty, ok};
+use realmpipe_core::adapters::RLE;
+use realmpipe_core::mappings::{Mappings, RC4_LEN};
+use realmpipe_core::packets::{client, server, InternalPacketId, Packet};
+use realmpipe_core::pipe::{
+    AutoPacket, PacketContext, PacketFuture, PacketSide, Pipe, Plugin, PluginState, SessionInfo,
+    SessionSetup, TimerAction, TimerId,
+};
+use realmpipe_core::proxy::codec::CodecError;
+use realmpipe_core::proxy::memory::MemoryConnection;
+use realmpipe_core::proxy::raw::RawPacket;
+use realmpipe_core::serverlist::ServerList;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+/// How long to wait for the pipe to handle every packet
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The packets which arrived at each side of the connection
+#[derive(Debug)]
+struct Arrived<T = Packet> {
+    server: Vec<T>,
+    client: Vec<T>,
+}
+
+/// Runs scripted sessions through a pipe with the given plugins
+struct Harness {
+    pipe: Arc<Pipe>,
+    mappings: Arc<Mappings>,
+}
+
+impl Harness {
+    fn new(plugins: Vec<Box<dyn Plugin>>) -> Self {
+        let mut ids = BiHashMap::new();
+        ids.insert(1, InternalPacketId::PlayerText);
+        ids.insert(2, InternalPacketId::Pong);
+        ids.insert(3, InternalPacketId::Ping);
+        ids.insert(4, InternalPacketId::Notification);
+        let mappings = Arc::new(Mappings::new("00".repeat(RC4_LEN), ids).unwrap());
+
+        let mut servers = HashMap::new();
+        servers.insert("USWest", IpAddr::from([127, 0, 0, 1]));
+
+        let pipe = plugins
+            .into_iter()
+            .fold(Pipe::builder(), |builder, plugin| builder.plugin(plugin))
+            .mappings(Arc::clone(&mappings))
+            .servers(ServerList::new(&servers), "usw")
+            .build()
+            .unwrap();
+
+        Self {
+            pipe: Arc::new(pipe),
+            mappings,
+        }
+    }
+
+    fn encode(&self, packets: Vec<Packet>) -> Vec<RawPacket> {
+        packets
+            .into_iter()
+            .map(|p| RawPacket::from_packet(p, &self.mappings).unwrap())
+            .collect()
+    }
+
+    /// Send packets from the fake client and server at the same time, wait for
+    /// the pipe to handle them all, then disconnect the session and collect
+    /// the packets which arrived at each side. The client must send at least
+    /// one packet, so the pipe connects to the server.
+    fn run(&self, from_client: Vec<Packet>, from_server: Vec<Packet>) -> Arrived {
+        let arrived = self.run_raw(self.encode(from_client), self.encode(from_server));
+
+        let decode = |raw: Vec<RawPacket>| -> Vec<Packet> {
+            raw.iter()
+                .map(|r| r.to_packet(&self.mappings).unwrap())
+                .collect()
+        };
+        Arrived {
+            server: decode(arrived.server),
+            client: decode(arrived.client),
+        }
+    }
+
+    /// Start a session, send packets from the fake client and server, and wait
+    /// for the pipe to receive them all, returning both ends and the session
+    fn connect(
+        &self,
+        from_client: Vec<RawPacket>,
+        from_server: Vec<RawPacket>,
+    ) -> (
+        Runtime,
+        MemoryConnection,
+        MemoryConnection,
+        Arc<SessionInfo>,
+    ) {
+        assert!(!from_client.is_empty(), "the client must send a packet");
+        let (client_count, server_count) = (from_client.len() as u64, from_server.len() as u64);
+
+        let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let proxy_addr: SocketAddr = "127.0.0.1:2050".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:2051".parse().unwrap();
+        let (client, proxy_client) = MemoryConnection::pair(client_addr, proxy_addr);
+        let (proxy_server, server) = MemoryConnection::pair(proxy_addr, server_addr);
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(
+            Arc::clone(&self.pipe)
+                .accept_client_with(proxy_client, move |_| Ok(proxy_server))
+                .map_err(|e| panic!("session error: {}", e)),
+        );
+
+        let (client, _) = client
+            .send_all(stream::iter_ok::<_, CodecError>(from_client))
+            .wait()
+            .unwrap();
+        let (server, _) = server
+            .send_all(stream::iter_ok::<_, CodecError>(from_server))
+            .wait()
+            .unwrap();
+
+        let started = Instant::now();
+        let session = loop {
+            let session = self.pipe.sessions().into_iter().find(|s| {
+                s.packets(PacketSide::Client) == client_count
+                    && s.packets(PacketSide::Server) == server_count
+            });
+
+            match session {
+                Some(session) => break session,
+                None if started.elapsed() > TIMEOUT => panic!("timed out waiting for packets"),
+                None => sleep(Duration::from_millis(1)),
+            }
+        };
+
+        (runtime, client, server, session)
+    }
+
+    /// Like `run`, but sending and collecting raw packets
+    fn run_raw(
+        &self,
+        from_client: Vec<RawPacket>,
+        from_server: Vec<RawPacket>,
+    ) -> Arrived<RawPacket> {
+        let (runtime, client, server, session) = self.connect(from_client, from_server);
+
+        // once every packet has been received, disconnecting will still send
+        // everything which was pending
+        session.disconnect().unwrap();
+
+        let arrived = Arrived {
+            server: server.collect().wait().unwrap(),
+            client: client.collect().wait().unwrap(),
+        };
+
+        runtime.shutdown_on_idle().wait().unwrap();
+        arrived
+    }
+}
+
+fn text(text: &str) -> Packet {
+    Packet::PlayerText(client::PlayerText {
+        text: RLE::new(text.to_owned()),
+    })
+}
+
+fn ping(serial: u32) -> Packet {
+    Packet::Ping(server::Ping { serial })
+}
+
+fn pong(serial: u32) -> Packet {
+    Packet::Pong(client::Pong { serial, time: 0 })
+}
+
+fn notification(message: &str) -> Packet {
+    Packet::Notification(server::Notification {
+        object_id: 0,
+        message: RLE::new(message.to_owned()),
+        color: 0,
+    })
+}
+
+/// Cancels commands sent by the client, replying with a notification
+struct Commands;
+
+impl Plugin for Commands {
+    fn name(&self) -> &str {
+        "commands"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup.handlers().on(|pkt: &client::PlayerText, ctx| {
+            if pkt.text.starts_with('/') {
+                ctx.cancel_packet();
+                ctx.send_packet(notification(&pkt.text[1..]));
+            }
+        });
+        Box::new(NoState)
+    }
+}
+
+/// Modifies pings from the server
+struct Serials;
+
+impl Plugin for Serials {
+    fn name(&self) -> &str {
+        "serials"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup
+            .handlers()
+            .on_mut(|pkt: &mut server::Ping, _ctx| pkt.serial += 100);
+        Box::new(NoState)
+    }
+}
+
+struct NoState;
+
+impl PluginState for NoState {}
+
+/// Cancels pongs asynchronously
+struct AsyncCancel;
+
+impl Plugin for AsyncCancel {
+    fn name(&self) -> &str {
+        "async"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup.handlers().subscribe(InternalPacketId::Pong);
+        Box::new(AsyncCancel)
+    }
+}
+
+impl PluginState for AsyncCancel {
+    fn on_packet_async(
+        &mut self,
+        packet: &mut AutoPacket,
+        _context: &mut PacketContext,
+    ) -> Option<PacketFuture> {
+        packet.downcast::<client::Pong>()?;
+
+        let mut ctx = PacketContext::default();
+        ctx.cancel_packet();
+        ctx.send_packet(text("pong cancelled"));
+        Some(Box::new(ok(ctx)))
+    }
+}
+
+/// Schedules a callback which repeats without any interval, counting how many
+/// times it fires
+#[derive(Clone, Default)]
+struct Spin(Arc<AtomicUsize>);
+
+impl Plugin for Spin {
+    fn name(&self) -> &str {
+        "spin"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup
+            .timers()
+            .every(Duration::from_millis(0), TimerAction::Callback)
+            .unwrap();
+        Box::new(self.clone())
+    }
+}
+
+impl PluginState for Spin {
+    fn on_timer(&mut self, _timer: TimerId, _context: &mut PacketContext) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Holds pongs forever with asynchronous callbacks which never complete
+struct Stall;
+
+impl Plugin for Stall {
+    fn name(&self) -> &str {
+        "stall"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup.handlers().subscribe(InternalPacketId::Pong);
+        Box::new(Stall)
+    }
+}
+
+impl PluginState for Stall {
+    fn on_packet_async(
+        &mut self,
+        packet: &mut AutoPacket,
+        _context: &mut PacketContext,
+    ) -> Option<PacketFuture> {
+        packet.downcast::<client::Pong>()?;
+        Some(Box::new(empty()))
+    }
+}
+
+/// Records whether each packet could be decoded, subscribing only to
+/// `PlayerText`
+#[derive(Clone, Default)]
+struct DecodeLog(Arc<Mutex<Vec<bool>>>);
+
+impl Plugin for DecodeLog {
+    fn name(&self) -> &str {
+        "decode_log"
+    }
+
+    fn init_plugin(&mut self, setup: &mut SessionSetup) -> Box<dyn PluginState> {
+        setup.handlers().subscribe(InternalPacketId::PlayerText);
+        Box::new(self.clone())
+    }
+}
+
+impl PluginState for DecodeLog {
+    fn on_packet(&mut self, packet: &mut AutoPacket, _context: &mut PacketContext) {
+        let decoded = packet.get_any().is_some();
+        self.0.lock().unwrap().push(decoded);
+    }
+}
+
+#[test]
+fn test_forwarding() {
+    let harness = Harness::new(vec![]);
+    let arrived = harness.run(
+        vec![text("hello"), pong(1)],
+        vec![ping(1), notification("hi")],
+    );
+
+    assert_eq!(arrived.server, vec![text("hello"), pong(1)]);
+    assert_eq!(arrived.client, vec![ping(1), notification("hi")]);
+}
+
+#[test]
+fn test_cancel_and_inject() {
+    let harness = Harness::new(vec![Box::new(Commands)]);
+    let arrived = harness.run(vec![text("hello"), text("/help"), text("bye")], vec![]);
+
+    assert_eq!(arrived.server, vec![text("hello"), text("bye")]);
+    assert_eq!(arrived.client, vec![notification("help")]);
+}
+
+#[test]
+fn test_modify() {
+    let harness = Harness::new(vec![Box::new(Serials)]);
+    let arrived = harness.run(vec![pong(0)], vec![ping(1), ping(2)]);
+
+    assert_eq!(arrived.client, vec![ping(101), ping(102)]);
+}
+
+#[test]
+fn test_async_order() {
+    let harness = Harness::new(vec![Box::new(AsyncCancel)]);
+    let arrived = harness.run(vec![text("a"), pong(1), text("b")], vec![ping(1)]);
+
+    // packets sent after the cancelled one are held until it's resolved, and
+    // injected packets are sent in its place
+    assert_eq!(
+        arrived.server,
+        vec![text("a"), text("pong cancelled"), text("b")]
+    );
+    assert_eq!(arrived.client, vec![ping(1)]);
+}
+
+#[test]
+fn test_unsubscribed() {
+    let log = DecodeLog::default();
+    let harness = Harness::new(vec![Box::new(log.clone())]);
+    let ping = harness.encode(vec![ping(7)]);
+    let arrived = harness.run_raw(harness.encode(vec![text("hello")]), ping.clone());
+
+    // only the subscribed packet type was decoded, and the other was
+    // forwarded exactly as it was received
+    assert_eq!(*log.0.lock().unwrap(), vec![true, false]);
+    assert_eq!(arrived.client.len(), 1);
+    assert_eq!(arrived.client[0].game_id(), ping[0].game_id());
+    assert_eq!(arrived.client[0].contents(), ping[0].contents());
+}
+
+#[test]
+fn test_handle_timers() {
+    let harness = Harness::new(vec![]);
+    let (runtime, client, server, session) =
+        harness.connect(harness.encode(vec![text("hello")]), vec![]);
+
+    let handle = session.handle();
+    handle
+        .send_after(Duration::from_millis(20), notification("later"))
+        .unwrap();
+    let timer = handle
+        .send_every(Duration::from_millis(5), notification("tick"))
+        .unwrap();
+
+    // the repeating timer fires a few times before the delayed one
+    let mut ticks = 0;
+    let mut client = client.map(|raw| raw.to_packet(&harness.mappings).unwrap());
+    let arrived = client
+        .by_ref()
+        .skip_while(|p| {
+            if p == &notification("tick") {
+                ticks += 1;
+            }
+            Ok(p != &notification("later"))
+        })
+        .take(1)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(arrived, vec![notification("later")]);
+    assert!(ticks >= 2);
+
+    handle.cancel_timer(timer).unwrap();
+    handle.disconnect().unwrap();
+    let rest = client.collect().wait().unwrap();
+    assert!(rest.iter().all(|p| p == &notification("tick")));
+    assert_eq!(server.collect().wait().unwrap().len(), 1);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn test_close_while_pending() {
+    let harness = Harness::new(vec![Box::new(Stall)]);
+    let (runtime, client, server, _session) =
+        harness.connect(harness.encode(vec![text("a"), pong(1)]), vec![]);
+
+    // the client leaving abandons the stalled pong, ending the session
+    drop(client);
+    let arrived = server
+        .map(|raw| raw.to_packet(&harness.mappings).unwrap())
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(arrived, vec![text("a")]);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn test_disconnect_while_pending() {
+    let harness = Harness::new(vec![Box::new(Stall)]);
+    let (runtime, client, server, session) =
+        harness.connect(harness.encode(vec![pong(1), text("a")]), vec![]);
+
+    // disconnecting doesn't wait for the stalled pong, or the packet after it
+    session.disconnect().unwrap();
+    assert_eq!(server.collect().wait().unwrap().len(), 0);
+    assert_eq!(client.collect().wait().unwrap().len(), 0);
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn test_zero_interval() {
+    let spin = Spin::default();
+    let harness = Harness::new(vec![Box::new(spin.clone())]);
+    let started = Instant::now();
+    let arrived = harness.run(vec![text("hello")], vec![ping(1)]);
+    let elapsed = started.elapsed();
+
+    assert_eq!(arrived.server, vec![text("hello")]);
+    assert_eq!(arrived.client, vec![ping(1)]);
+
+    // the timer fires at most once a millisecond
+    let fired = spin.0.load(Ordering::SeqCst) as u128;
+    assert!(
+        fired <= elapsed.as_millis() + 1,
+        "fired {} times in {:?}",
+        fired,
+        elapsed
+    );
+}
+
+#[cfg(feature = "wasm")]
+#[test]
+fn test_wasm_sees_modified() {
+    use realmpipe_core::wasm::WasmPlugin;
+
+    // echoes every packet back and cancels it
+    let wasm = wat::parse_str(
+        r#"(module
+            (import "realmpipe" "send_packet" (func $send (param i32 i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32)
+                i32.const 1024)
+            (func (export "on_packet") (param i32 i32 i32 i32) (result i32)
+                (call $send (local.get 0) (local.get 2) (local.get 3))
+                i32.const 1))"#,
+    )
+    .unwrap();
+    let echo = WasmPlugin::new("echo", &wasm).unwrap();
+
+    let harness = Harness::new(vec![Box::new(Serials), Box::new(echo)]);
+    let arrived = harness.run(vec![text("hello")], vec![ping(1)]);
+
+    // the module sees, and echoes, the ping as modified by the first plugin
+    assert_eq!(arrived.client, vec![ping(101)]);
+    assert_eq!(arrived.server, vec![text("hello")]);
+}
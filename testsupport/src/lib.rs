@@ -0,0 +1,24 @@
+//! Utilities for testing the proxy, plugins and clients without connecting to
+//! the real game, including a fake game server.
+
+#![deny(bare_trait_objects)]
+#![deny(missing_docs)]
+
+pub mod server;
+
+use bimap::BiHashMap;
+use realmpipe_core::mappings::{Mappings, RC4_LEN};
+use realmpipe_core::packets::InternalPacketId;
+
+/// Create mappings for testing, which map every packet type to its internal
+/// ID and use an all-zero RC4 key
+pub fn test_mappings() -> Mappings {
+    let mut ids = BiHashMap::new();
+    for byte in 0..=255 {
+        if let Some(id) = InternalPacketId::from_byte(byte) {
+            ids.insert(byte, id);
+        }
+    }
+
+    Mappings::new("00".repeat(RC4_LEN), ids).expect("invalid test mappings")
+}
@@ -0,0 +1,606 @@
+//! A minimal stand-in for a ROTMG game server.
+//!
+//! The server starts a game session the same way as the real one: it waits
+//! for `Hello` and replies with `MapInfo`, then waits for `Load` and replies
+//! with `CreateSuccess` and an `Update` describing the map. From then on it
+//! sends a `NewTick` every tick and a `Ping` every few ticks, and expects each
+//! to be answered with a `Move` or `Pong` before the next one is sent. Every
+//! packet received and every mistake made by the client is recorded, so tests
+//! can check them afterwards.
+
+use failure::Error;
+use failure_derive::Fail;
+use log::warn;
+use realmpipe_core::adapters::RLE;
+use realmpipe_core::gamedata::{
+    GroundTileData, ObjectData, ObjectStatusData, StatData, StatType, WorldPosData,
+};
+use realmpipe_core::mappings::Mappings;
+use realmpipe_core::packets::{server, InternalPacketId, Packet};
+use realmpipe_core::proxy::codec::Codec;
+use realmpipe_core::proxy::raw::RawPacket;
+use realmpipe_core::proxy::Transport;
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::codec::Decoder;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+/// The shortest interval between ticks
+const MIN_TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The map and timing used by a fake server
+#[derive(Debug, Clone)]
+pub struct World {
+    /// The name of the map, sent in `MapInfo`
+    pub name: String,
+
+    /// The width of the map in tiles
+    pub width: u32,
+
+    /// The height of the map in tiles
+    pub height: u32,
+
+    /// The tiles sent in the first `Update`
+    pub tiles: Vec<GroundTileData>,
+
+    /// The objects other than the player sent in the first `Update`
+    pub objects: Vec<ObjectData>,
+
+    /// The object ID of the player, sent in `CreateSuccess`
+    pub player_id: u32,
+
+    /// The object type of the player's character
+    pub player_type: u16,
+
+    /// The stats of the player, sent in the first `Update`
+    pub player_stats: Vec<StatData>,
+
+    /// The position the player starts at
+    pub start_pos: WorldPosData,
+
+    /// How often a `NewTick` is sent. Intervals shorter than 1ms are treated
+    /// as 1ms.
+    pub tick_interval: Duration,
+
+    /// How many ticks there are between each `Ping`, or 0 to never send any
+    pub ping_every: u32,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self {
+            name: "Nexus".to_owned(),
+            width: 32,
+            height: 32,
+            tiles: Vec::new(),
+            objects: Vec::new(),
+            player_id: 1,
+            player_type: 0x030e,
+            player_stats: vec![
+                StatData::String(StatType::NAME_STAT, "Tester".to_owned()),
+                StatData::Integer(StatType::MAX_HP_STAT, 100),
+                StatData::Integer(StatType::HP_STAT, 100),
+            ],
+            start_pos: WorldPosData { x: 16.0, y: 16.0 },
+            tick_interval: Duration::from_millis(200),
+            ping_every: 5,
+        }
+    }
+}
+
+/// A mistake made by a client connected to a fake server
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum ProtocolError {
+    /// The client sent the wrong packet while the session was starting. The
+    /// server closes the connection after this.
+    #[fail(display = "expected {:?}, received {:?}", expected, found)]
+    UnexpectedPacket {
+        /// The packet the server was waiting for
+        expected: InternalPacketId,
+
+        /// The packet which was received instead
+        found: InternalPacketId,
+    },
+
+    /// The client sent a packet which couldn't be decoded
+    #[fail(display = "invalid packet: {}", _0)]
+    InvalidPacket(String),
+
+    /// The client didn't answer a `NewTick` before the next one
+    #[fail(display = "no Move for tick {}", _0)]
+    MissingMove(u32),
+
+    /// The client answered a `NewTick` which wasn't waiting for an answer
+    #[fail(display = "unexpected Move for tick {}", _0)]
+    UnexpectedMove(u32),
+
+    /// The client didn't answer a `Ping` before the next one
+    #[fail(display = "no Pong for ping {}", _0)]
+    MissingPong(u32),
+
+    /// The client answered a `Ping` which wasn't waiting for an answer
+    #[fail(display = "unexpected Pong for ping {}", _0)]
+    UnexpectedPong(u32),
+}
+
+/// Everything which has happened on a fake server
+#[derive(Debug, Default)]
+struct ServerLog {
+    received: Vec<Packet>,
+    errors: Vec<ProtocolError>,
+}
+
+/// The state shared by every connection to a fake server
+#[derive(Debug)]
+struct Shared {
+    mappings: Arc<Mappings>,
+    world: World,
+    log: Mutex<ServerLog>,
+}
+
+impl Shared {
+    fn log(&self) -> MutexGuard<'_, ServerLog> {
+        self.log.lock().expect("error acquiring server log lock")
+    }
+}
+
+/// A fake game server. Cloned servers share their world and their record of
+/// received packets and errors.
+#[derive(Debug, Clone)]
+pub struct FakeServer {
+    shared: Arc<Shared>,
+}
+
+impl FakeServer {
+    /// Create a new server, using the given mappings to encode and decode
+    /// packets
+    pub fn new(mappings: Arc<Mappings>, mut world: World) -> Self {
+        // a zero interval would make the tick timer panic
+        world.tick_interval = world.tick_interval.max(MIN_TICK_INTERVAL);
+
+        Self {
+            shared: Arc::new(Shared {
+                mappings,
+                world,
+                log: Mutex::new(ServerLog::default()),
+            }),
+        }
+    }
+
+    /// Serve a single connection, e.g. one end of a `MemoryConnection`. The
+    /// returned future completes once the connection closes, and must be run
+    /// on a tokio runtime.
+    pub fn serve<T: Transport>(&self, conn: T) -> impl Future<Item = (), Error = ()> + Send {
+        ServerSession::new(conn, Arc::clone(&self.shared))
+            .map_err(|e| warn!("Error in fake server connection: {}", e))
+    }
+
+    /// Listen for TCP connections on the given address, which may use port 0
+    /// to pick any free port. Returns the address which was bound, and a future
+    /// serving connections on it, which must be spawned on a tokio runtime.
+    pub fn listen(
+        &self,
+        address: &SocketAddr,
+    ) -> IoResult<(SocketAddr, impl Future<Item = (), Error = ()> + Send)> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let server = self.clone();
+
+        let future = listener
+            .incoming()
+            .map_err(|e| warn!("Error accepting connection: {}", e))
+            .for_each(move |socket| {
+                let conn = Codec::new_client(&server.shared.mappings).framed(socket);
+                tokio::spawn(server.serve(conn));
+                Ok(())
+            });
+
+        Ok((address, future))
+    }
+
+    /// Get every packet received from clients so far
+    pub fn received(&self) -> Vec<Packet> {
+        self.shared.log().received.clone()
+    }
+
+    /// Get every mistake made by clients so far
+    pub fn errors(&self) -> Vec<ProtocolError> {
+        self.shared.log().errors.clone()
+    }
+}
+
+/// The packet a connection is waiting for before the game starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Hello,
+    Load,
+    Playing,
+}
+
+/// A future driving a single connection to a fake server
+struct ServerSession<T> {
+    conn: T,
+    shared: Arc<Shared>,
+    stage: Stage,
+    ticks: Option<Interval>,
+    tick_id: u32,
+    ticks_since_ping: u32,
+    ping_serial: u32,
+    awaiting_move: Option<u32>,
+    awaiting_pong: Option<u32>,
+    pos: WorldPosData,
+    queue: VecDeque<RawPacket>,
+    closing: bool,
+}
+
+impl<T: Transport> ServerSession<T> {
+    fn new(conn: T, shared: Arc<Shared>) -> Self {
+        let pos = shared.world.start_pos.clone();
+
+        Self {
+            conn,
+            shared,
+            stage: Stage::Hello,
+            ticks: None,
+            tick_id: 0,
+            ticks_since_ping: 0,
+            ping_serial: 0,
+            awaiting_move: None,
+            awaiting_pong: None,
+            pos,
+            queue: VecDeque::new(),
+            closing: false,
+        }
+    }
+
+    /// Queue a packet to be sent to the client
+    fn send(&mut self, packet: Packet) {
+        match RawPacket::from_packet(packet, &self.shared.mappings) {
+            Ok(raw) => self.queue.push_back(raw),
+            Err(e) => warn!("Fake server couldn't encode packet: {}", e),
+        }
+    }
+
+    /// Record a mistake made by the client
+    fn error(&self, error: ProtocolError) {
+        self.shared.log().errors.push(error);
+    }
+
+    /// Get the status of the player
+    fn player_status(&self, stats: Vec<StatData>) -> ObjectStatusData {
+        ObjectStatusData {
+            object_id: self.shared.world.player_id,
+            pos: self.pos.clone(),
+            stats: RLE::new(stats),
+        }
+    }
+
+    /// Handle a packet received from the client
+    fn handle_packet(&mut self, raw: RawPacket) {
+        let packet = match raw.to_packet(&self.shared.mappings) {
+            Ok(packet) => packet,
+            Err(e) => {
+                self.error(ProtocolError::InvalidPacket(e.to_string()));
+                self.closing = self.stage != Stage::Playing;
+                return;
+            }
+        };
+
+        self.shared.log().received.push(packet.clone());
+        let shared = Arc::clone(&self.shared);
+        let world = &shared.world;
+
+        match (self.stage, packet) {
+            (Stage::Hello, Packet::Hello(_)) => {
+                self.stage = Stage::Load;
+                self.send(Packet::MapInfo(server::MapInfo {
+                    width: world.width,
+                    height: world.height,
+                    name: RLE::new(world.name.clone()),
+                    display_name: RLE::new(world.name.clone()),
+                    fp: 0,
+                    background: 0,
+                    difficulty: 0,
+                    allow_player_teleport: true,
+                    show_displays: true,
+                    client_xml: RLE::new(Vec::new()),
+                    extra_xml: RLE::new(Vec::new()),
+                }));
+            }
+            (Stage::Load, Packet::Load(load)) => {
+                let mut objects = world.objects.clone();
+                objects.push(ObjectData {
+                    object_type: world.player_type,
+                    status: self.player_status(world.player_stats.clone()),
+                });
+
+                let create = server::CreateSuccess {
+                    object_id: world.player_id,
+                    char_id: load.char_id,
+                };
+                let update = server::Update {
+                    tiles: RLE::new(world.tiles.clone()),
+                    new_objs: RLE::new(objects),
+                    drops: RLE::new(Vec::new()),
+                };
+                let interval = world.tick_interval;
+
+                self.stage = Stage::Playing;
+                self.send(Packet::CreateSuccess(create));
+                self.send(Packet::Update(update));
+                self.ticks = Some(Interval::new(Instant::now() + interval, interval));
+            }
+            (Stage::Playing, Packet::Move(m)) => {
+                if self.awaiting_move.take() != Some(m.tick_id) {
+                    self.error(ProtocolError::UnexpectedMove(m.tick_id));
+                }
+                self.pos = m.new_pos;
+            }
+            (Stage::Playing, Packet::Pong(pong)) => {
+                if self.awaiting_pong.take() != Some(pong.serial) {
+                    self.error(ProtocolError::UnexpectedPong(pong.serial));
+                }
+            }
+            (Stage::Playing, _) => {}
+            (stage, packet) => {
+                let expected = match stage {
+                    Stage::Hello => InternalPacketId::Hello,
+                    _ => InternalPacketId::Load,
+                };
+                self.error(ProtocolError::UnexpectedPacket {
+                    expected,
+                    found: packet.get_internal_id(),
+                });
+                self.closing = true;
+            }
+        }
+    }
+
+    /// Send the next `NewTick`, along with a `Ping` if one is due
+    fn tick(&mut self) {
+        if let Some(tick_id) = self.awaiting_move.take() {
+            self.error(ProtocolError::MissingMove(tick_id));
+        }
+
+        self.tick_id += 1;
+        self.awaiting_move = Some(self.tick_id);
+
+        let tick = server::NewTick {
+            tick_id: self.tick_id,
+            tick_time: self.shared.world.tick_interval.as_millis() as u32,
+            statuses: RLE::new(vec![self.player_status(Vec::new())]),
+        };
+        self.send(Packet::NewTick(tick));
+
+        let ping_every = self.shared.world.ping_every;
+        self.ticks_since_ping += 1;
+        if ping_every != 0 && self.ticks_since_ping >= ping_every {
+            self.ticks_since_ping = 0;
+            if let Some(serial) = self.awaiting_pong.take() {
+                self.error(ProtocolError::MissingPong(serial));
+            }
+
+            self.ping_serial += 1;
+            self.awaiting_pong = Some(self.ping_serial);
+            self.send(Packet::Ping(server::Ping {
+                serial: self.ping_serial,
+            }));
+        }
+    }
+
+    /// Send as many queued packets as possible, returning whether they've all
+    /// been flushed
+    fn flush(&mut self) -> Result<bool, Error> {
+        while let Some(raw) = self.queue.pop_front() {
+            if let AsyncSink::NotReady(raw) = self.conn.start_send(raw)? {
+                self.queue.push_front(raw);
+                break;
+            }
+        }
+
+        Ok(self.conn.poll_complete()?.is_ready() && self.queue.is_empty())
+    }
+}
+
+impl<T: Transport> Future for ServerSession<T> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut progress = false;
+
+            // after a mistake while starting, we stop reading from the client
+            if !self.closing {
+                match self.conn.poll()? {
+                    Async::Ready(Some(raw)) => {
+                        self.handle_packet(raw);
+                        progress = true;
+                    }
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady => {}
+                }
+            }
+
+            if let Some(ticks) = &mut self.ticks {
+                if let Async::Ready(Some(_)) = ticks.poll()? {
+                    self.tick();
+                    progress = true;
+                }
+            }
+
+            let flushed = self.flush()?;
+            if self.closing && flushed {
+                return Ok(Async::Ready(()));
+            } else if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_mappings;
+    use realmpipe_core::packets::client;
+    use realmpipe_core::proxy::codec::CodecError;
+    use realmpipe_core::proxy::memory::MemoryConnection;
+    use tokio::runtime::Runtime;
+
+    /// A client connected to a fake server, driven synchronously
+    struct TestClient {
+        conn: Option<MemoryConnection>,
+        mappings: Arc<Mappings>,
+    }
+
+    impl TestClient {
+        fn send(&mut self, packet: Packet) {
+            let raw = RawPacket::from_packet(packet, &self.mappings).unwrap();
+            let conn = self.conn.take().unwrap();
+            self.conn = Some(conn.send(raw).wait().unwrap());
+        }
+
+        fn recv(&mut self) -> Packet {
+            let conn = self.conn.take().unwrap();
+            let (raw, conn) = conn.into_future().wait().map_err(|(e, _)| e).unwrap();
+            self.conn = Some(conn);
+            raw.unwrap().to_packet(&self.mappings).unwrap()
+        }
+    }
+
+    fn start(world: World) -> (Runtime, FakeServer, TestClient) {
+        let mappings = Arc::new(test_mappings());
+        let server = FakeServer::new(Arc::clone(&mappings), world);
+        let addr: SocketAddr = "127.0.0.1:2050".parse().unwrap();
+        let (client, server_end) = MemoryConnection::pair(addr, addr);
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(server.serve(server_end));
+
+        let client = TestClient {
+            conn: Some(client),
+            mappings,
+        };
+        (runtime, server, client)
+    }
+
+    fn hello() -> Packet {
+        let empty = || RLE::new(String::new());
+        Packet::Hello(client::Hello {
+            build_version: empty(),
+            game_id: 0,
+            guid: empty(),
+            rand1: 0,
+            password: empty(),
+            rand2: 0,
+            secret: empty(),
+            key_time: 0,
+            key: RLE::new(Vec::new()),
+            map_json: RLE::new(String::new()),
+            entry_tag: empty(),
+            game_net: empty(),
+            game_net_user_id: empty(),
+            play_platform: empty(),
+            platform_token: empty(),
+            user_token: empty(),
+        })
+    }
+
+    fn load() -> Packet {
+        Packet::Load(client::Load {
+            char_id: 7,
+            from_arena: false,
+        })
+    }
+
+    #[test]
+    fn test_session() {
+        let world = World {
+            tick_interval: Duration::from_millis(20),
+            ping_every: 2,
+            ..World::default()
+        };
+        let (runtime, server, mut client) = start(world);
+
+        client.send(hello());
+        assert_eq!(client.recv().get_internal_id(), InternalPacketId::MapInfo);
+        client.send(load());
+        assert_eq!(
+            client.recv(),
+            Packet::CreateSuccess(server::CreateSuccess {
+                object_id: 1,
+                char_id: 7
+            })
+        );
+        assert_eq!(client.recv().get_internal_id(), InternalPacketId::Update);
+
+        // answer a few ticks and pings correctly
+        let mut pings = 0;
+        while pings < 2 {
+            match client.recv() {
+                Packet::NewTick(tick) => client.send(Packet::Move(client::Move {
+                    tick_id: tick.tick_id,
+                    time: 0,
+                    new_pos: WorldPosData { x: 1.0, y: 2.0 },
+                    records: RLE::new(Vec::new()),
+                })),
+                Packet::Ping(ping) => {
+                    pings += 1;
+                    client.send(Packet::Pong(client::Pong {
+                        serial: ping.serial,
+                        time: 0,
+                    }));
+                }
+                other => panic!("unexpected packet: {:?}", other),
+            }
+        }
+
+        // the server stops once the client disconnects
+        drop(client);
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        assert_eq!(server.errors(), vec![]);
+        assert_eq!(server.received().len(), 2 + 4 + 2);
+    }
+
+    #[test]
+    fn test_mistakes() {
+        let (_runtime, server, mut client) = start(World::default());
+
+        // the server disconnects if the client doesn't start correctly
+        client.send(load());
+        let conn = client.conn.take().unwrap();
+        let rest: Result<Vec<_>, CodecError> = conn.collect().wait();
+        assert!(rest.unwrap().is_empty());
+
+        assert_eq!(
+            server.errors(),
+            vec![ProtocolError::UnexpectedPacket {
+                expected: InternalPacketId::Hello,
+                found: InternalPacketId::Load
+            }]
+        );
+    }
+
+    #[test]
+    fn test_zero_tick_interval() {
+        let world = World {
+            tick_interval: Duration::from_millis(0),
+            ..World::default()
+        };
+        let (_runtime, _server, mut client) = start(world);
+
+        client.send(hello());
+        client.recv();
+        client.send(load());
+        client.recv();
+        client.recv();
+        assert_eq!(client.recv().get_internal_id(), InternalPacketId::NewTick);
+    }
+}
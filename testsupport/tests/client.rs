@@ -0,0 +1,106 @@
+//! Tests of the headless client playing on the fake server
+
+use realmpipe_core::adapters::RLE;
+use realmpipe_core::client::{Client, ClientConfig, ClientHandle};
+use realmpipe_core::gamedata::WorldPosData;
+use realmpipe_core::packets::{client, InternalPacketId, Packet};
+use realmpipe_core::proxy::memory::MemoryConnection;
+use realmpipe_testsupport::server::{FakeServer, World};
+use realmpipe_testsupport::test_mappings;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+/// How long to wait for the client to reach each state
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start a client on the fake server, returning a receiver for whether the
+/// client finished without an error
+fn start(world: World) -> (Runtime, FakeServer, ClientHandle, Receiver<bool>) {
+    let mappings = Arc::new(test_mappings());
+    let server = FakeServer::new(Arc::clone(&mappings), world);
+    let addr: SocketAddr = "127.0.0.1:2050".parse().unwrap();
+    let (client_end, server_end) = MemoryConnection::pair(addr, addr);
+
+    let config = ClientConfig::new("guid@example.com", "password", "1.0", 7);
+    let client = Client::new(client_end, mappings, config);
+    let handle = client.handle();
+
+    let (sender, finished) = channel();
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server.serve(server_end));
+    runtime.spawn(client.then(move |result| {
+        sender.send(result.is_ok()).unwrap();
+        Ok(())
+    }));
+
+    (runtime, server, handle, finished)
+}
+
+/// Wait until the condition is true, panicking if it takes too long
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "timed out waiting for {}",
+            what
+        );
+        sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_client() {
+    let world = World {
+        tick_interval: Duration::from_millis(20),
+        ping_every: 2,
+        start_pos: WorldPosData { x: 10.0, y: 10.0 },
+        ..World::default()
+    };
+    let (runtime, server, handle, finished) = start(world);
+
+    wait_until("the player to load", || handle.status().pos.is_some());
+    let status = handle.status();
+    assert_eq!(status.map.as_deref(), Some("Nexus"));
+    assert_eq!(status.player_id, Some(1));
+    assert_eq!(status.pos, Some(WorldPosData { x: 10.0, y: 10.0 }));
+
+    let target = WorldPosData { x: 10.5, y: 10.0 };
+    handle.move_to(target.clone()).unwrap();
+    wait_until("the player to move", || {
+        handle.status().pos == Some(target.clone())
+    });
+
+    handle.chat("hello").unwrap();
+    handle.use_portal(5).unwrap();
+    wait_until("a ping", || {
+        let pongs = server
+            .received()
+            .iter()
+            .filter(|p| p.get_internal_id() == InternalPacketId::Pong)
+            .count();
+        pongs >= 2
+    });
+
+    handle.disconnect().unwrap();
+    assert!(finished.recv_timeout(TIMEOUT).unwrap());
+    runtime.shutdown_on_idle().wait().unwrap();
+    assert!(handle.status().pos.is_some());
+    assert!(handle.move_to(target.clone()).is_err());
+
+    let received = server.received();
+    assert_eq!(server.errors(), vec![]);
+    assert!(received.contains(&Packet::PlayerText(client::PlayerText {
+        text: RLE::new("hello".to_owned())
+    })));
+    assert!(received.contains(&Packet::UsePortal(client::UsePortal { object_id: 5 })));
+    assert!(received.iter().any(|p| match p {
+        Packet::Move(m) => m.new_pos == target,
+        _ => false,
+    }));
+}
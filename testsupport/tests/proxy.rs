@@ -0,0 +1,70 @@
+//! Tests of the headless client playing on the fake server through a pipe
+
+use realmpipe_core::client::{Client, ClientConfig};
+use realmpipe_core::pipe::Pipe;
+use realmpipe_core::proxy::memory::MemoryConnection;
+use realmpipe_core::serverlist::ServerList;
+use realmpipe_testsupport::server::{FakeServer, World};
+use realmpipe_testsupport::test_mappings;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+#[test]
+fn test_session_info() {
+    let mappings = Arc::new(test_mappings());
+    let mut servers = HashMap::new();
+    servers.insert("USWest", IpAddr::from([127, 0, 0, 1]));
+    let pipe = Pipe::builder()
+        .mappings(Arc::clone(&mappings))
+        .servers(ServerList::new(&servers), "usw")
+        .build()
+        .unwrap();
+    let pipe = Arc::new(pipe);
+
+    let client_addr: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+    let proxy_addr: SocketAddr = "127.0.0.1:2050".parse().unwrap();
+    let server_addr: SocketAddr = "127.0.0.1:2051".parse().unwrap();
+    let (client_end, proxy_client) = MemoryConnection::pair(client_addr, proxy_addr);
+    let (proxy_server, server_end) = MemoryConnection::pair(proxy_addr, server_addr);
+
+    let server = FakeServer::new(Arc::clone(&mappings), World::default());
+    let config = ClientConfig::new("guid@example.com", "password", "1.0", 7);
+    let client = Client::new(client_end, mappings, config);
+    let handle = client.handle();
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server.serve(server_end));
+    runtime.spawn(
+        Arc::clone(&pipe)
+            .accept_client_with(proxy_client, move |_| Ok(proxy_server))
+            .map_err(|e| panic!("session error: {}", e)),
+    );
+    runtime.spawn(client.map_err(|e| panic!("client error: {}", e)));
+
+    // the session picks up who is playing as the client logs in
+    let started = Instant::now();
+    let session = loop {
+        let session = pipe
+            .sessions()
+            .into_iter()
+            .find(|s| s.character_name().is_some());
+
+        match session {
+            Some(session) => break session,
+            None if started.elapsed() > Duration::from_secs(5) => {
+                panic!("timed out waiting for the character")
+            }
+            None => sleep(Duration::from_millis(1)),
+        }
+    };
+    assert_eq!(session.account().as_deref(), Some("guid@example.com"));
+    assert_eq!(session.character_name().as_deref(), Some("Tester"));
+
+    handle.disconnect().unwrap();
+    runtime.shutdown_on_idle().wait().unwrap();
+}